@@ -0,0 +1,560 @@
+use std::ops::{Add, Mul};
+
+use cgmath::{InnerSpace, One, VectorSpace, Zero};
+
+use crate::components::model::AnimationState;
+
+// The most morph targets a primitive blends. Loaders drop any further targets with
+// Primitive::limit_morph_targets, which counts them in dropped_morph_targets.
+pub const MAX_MORPH_TARGETS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MorphWeights(pub [f32; MAX_MORPH_TARGETS]);
+impl MorphWeights {
+    pub fn from_slice(weights: &[f32]) -> Self {
+        let mut morph_weights = Self::default();
+        for (weight, &value) in morph_weights.0.iter_mut().zip(weights) {
+            *weight = value;
+        }
+        morph_weights
+    }
+    pub fn lerp(&self, other: &MorphWeights, amount: f32) -> MorphWeights {
+        *self * (1.0 - amount) + *other * amount
+    }
+}
+impl Add for MorphWeights {
+    type Output = MorphWeights;
+    fn add(self, other: MorphWeights) -> MorphWeights {
+        let mut result = self;
+        for (weight, value) in result.0.iter_mut().zip(other.0) {
+            *weight += value;
+        }
+        result
+    }
+}
+impl Mul<f32> for MorphWeights {
+    type Output = MorphWeights;
+    fn mul(self, scalar: f32) -> MorphWeights {
+        MorphWeights(self.0.map(|weight| weight * scalar))
+    }
+}
+
+// Local state of a node; morph weights only apply to nodes with a mesh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trs {
+    pub translation: cgmath::Vector3<f32>,
+    pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
+    pub weights: MorphWeights,
+}
+impl Trs {
+    pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.translation)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+    // Splits an affine matrix without shear back into its parts
+    pub fn from_matrix(matrix: cgmath::Matrix4<f32>) -> Trs {
+        let scale = cgmath::vec3(
+            matrix.x.truncate().magnitude(),
+            matrix.y.truncate().magnitude(),
+            matrix.z.truncate().magnitude(),
+        );
+        let rotation = cgmath::Matrix3::from_cols(
+            matrix.x.truncate() / scale.x,
+            matrix.y.truncate() / scale.y,
+            matrix.z.truncate() / scale.z,
+        );
+        Trs {
+            translation: matrix.w.truncate(),
+            rotation: rotation.into(),
+            scale,
+            weights: MorphWeights::default(),
+        }
+    }
+    pub fn lerp(&self, other: &Trs, amount: f32) -> Trs {
+        Trs {
+            translation: self.translation.lerp(other.translation, amount),
+            rotation: self.rotation.nlerp(other.rotation, amount),
+            scale: self.scale.lerp(other.scale, amount),
+            weights: self.weights.lerp(&other.weights, amount),
+        }
+    }
+}
+impl Default for Trs {
+    fn default() -> Self {
+        Self {
+            translation: cgmath::Vector3::zero(),
+            rotation: cgmath::Quaternion::one(),
+            scale: cgmath::vec3(1.0, 1.0, 1.0),
+            weights: MorphWeights::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackMode {
+    Loop,
+    Clamp,
+    PingPong,
+}
+impl PlaybackMode {
+    // Maps an unbounded playback time onto the clip's [0, duration] range
+    pub fn apply(&self, time: f32, duration: f32) -> f32 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            PlaybackMode::Loop => time.rem_euclid(duration),
+            PlaybackMode::Clamp => time.clamp(0.0, duration),
+            PlaybackMode::PingPong => {
+                let time = time.rem_euclid(2.0 * duration);
+                if time > duration {
+                    2.0 * duration - time
+                } else {
+                    time
+                }
+            }
+        }
+    }
+    // Splits the playback range between two times into the local clip time
+    // spans it covers, in the order they were played
+    pub fn sweep(&self, from: f32, to: f32, duration: f32) -> Vec<Sweep> {
+        if duration <= 0.0 || from == to {
+            return Vec::new();
+        }
+        if from > to {
+            return self
+                .sweep(to, from, duration)
+                .into_iter()
+                .rev()
+                .map(|sweep| Sweep {
+                    from: sweep.to,
+                    to: sweep.from,
+                    include_from: false,
+                })
+                .collect();
+        }
+        if let PlaybackMode::Clamp = self {
+            if to <= 0.0 || from >= duration {
+                return Vec::new();
+            }
+            return vec![Sweep {
+                from: from.clamp(0.0, duration),
+                to: to.clamp(0.0, duration),
+                include_from: from <= 0.0,
+            }];
+        }
+        let mut sweeps = Vec::new();
+        let mut period = (from / duration).floor();
+        let mut start = from;
+        while start < to {
+            let end = to.min((period + 1.0) * duration);
+            let (local_start, local_end) = (start - period * duration, end - period * duration);
+            let at_boundary = local_start == 0.0;
+            let sweep = match self {
+                PlaybackMode::PingPong if period.rem_euclid(2.0) == 1.0 => Sweep {
+                    from: duration - local_start,
+                    to: duration - local_end,
+                    include_from: false,
+                },
+                PlaybackMode::PingPong => Sweep {
+                    from: local_start,
+                    to: local_end,
+                    include_from: at_boundary && start == 0.0,
+                },
+                _ => Sweep {
+                    from: local_start,
+                    to: local_end,
+                    include_from: at_boundary,
+                },
+            };
+            sweeps.push(sweep);
+            start = end;
+            period += 1.0;
+        }
+        sweeps
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sweep {
+    pub from: f32,
+    pub to: f32,
+    pub include_from: bool,
+}
+impl Sweep {
+    pub fn contains(&self, time: f32) -> bool {
+        if time == self.from {
+            return self.include_from;
+        }
+        let (lo, hi) = if self.from <= self.to {
+            (self.from, self.to)
+        } else {
+            (self.to, self.from)
+        };
+        lo <= time && time <= hi
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+pub enum Keyframes {
+    Translations(Vec<cgmath::Vector3<f32>>),
+    Rotations(Vec<cgmath::Quaternion<f32>>),
+    Scales(Vec<cgmath::Vector3<f32>>),
+    Weights(Vec<MorphWeights>),
+}
+
+pub struct Channel {
+    pub node: usize,
+    pub interpolation: Interpolation,
+    pub inputs: Vec<f32>,
+    pub outputs: Keyframes,
+}
+impl Channel {
+    // Returns the keyframes surrounding `time` and the blend factor between them
+    fn keyframe(&self, time: f32) -> (usize, usize, f32) {
+        let last = self.inputs.len() - 1;
+        if time <= self.inputs[0] {
+            return (0, 0, 0.0);
+        }
+        if time >= self.inputs[last] {
+            return (last, last, 0.0);
+        }
+        let rhs = self.inputs.partition_point(|&t| t <= time);
+        let lhs = rhs - 1;
+        let span = self.inputs[rhs] - self.inputs[lhs];
+        if span <= 0.0 {
+            return (lhs, rhs, 0.0);
+        }
+        (lhs, rhs, (time - self.inputs[lhs]) / span)
+    }
+    fn interpolate<T: Copy + Add<Output = T> + Mul<f32, Output = T>>(
+        &self,
+        values: &[T],
+        time: f32,
+        lerp: fn(T, T, f32) -> T,
+    ) -> T {
+        let (lhs, rhs, t) = self.keyframe(time);
+        match self.interpolation {
+            Interpolation::Step => values[lhs],
+            Interpolation::Linear => lerp(values[lhs], values[rhs], t),
+            Interpolation::CubicSpline => {
+                // Values are stored as (in-tangent, value, out-tangent) triplets
+                if lhs == rhs {
+                    return values[lhs * 3 + 1];
+                }
+                let dt = self.inputs[rhs] - self.inputs[lhs];
+                let t2 = t * t;
+                let t3 = t2 * t;
+                values[lhs * 3 + 1] * (2.0 * t3 - 3.0 * t2 + 1.0)
+                    + values[lhs * 3 + 2] * ((t3 - 2.0 * t2 + t) * dt)
+                    + values[rhs * 3 + 1] * (-2.0 * t3 + 3.0 * t2)
+                    + values[rhs * 3] * ((t3 - t2) * dt)
+            }
+        }
+    }
+    pub fn sample(&self, time: f32, trs: &mut Trs) {
+        match &self.outputs {
+            Keyframes::Translations(values) => {
+                trs.translation = self.interpolate(values, time, |a, b, t| a.lerp(b, t));
+            }
+            Keyframes::Rotations(values) => {
+                let rotation = self.interpolate(values, time, |a, b, t| a.slerp(b, t));
+                trs.rotation = cgmath::InnerSpace::normalize(rotation);
+            }
+            Keyframes::Scales(values) => {
+                trs.scale = self.interpolate(values, time, |a, b, t| a.lerp(b, t));
+            }
+            Keyframes::Weights(values) => {
+                trs.weights = self.interpolate(values, time, |a, b, t| a.lerp(&b, t));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationEvent {
+    pub name: String,
+    pub time: f32,
+}
+
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+    pub events: Vec<AnimationEvent>,
+}
+impl AnimationClip {
+    pub fn sample(&self, time: f32, pose: &mut [Trs]) {
+        for channel in self.channels.iter() {
+            channel.sample(time, &mut pose[channel.node]);
+        }
+    }
+    pub fn add_event(&mut self, name: &str, time: f32) {
+        self.events.push(AnimationEvent {
+            name: String::from(name),
+            time,
+        });
+    }
+    pub fn translation_at(&self, node: usize, time: f32) -> Option<cgmath::Vector3<f32>> {
+        let channel = self.channels.iter().find(|channel| {
+            channel.node == node && matches!(channel.outputs, Keyframes::Translations(_))
+        })?;
+        let mut trs = Trs::default();
+        channel.sample(time, &mut trs);
+        Some(trs.translation)
+    }
+}
+
+pub struct PoseBlender {
+    pose: Vec<Trs>,
+    total_weight: f32,
+}
+impl PoseBlender {
+    // The rest pose only contributes whatever weight the layers leave unclaimed
+    pub fn new(rest: &[Trs], rest_weight: f32) -> Self {
+        Self {
+            pose: rest.to_vec(),
+            total_weight: rest_weight,
+        }
+    }
+    pub fn add(&mut self, pose: &[Trs], weight: f32) {
+        if weight <= 0.0 {
+            return;
+        }
+        let amount = weight / (self.total_weight + weight);
+        for (blended, sampled) in self.pose.iter_mut().zip(pose) {
+            *blended = blended.lerp(sampled, amount);
+        }
+        self.total_weight += weight;
+    }
+    pub fn finish(self) -> Vec<Trs> {
+        self.pose
+    }
+}
+
+pub fn sample_pose(rest: &[Trs], clips: &[AnimationClip], animation: &AnimationState) -> Vec<Trs> {
+    let total_weight: f32 = animation.layers.iter().map(|layer| layer.weight).sum();
+    let mut blender = PoseBlender::new(rest, (1.0 - total_weight).max(0.0));
+    for layer in animation.layers.iter() {
+        if layer.weight <= 0.0 {
+            continue;
+        }
+        let clip = clips.get(layer.index).expect("Invalid animation index");
+        let mut pose = rest.to_vec();
+        clip.sample(layer.mode.apply(layer.time, clip.duration), &mut pose);
+        blender.add(&pose, layer.weight);
+    }
+    let mut pose = blender.finish();
+    // Horizontal root translation is moved onto the entity by root motion
+    if let Some(root) = animation.root_motion {
+        pose[root].translation.x = rest[root].translation.x;
+        pose[root].translation.z = rest[root].translation.z;
+    }
+    pose
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translation_clip(interpolation: Interpolation, outputs: Vec<cgmath::Vector3<f32>>) -> AnimationClip {
+        let inputs = match interpolation {
+            Interpolation::CubicSpline => (0..outputs.len() / 3).map(|i| i as f32).collect(),
+            _ => (0..outputs.len()).map(|i| i as f32).collect::<Vec<_>>(),
+        };
+        AnimationClip {
+            name: None,
+            events: Vec::new(),
+            duration: *inputs.last().unwrap(),
+            channels: vec![Channel {
+                node: 0,
+                interpolation,
+                inputs,
+                outputs: Keyframes::Translations(outputs),
+            }],
+        }
+    }
+
+    #[test]
+    fn trs_matrix_round_trip() {
+        let trs = Trs {
+            translation: cgmath::vec3(1.0, 2.0, 3.0),
+            rotation: cgmath::Quaternion::from(cgmath::Euler::new(
+                cgmath::Deg(0.0),
+                cgmath::Deg(90.0),
+                cgmath::Deg(0.0),
+            )),
+            scale: cgmath::vec3(2.0, 2.0, 2.0),
+            weights: MorphWeights::default(),
+        };
+        let decomposed = Trs::from_matrix(trs.to_matrix());
+        assert_eq!(decomposed.translation, trs.translation);
+        assert!((decomposed.scale - trs.scale).magnitude() < 1e-5);
+        assert!(decomposed.rotation.dot(trs.rotation).abs() > 0.9999);
+    }
+
+    #[test]
+    fn sample_linear() {
+        let clip = translation_clip(
+            Interpolation::Linear,
+            vec![cgmath::vec3(0.0, 0.0, 0.0), cgmath::vec3(2.0, 0.0, 0.0)],
+        );
+        let mut pose = vec![Trs::default()];
+        clip.sample(0.25, &mut pose);
+        assert_eq!(pose[0].translation, cgmath::vec3(0.5, 0.0, 0.0));
+    }
+
+    #[test]
+    fn sample_step() {
+        let clip = translation_clip(
+            Interpolation::Step,
+            vec![cgmath::vec3(0.0, 0.0, 0.0), cgmath::vec3(2.0, 0.0, 0.0)],
+        );
+        let mut pose = vec![Trs::default()];
+        clip.sample(0.9, &mut pose);
+        assert_eq!(pose[0].translation, cgmath::vec3(0.0, 0.0, 0.0));
+        clip.sample(1.0, &mut pose);
+        assert_eq!(pose[0].translation, cgmath::vec3(2.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn sample_cubic_spline_hits_keyframes() {
+        let zero = cgmath::vec3(0.0, 0.0, 0.0);
+        let clip = translation_clip(
+            Interpolation::CubicSpline,
+            vec![zero, zero, zero, zero, cgmath::vec3(0.0, 4.0, 0.0), zero],
+        );
+        let mut pose = vec![Trs::default()];
+        clip.sample(0.5, &mut pose);
+        assert_eq!(pose[0].translation, cgmath::vec3(0.0, 2.0, 0.0));
+        clip.sample(1.0, &mut pose);
+        assert_eq!(pose[0].translation, cgmath::vec3(0.0, 4.0, 0.0));
+    }
+
+    #[test]
+    fn sample_morph_weights() {
+        let clip = AnimationClip {
+            name: None,
+            events: Vec::new(),
+            duration: 1.0,
+            channels: vec![Channel {
+                node: 0,
+                interpolation: Interpolation::Linear,
+                inputs: vec![0.0, 1.0],
+                outputs: Keyframes::Weights(vec![
+                    MorphWeights::from_slice(&[0.0, 1.0]),
+                    MorphWeights::from_slice(&[1.0, 0.0]),
+                ]),
+            }],
+        };
+        let mut pose = vec![Trs::default()];
+        clip.sample(0.5, &mut pose);
+        assert_eq!(pose[0].weights, MorphWeights::from_slice(&[0.5, 0.5]));
+    }
+
+    #[test]
+    fn playback_modes() {
+        assert_eq!(PlaybackMode::Loop.apply(2.5, 2.0), 0.5);
+        assert_eq!(PlaybackMode::Clamp.apply(2.5, 2.0), 2.0);
+        assert_eq!(PlaybackMode::Clamp.apply(-1.0, 2.0), 0.0);
+        assert_eq!(PlaybackMode::PingPong.apply(2.5, 2.0), 1.5);
+        assert_eq!(PlaybackMode::PingPong.apply(4.5, 2.0), 0.5);
+    }
+
+    #[test]
+    fn blend_layers() {
+        let clips = vec![
+            translation_clip(
+                Interpolation::Step,
+                vec![cgmath::vec3(0.0, 0.0, 0.0), cgmath::vec3(0.0, 0.0, 0.0)],
+            ),
+            translation_clip(
+                Interpolation::Step,
+                vec![cgmath::vec3(4.0, 0.0, 0.0), cgmath::vec3(4.0, 0.0, 0.0)],
+            ),
+        ];
+        let rest = vec![Trs::default()];
+        let mut animation = AnimationState::new(0);
+        animation.crossfade(1, 1.0);
+        animation.advance(0.25, &clips);
+        let pose = sample_pose(&rest, &clips, &animation);
+        assert_eq!(pose[0].translation, cgmath::vec3(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn partial_weight_blends_with_rest_pose() {
+        let clips = vec![translation_clip(
+            Interpolation::Step,
+            vec![cgmath::vec3(2.0, 0.0, 0.0), cgmath::vec3(2.0, 0.0, 0.0)],
+        )];
+        let rest = vec![Trs::default()];
+        let mut animation = AnimationState::new(0);
+        animation.layers[0].weight = 0.5;
+        let pose = sample_pose(&rest, &clips, &animation);
+        assert_eq!(pose[0].translation, cgmath::vec3(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn sweep_wraps_loops() {
+        let sweeps = PlaybackMode::Loop.sweep(1.5, 2.5, 2.0);
+        assert_eq!(sweeps.len(), 2);
+        assert!(sweeps[0].contains(2.0));
+        assert!(!sweeps[0].contains(1.5));
+        assert!(sweeps[1].contains(0.0));
+        assert!(sweeps[1].contains(0.5));
+        assert!(!sweeps[1].contains(1.0));
+    }
+
+    #[test]
+    fn sweep_ping_pong_turns_around() {
+        let sweeps = PlaybackMode::PingPong.sweep(1.5, 2.5, 2.0);
+        assert_eq!(sweeps[1].from, 2.0);
+        assert_eq!(sweeps[1].to, 1.5);
+        assert!(!sweeps[1].contains(2.0));
+        assert!(sweeps[1].contains(1.75));
+        assert!(PlaybackMode::Clamp.sweep(2.5, 3.0, 2.0).is_empty());
+    }
+
+    #[test]
+    fn events_fire_when_crossed() {
+        let mut clips = vec![translation_clip(
+            Interpolation::Linear,
+            vec![cgmath::vec3(0.0, 0.0, 0.0), cgmath::vec3(2.0, 0.0, 0.0)],
+        )];
+        clips[0].add_event("footstep", 0.5);
+        let mut animation = AnimationState::new(0);
+        animation.advance(0.25, &clips);
+        assert!(animation.events().is_empty());
+        animation.advance(0.5, &clips);
+        assert_eq!(animation.events()[0].name, "footstep");
+        animation.advance(0.5, &clips);
+        assert!(animation.events().is_empty());
+        animation.advance(0.5, &clips);
+        assert_eq!(animation.events().len(), 1);
+    }
+
+    #[test]
+    fn root_motion_moves_entity_not_mesh() {
+        let clips = vec![translation_clip(
+            Interpolation::Linear,
+            vec![cgmath::vec3(0.0, 1.0, 0.0), cgmath::vec3(2.0, 1.0, 0.0)],
+        )];
+        let rest = vec![Trs::default()];
+        let mut animation = AnimationState::new(0);
+        animation.root_motion = Some(0);
+        animation.advance(0.75, &clips);
+        assert_eq!(animation.root_motion_delta(), cgmath::vec3(1.5, 0.0, 0.0));
+        animation.advance(0.5, &clips);
+        assert_eq!(animation.root_motion_delta(), cgmath::vec3(1.0, 0.0, 0.0));
+        let pose = sample_pose(&rest, &clips, &animation);
+        assert_eq!(pose[0].translation, cgmath::vec3(0.0, 1.0, 0.0));
+    }
+}
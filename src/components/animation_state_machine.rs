@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+
+use crate::animation::PlaybackMode;
+
+use super::Component;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Greater,
+    Less,
+}
+
+pub struct Condition {
+    pub parameter: String,
+    pub comparison: Comparison,
+    pub value: f32,
+}
+impl Condition {
+    fn test(&self, parameters: &HashMap<String, f32>) -> bool {
+        let parameter = parameters.get(&self.parameter).copied().unwrap_or(0.0);
+        match self.comparison {
+            Comparison::Greater => parameter > self.value,
+            Comparison::Less => parameter < self.value,
+        }
+    }
+}
+
+pub struct AnimationNode {
+    pub name: String,
+    pub clip: usize,
+    pub speed: f32,
+    pub mode: PlaybackMode,
+}
+
+pub struct Transition {
+    pub from: usize,
+    pub to: usize,
+    pub duration: f32,
+    pub conditions: Vec<Condition>,
+}
+impl Transition {
+    pub fn when(&mut self, parameter: &str, comparison: Comparison, value: f32) -> &mut Self {
+        self.conditions.push(Condition {
+            parameter: String::from(parameter),
+            comparison,
+            value,
+        });
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct AnimationStateMachine {
+    pub states: Vec<AnimationNode>,
+    pub transitions: Vec<Transition>,
+    parameters: HashMap<String, f32>,
+    current: Option<usize>,
+}
+
+impl AnimationStateMachine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // The first state added is the entry state
+    pub fn add_state(&mut self, name: &str, clip: usize) -> &mut AnimationNode {
+        self.states.push(AnimationNode {
+            name: String::from(name),
+            clip,
+            speed: 1.0,
+            mode: PlaybackMode::Loop,
+        });
+        self.states.last_mut().unwrap()
+    }
+    // Both states must have been added already
+    pub fn add_transition(
+        &mut self,
+        from: &str,
+        to: &str,
+        duration: f32,
+    ) -> anyhow::Result<&mut Transition> {
+        let index = |name| {
+            self.state_index(name)
+                .with_context(|| format!("Animation state {} does not exist", name))
+        };
+        let (from, to) = (index(from)?, index(to)?);
+        self.transitions.push(Transition {
+            from,
+            to,
+            duration,
+            conditions: Vec::new(),
+        });
+        Ok(self.transitions.last_mut().unwrap())
+    }
+    pub fn state_index(&self, name: &str) -> Option<usize> {
+        self.states.iter().position(|state| state.name == name)
+    }
+    pub fn set_parameter(&mut self, name: &str, value: f32) {
+        self.parameters.insert(String::from(name), value);
+    }
+    pub fn parameter(&self, name: &str) -> Option<f32> {
+        self.parameters.get(name).copied()
+    }
+    pub fn current_state(&self) -> Option<&AnimationNode> {
+        self.current.map(|current| &self.states[current])
+    }
+    // Returns the state to blend into and the crossfade duration when the state changes
+    pub fn update(&mut self) -> Option<(&AnimationNode, f32)> {
+        let current = match self.current {
+            Some(current) => current,
+            None => {
+                if self.states.is_empty() {
+                    return None;
+                }
+                self.current = Some(0);
+                return Some((&self.states[0], 0.0));
+            }
+        };
+        let transition = self.transitions.iter().find(|transition| {
+            transition.from == current
+                && transition
+                    .conditions
+                    .iter()
+                    .all(|condition| condition.test(&self.parameters))
+        })?;
+        self.current = Some(transition.to);
+        Some((&self.states[transition.to], transition.duration))
+    }
+}
+impl Component for AnimationStateMachine {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locomotion() -> AnimationStateMachine {
+        let mut machine = AnimationStateMachine::new();
+        machine.add_state("idle", 0);
+        machine.add_state("walk", 1);
+        machine
+            .add_transition("idle", "walk", 0.25)
+            .unwrap()
+            .when("speed", Comparison::Greater, 0.1);
+        machine
+            .add_transition("walk", "idle", 0.5)
+            .unwrap()
+            .when("speed", Comparison::Less, 0.1);
+        machine
+    }
+
+    #[test]
+    fn enters_first_state() {
+        let mut machine = locomotion();
+        let (state, duration) = machine.update().unwrap();
+        assert_eq!(state.name, "idle");
+        assert_eq!(duration, 0.0);
+        assert!(machine.update().is_none());
+    }
+
+    #[test]
+    fn transitions_on_parameters() {
+        let mut machine = locomotion();
+        machine.update();
+        machine.set_parameter("speed", 5.0);
+        let (state, duration) = machine.update().unwrap();
+        assert_eq!(state.clip, 1);
+        assert_eq!(duration, 0.25);
+        assert!(machine.update().is_none());
+        machine.set_parameter("speed", 0.0);
+        assert_eq!(machine.update().unwrap().0.name, "idle");
+    }
+
+    #[test]
+    fn unknown_states_are_reported() {
+        let mut machine = locomotion();
+        assert_eq!(machine.state_index("walk"), Some(1));
+        assert_eq!(machine.state_index("run"), None);
+        let error = machine.add_transition("walk", "run", 0.25).err().unwrap();
+        assert_eq!(error.to_string(), "Animation state run does not exist");
+        assert_eq!(machine.transitions.len(), 2);
+    }
+}
@@ -0,0 +1,5 @@
+use super::Component;
+
+// The entity's meshes are drawn into the shadow map of the sun
+pub struct CastsShadow;
+impl Component for CastsShadow {}
@@ -0,0 +1,122 @@
+use crate::{component_manager::ComponentManager, EntityHandle};
+
+use super::{parent, Component};
+
+// Shines along the entity's -z axis from infinitely far away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirectionalLight {
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+}
+impl DirectionalLight {
+    pub fn new(color: cgmath::Vector3<f32>, intensity: f32) -> Self {
+        Self { color, intensity }
+    }
+}
+impl Component for DirectionalLight {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointLight {
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+    // Distance at which the light fades out completely, unlimited when None
+    pub range: Option<f32>,
+}
+impl PointLight {
+    pub fn new(color: cgmath::Vector3<f32>, intensity: f32, range: Option<f32>) -> Self {
+        Self {
+            color,
+            intensity,
+            range,
+        }
+    }
+}
+impl Component for PointLight {}
+
+// Cone along the entity's -z axis, angles in radians from its centre
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpotLight {
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+    // Distance at which the light fades out completely, unlimited when None
+    pub range: Option<f32>,
+    pub inner_cone_angle: f32,
+    pub outer_cone_angle: f32,
+}
+impl SpotLight {
+    pub fn new(
+        color: cgmath::Vector3<f32>,
+        intensity: f32,
+        range: Option<f32>,
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    ) -> Self {
+        Self {
+            color,
+            intensity,
+            range,
+            inner_cone_angle,
+            outer_cone_angle,
+        }
+    }
+}
+impl Component for SpotLight {}
+
+// Any of the light components, for code handling every kind of light
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Light {
+    Directional(DirectionalLight),
+    Point(PointLight),
+    Spot(SpotLight),
+}
+impl Light {
+    pub fn color(&self) -> cgmath::Vector3<f32> {
+        match self {
+            Light::Directional(light) => light.color,
+            Light::Point(light) => light.color,
+            Light::Spot(light) => light.color,
+        }
+    }
+    pub fn intensity(&self) -> f32 {
+        match self {
+            Light::Directional(light) => light.intensity,
+            Light::Point(light) => light.intensity,
+            Light::Spot(light) => light.intensity,
+        }
+    }
+    // None for directional lights and lights reaching infinitely far
+    pub fn range(&self) -> Option<f32> {
+        match self {
+            Light::Directional(_) => None,
+            Light::Point(light) => light.range,
+            Light::Spot(light) => light.range,
+        }
+    }
+
+    // Adds the component of this kind of light to the entity
+    pub fn add_to(self, entity: EntityHandle, cm: &mut ComponentManager) {
+        match self {
+            Light::Directional(light) => cm.add_component(light, entity),
+            Light::Point(light) => cm.add_component(light, entity),
+            Light::Spot(light) => cm.add_component(light, entity),
+        }
+    }
+
+    // Every light in the world with its global transform, directional lights first
+    pub fn collect(cm: &ComponentManager) -> Vec<(Light, cgmath::Matrix4<f32>)> {
+        let transform = |entity| parent::global_matrix(cm, entity);
+        let directional = cm
+            .get_all_by_type::<DirectionalLight>()
+            .into_iter()
+            .map(|(entity, light)| (Light::Directional(*light), transform(entity)));
+        let point = cm
+            .get_all_by_type::<PointLight>()
+            .into_iter()
+            .map(|(entity, light)| (Light::Point(*light), transform(entity)));
+        let spot = cm
+            .get_all_by_type::<SpotLight>()
+            .into_iter()
+            .map(|(entity, light)| (Light::Spot(*light), transform(entity)));
+        directional.chain(point).chain(spot).collect()
+    }
+}
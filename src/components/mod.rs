@@ -5,5 +5,12 @@ pub mod walk_to;
 pub mod walkable_surface;
 pub mod click;
 pub mod click_move;
+pub mod animation_state_machine;
+pub mod morph_weights_override;
+pub mod parent;
+pub mod scene_node;
+pub mod light;
+pub mod casts_shadow;
+pub mod receives_shadow;
 
 pub trait Component {}
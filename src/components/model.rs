@@ -1,28 +1,175 @@
-use std::time::{Instant, Duration};
+use cgmath::Zero;
 
-use crate::asset_manager::AssetHandle;
+use crate::{
+    animation::{AnimationClip, PlaybackMode},
+    asset_manager::AssetHandle,
+};
 
 use super::Component;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fade {
+    from: f32,
+    to: f32,
+    elapsed: f32,
+    duration: f32,
+}
 
-pub struct AnimationState {
-    pub start_time: Instant,
-    pub current_time: Instant,
+pub struct AnimationLayer {
     pub index: usize,
+    pub time: f32,
+    pub speed: f32,
+    pub weight: f32,
+    pub mode: PlaybackMode,
+    fade: Option<Fade>,
+}
+impl AnimationLayer {
+    pub fn new(index: usize, weight: f32) -> Self {
+        Self {
+            index,
+            time: 0.0,
+            speed: 1.0,
+            weight,
+            mode: PlaybackMode::Loop,
+            fade: None,
+        }
+    }
+    pub fn fade_to(&mut self, weight: f32, duration: f32) {
+        if duration <= 0.0 {
+            self.weight = weight;
+            self.fade = None;
+        } else {
+            self.fade = Some(Fade {
+                from: self.weight,
+                to: weight,
+                elapsed: 0.0,
+                duration,
+            });
+        }
+    }
+    pub fn target_weight(&self) -> f32 {
+        self.fade.map_or(self.weight, |fade| fade.to)
+    }
+    fn is_faded_out(&self) -> bool {
+        self.weight <= 0.0 && self.fade.is_none_or(|fade| fade.to <= 0.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiredEvent {
+    pub name: String,
+    pub clip: usize,
+    pub weight: f32,
+}
+
+pub struct AnimationState {
+    pub layers: Vec<AnimationLayer>,
+    pub speed: f32,
+    pub paused: bool,
+    pub root_motion: Option<usize>,
+    root_motion_delta: cgmath::Vector3<f32>,
+    events: Vec<FiredEvent>,
 }
 
 impl AnimationState {
     pub fn new(index: usize) -> Self {
-        let start_time = Instant::now();
         Self {
-            start_time,
-            current_time: start_time,
-            index,
+            layers: vec![AnimationLayer::new(index, 1.0)],
+            speed: 1.0,
+            paused: false,
+            root_motion: None,
+            root_motion_delta: cgmath::Vector3::zero(),
+            events: Vec::new(),
         }
     }
-    pub fn advance(&mut self, delta: f32) {
-        let duration = Duration::from_secs_f32(delta);
-        self.current_time += duration;
+    pub fn play(&mut self, index: usize) -> &mut AnimationLayer {
+        self.layers.clear();
+        self.layers.push(AnimationLayer::new(index, 1.0));
+        self.layers.last_mut().unwrap()
+    }
+    pub fn crossfade(&mut self, index: usize, duration: f32) -> &mut AnimationLayer {
+        if duration <= 0.0 {
+            return self.play(index);
+        }
+        let target = match self.layers.iter().position(|layer| layer.index == index) {
+            Some(target) => target,
+            None => {
+                self.layers.push(AnimationLayer::new(index, 0.0));
+                self.layers.len() - 1
+            }
+        };
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer.fade_to(if i == target { 1.0 } else { 0.0 }, duration);
+        }
+        &mut self.layers[target]
+    }
+    pub fn add_layer(&mut self, index: usize, weight: f32) -> &mut AnimationLayer {
+        self.layers.push(AnimationLayer::new(index, weight));
+        self.layers.last_mut().unwrap()
+    }
+    pub fn current(&self) -> Option<&AnimationLayer> {
+        self.layers
+            .iter()
+            .max_by(|a, b| a.target_weight().total_cmp(&b.target_weight()))
+    }
+    // Events crossed during the last call to `advance`
+    pub fn events(&self) -> &[FiredEvent] {
+        &self.events
+    }
+    // Horizontal root node translation played during the last call to `advance`
+    pub fn root_motion_delta(&self) -> cgmath::Vector3<f32> {
+        self.root_motion_delta
+    }
+    pub fn advance(&mut self, delta: f32, clips: &[AnimationClip]) {
+        self.events.clear();
+        self.root_motion_delta = cgmath::Vector3::zero();
+        if self.paused {
+            return;
+        }
+        let total_weight = self
+            .layers
+            .iter()
+            .map(|layer| layer.weight)
+            .sum::<f32>()
+            .max(1.0);
+        for layer in self.layers.iter_mut() {
+            let from = layer.time;
+            layer.time += delta * self.speed * layer.speed;
+            if let Some(clip) = clips.get(layer.index) {
+                let sweeps = layer.mode.sweep(from, layer.time, clip.duration);
+                // Layers that are fading out stay silent so crossfades don't double up events
+                if layer.target_weight() > 0.0 {
+                    for event in clip.events.iter() {
+                        if sweeps.iter().any(|sweep| sweep.contains(event.time)) {
+                            self.events.push(FiredEvent {
+                                name: event.name.clone(),
+                                clip: layer.index,
+                                weight: layer.weight,
+                            });
+                        }
+                    }
+                }
+                if let Some(root) = self.root_motion {
+                    for sweep in sweeps.iter() {
+                        let start = clip.translation_at(root, sweep.from);
+                        let end = clip.translation_at(root, sweep.to);
+                        if let (Some(start), Some(end)) = (start, end) {
+                            self.root_motion_delta += (end - start) * layer.weight / total_weight;
+                        }
+                    }
+                }
+            }
+            if let Some(fade) = &mut layer.fade {
+                fade.elapsed += delta;
+                let amount = (fade.elapsed / fade.duration).min(1.0);
+                layer.weight = fade.from + (fade.to - fade.from) * amount;
+                if amount >= 1.0 {
+                    layer.fade = None;
+                }
+            }
+        }
+        self.root_motion_delta.y = 0.0;
+        self.layers.retain(|layer| !layer.is_faded_out());
     }
 }
 
@@ -34,3 +181,42 @@ pub struct Model {
 impl Component for Model {
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crossfade_weights() {
+        let mut animation = AnimationState::new(0);
+        animation.crossfade(1, 2.0);
+        animation.advance(0.5, &[]);
+        assert_eq!(animation.layers[0].weight, 0.75);
+        assert_eq!(animation.layers[1].weight, 0.25);
+        animation.advance(1.5, &[]);
+        assert_eq!(animation.layers.len(), 1);
+        assert_eq!(animation.layers[0].index, 1);
+        assert_eq!(animation.layers[0].weight, 1.0);
+    }
+
+    #[test]
+    fn crossfade_back_reuses_layer() {
+        let mut animation = AnimationState::new(0);
+        animation.crossfade(1, 1.0);
+        animation.advance(0.5, &[]);
+        animation.crossfade(0, 1.0);
+        assert_eq!(animation.layers.len(), 2);
+        assert_eq!(animation.current().unwrap().index, 0);
+    }
+
+    #[test]
+    fn paused_and_speed() {
+        let mut animation = AnimationState::new(0);
+        animation.speed = 2.0;
+        animation.advance(0.5, &[]);
+        assert_eq!(animation.layers[0].time, 1.0);
+        animation.paused = true;
+        animation.advance(0.5, &[]);
+        assert_eq!(animation.layers[0].time, 1.0);
+    }
+}
@@ -0,0 +1,12 @@
+use super::Component;
+
+// Replaces the morph target weights animations give every mesh of the entity
+pub struct MorphWeightsOverride {
+    pub weights: Vec<f32>,
+}
+impl MorphWeightsOverride {
+    pub fn new(weights: Vec<f32>) -> Self {
+        Self { weights }
+    }
+}
+impl Component for MorphWeightsOverride {}
@@ -0,0 +1,28 @@
+use cgmath::One;
+
+use crate::{component_manager::ComponentManager, EntityHandle};
+
+use super::{transform::Transform, Component};
+
+pub struct Parent {
+    pub entity: EntityHandle,
+}
+impl Parent {
+    pub fn new(entity: EntityHandle) -> Self {
+        Self { entity }
+    }
+}
+impl Component for Parent {}
+
+// Combines an entity's transform with those of all of its ancestors
+pub fn global_matrix(cm: &ComponentManager, entity: EntityHandle) -> cgmath::Matrix4<f32> {
+    let mut matrix = cgmath::Matrix4::one();
+    let mut current = Some(entity);
+    while let Some(ent) = current {
+        if let Some(transform) = cm.get_component::<Transform>(ent) {
+            matrix = transform.to_matrix() * matrix;
+        }
+        current = cm.get_component::<Parent>(ent).map(|parent| parent.entity);
+    }
+    matrix
+}
@@ -0,0 +1,5 @@
+use super::Component;
+
+// The entity's meshes are darkened where the shadow map hides them from the sun
+pub struct ReceivesShadow;
+impl Component for ReceivesShadow {}
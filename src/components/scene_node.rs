@@ -0,0 +1,9 @@
+use crate::asset_manager::AssetHandle;
+
+use super::Component;
+
+pub struct SceneNode {
+    pub asset_handle: AssetHandle,
+    pub node: usize,
+}
+impl Component for SceneNode {}
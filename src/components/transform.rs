@@ -6,6 +6,7 @@ pub struct Transform {
     pub position: cgmath::Point3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
     pub forward: cgmath::Vector3<f32>,
+    pub scale: cgmath::Vector3<f32>,
 }
 impl Transform {
     pub fn new(
@@ -29,12 +30,14 @@ impl Transform {
             position,
             rotation,
             forward,
+            scale: cgmath::vec3(1.0, 1.0, 1.0),
         }
     }
     pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
         let translation = cgmath::Matrix4::from_translation(self.position.to_vec());
         let rotation = cgmath::Matrix4::from(self.rotation);
-        translation * rotation
+        let scale = cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z);
+        translation * rotation * scale
     }
 }
 impl Default for Transform {
@@ -43,6 +46,7 @@ impl Default for Transform {
             position: cgmath::Point3::new(0.0, 0.0, 0.0),
             rotation: cgmath::Quaternion::from_angle_y(cgmath::Rad(0.0)),
             forward: cgmath::Vector3::unit_x(),
+            scale: cgmath::vec3(1.0, 1.0, 1.0),
         }
     }
 }
@@ -7,6 +7,10 @@ pub mod window;
 pub mod world;
 pub mod systems;
 pub mod ray;
+pub mod animation;
+pub mod mesh;
+pub mod mesh_processing;
+pub mod shapes;
 
 
 use uuid::Uuid;
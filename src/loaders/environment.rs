@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::renderer::environment::EnvironmentImage;
+
+// Loads the +x, -x, +y, -y, +z and -z faces of a cubemap, which must be square
+// images of one size
+pub fn load_faces(paths: [&Path; 6]) -> anyhow::Result<EnvironmentImage> {
+    let mut size = None;
+    let mut faces = Vec::with_capacity(6);
+    for path in paths {
+        let (width, height, pixels) = load_linear(path)?;
+        anyhow::ensure!(
+            width == height,
+            "Cubemap face {} is {}x{}, faces must be square",
+            path.display(),
+            width,
+            height
+        );
+        let size = *size.get_or_insert(width);
+        anyhow::ensure!(
+            width == size,
+            "Cubemap face {} is {} pixels wide, other faces are {}",
+            path.display(),
+            width,
+            size
+        );
+        faces.push(pixels);
+    }
+    Ok(EnvironmentImage::Faces {
+        size: size.unwrap_or(0),
+        faces,
+    })
+}
+
+// Loads a panorama mapping longitude to x and latitude to y, usually an HDR image
+pub fn load_equirectangular(path: &Path) -> anyhow::Result<EnvironmentImage> {
+    let (width, height, pixels) = load_linear(path)?;
+    Ok(EnvironmentImage::Equirectangular {
+        width,
+        height,
+        pixels,
+    })
+}
+
+// Floating point images hold linear colors, others are sRGB encoded
+fn load_linear(path: &Path) -> anyhow::Result<(u32, u32, Vec<[f32; 4]>)> {
+    let image = image::open(path).with_context(|| format!("Failed to load {}", path.display()))?;
+    let linear = matches!(
+        image,
+        image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_)
+    );
+    let image = image.into_rgba32f();
+    let pixels = image
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            if linear {
+                [r, g, b, a]
+            } else {
+                [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a]
+            }
+        })
+        .collect();
+    Ok((image.width(), image.height(), pixels))
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_png(path: &Path, size: u32, value: u8) {
+        image::RgbaImage::from_pixel(size, size, image::Rgba([value, value, value, 255]))
+            .save(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn faces_are_decoded_to_linear_and_must_match() {
+        let directory = std::env::temp_dir().join(format!("cubemap_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let paths = (0..6)
+            .map(|face| directory.join(format!("{}.png", face)))
+            .collect::<Vec<_>>();
+        for path in &paths {
+            write_png(path, 2, 128);
+        }
+        let faces = [0, 1, 2, 3, 4, 5].map(|face| paths[face].as_path());
+        match load_faces(faces).unwrap() {
+            EnvironmentImage::Faces { size, faces } => {
+                assert_eq!((size, faces.len()), (2, 6));
+                assert!((faces[0][0][0] - 0.2158).abs() < 1e-3);
+                assert_eq!(faces[0][0][3], 1.0);
+            }
+            _ => panic!("Expected cubemap faces"),
+        }
+        write_png(&paths[3], 4, 128);
+        assert!(load_faces(faces).is_err());
+    }
+}
@@ -1,405 +1,975 @@
-use std::collections::HashMap;
+use anyhow::Context;
+use cgmath::{EuclideanSpace, One};
 
-use cgmath::{One, VectorSpace};
-use wgpu::{util::DeviceExt, vertex_attr_array};
+use crate::{
+    animation::{self, AnimationClip, Channel, Interpolation, Keyframes, MorphWeights, Trs},
+    asset_manager::AssetHandle,
+    component_manager::ComponentManager,
+    components::{
+        light::{DirectionalLight, Light, PointLight, SpotLight},
+        model::AnimationState,
+        parent::Parent,
+        scene_node::SceneNode,
+        transform::Transform,
+    },
+    mesh::{
+        AlphaMode, Material, Mesh, MeshAsset, MeshFrameState, MorphTarget, Primitive, Shading,
+        TextureData,
+    },
+    mesh_processing::{self, Bounds, ProcessSettings},
+    world::World,
+    EntityHandle,
+};
 
-use crate::{components::model::AnimationState, renderer::render::Renderer};
-
-type Signature = String;
-
-pub trait DrawGltf<'a> {
-    fn draw_gltf(&mut self, gltf: &'a GltfFrameState);
-}
-impl<'a> DrawGltf<'a> for wgpu::RenderPass<'a> {
-    fn draw_gltf(&mut self, gltf: &'a GltfFrameState) {
-        for (mesh_idx, mesh) in gltf.gltf_file.meshes.iter().enumerate() {
-            for (prim_idx, primitive) in mesh.primitives.iter().enumerate() {
-                let pipeline = gltf
-                    .gltf_file
-                    .render_pipelines
-                    .get(&primitive.signature)
-                    .unwrap();
-                self.set_pipeline(pipeline);
-                self.set_vertex_buffer(
-                    0,
-                    gltf.meshes[mesh_idx][prim_idx]
-                        .instance_buffer
-                        .as_ref()
-                        .unwrap()
-                        .slice(..),
-                );
-                for (i, buffer) in primitive.vertex_buffers.iter().enumerate() {
-                    self.set_vertex_buffer((i + 1) as u32, buffer.slice(..));
-                }
-                self.set_index_buffer(primitive.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                self.draw_indexed(
-                    0..primitive.num_indices,
-                    0,
-                    0..primitive.instances.len() as u32,
-                );
-            }
-        }
-    }
-}
-pub struct GltfFramePrimitiveState {
-    node_instance_map: HashMap<usize, [[f32; 4]; 4]>,
-    instances: Vec<[[f32; 4]; 4]>,
-    instance_buffer: Option<wgpu::Buffer>,
-    num_instances: u32,
-}
-
-pub struct GltfFrameState<'a> {
-    pub meshes: Vec<Vec<GltfFramePrimitiveState>>,
-    pub gltf_file: &'a GltfFile,
-    global_transform: cgmath::Matrix4<f32>,
+pub struct GltfNode {
+    pub rest: Trs,
+    pub mesh: Option<usize>,
+    pub light: Option<usize>,
+    pub children: Vec<usize>,
 }
 
-impl<'a> GltfFrameState<'a> {
-    pub fn new(gltf_file: &'a GltfFile) -> Self {
-        let mut meshes = Vec::new();
-        for mesh in gltf_file.meshes.iter() {
-            let mut primitives = Vec::new();
-            for primitive in mesh.primitives.iter() {
-                let mut node_instance_map = HashMap::new();
-                for instance in primitive.instances.iter() {
-                    let node_idx = instance.parent_node;
-                    node_instance_map.insert(node_idx, instance.transform.into());
-                }
-                let frame_state = GltfFramePrimitiveState {
-                    node_instance_map,
-                    instances: Vec::new(),
-                    instance_buffer: None,
-                    num_instances: 0,
-                };
-                primitives.push(frame_state);
+impl From<gltf::khr_lights_punctual::Light<'_>> for Light {
+    fn from(light: gltf::khr_lights_punctual::Light) -> Self {
+        let (color, intensity, range) = (light.color().into(), light.intensity(), light.range());
+        match light.kind() {
+            gltf::khr_lights_punctual::Kind::Directional => {
+                Light::Directional(DirectionalLight::new(color, intensity))
             }
-            meshes.push(primitives);
-        }
-        Self {
-            meshes,
-            gltf_file,
-            global_transform: cgmath::Matrix4::one(),
-        }
-    }
-    fn update_instance(
-        &mut self,
-        transform: cgmath::Matrix4<f32>,
-        mesh_idx: usize,
-        node_idx: usize,
-    ) {
-        for primitive in self.meshes[mesh_idx].iter_mut() {
-            let prev_transform = primitive
-                .node_instance_map
-                .get(&node_idx)
-                .expect("Unable to apply transform: Node does not exist");
-            let new_transform = transform * cgmath::Matrix4::from(*prev_transform);
-            primitive
-                .node_instance_map
-                .insert(node_idx, new_transform.into());
-        }
-    }
-
-    fn add_node_transform(&mut self, transform: cgmath::Matrix4<f32>, node_idx: usize) {
-        let node = &self.gltf_file.document.nodes().collect::<Vec<_>>()[node_idx];
-        if let Some(mesh) = node.mesh() {
-            self.update_instance(transform, mesh.index(), node.index());
-        } else {
-            println!("Transform does not affect mesh");
-        }
-    }
-
-    pub fn set_animation(&mut self, animation: &AnimationState) {
-        let animations = self.gltf_file.document.animations().collect::<Vec<_>>();
-        let gltf_animation = animations
-            .get(animation.index)
-            .expect("Invalid animation index");
-        for channel in gltf_animation.channels() {
-            let sampler = channel.sampler();
-            let target = channel.target();
-            let node_idx = target.node().index();
-            let reader = channel.reader(|buffer| Some(&self.gltf_file.buffers[buffer.index()]));
-            let inputs = reader.read_inputs().unwrap().collect::<Vec<_>>();
-            let rotations = match reader.read_outputs().unwrap() {
-                gltf::animation::util::ReadOutputs::Rotations(rots) => {
-                 rots
-                        .into_f32()
-                        .map(|r| cgmath::Quaternion::from(r))
-                        .collect::<Vec<_>>()
-                },
-                _ => vec![],
-            };
-            let translations = match reader.read_outputs().unwrap() {
-                gltf::animation::util::ReadOutputs::Translations(trans) => {
-                    trans
-                        .map(|t| cgmath::Vector3::from(t))
-                        .collect::<Vec<_>>()
-                }
-                _ => vec![],
-            };
-
-            if inputs.iter().len() > 0 {
-                let elapsed = (animation.current_time - animation.start_time).as_secs_f32() % inputs.last().unwrap();
-                let rhs_index = inputs.iter().position(|&el| el >= elapsed).unwrap();
-                let lhs_index = if rhs_index > 0 { rhs_index - 1 } else { 0 };
-                let rhs_timestep = inputs[rhs_index];
-                let lhs_timestep = inputs[lhs_index];
-                let slerp_factor = (elapsed - lhs_timestep) / (rhs_timestep - lhs_timestep);
-
-                if target.property() == gltf::animation::Property::Rotation {
-                    let transform = cgmath::Matrix4::from(rotations[lhs_index].slerp(rotations[rhs_index], slerp_factor));
-                    self.add_node_transform(transform, node_idx)
-                }
-                if target.property() == gltf::animation::Property::Translation {
-                    let lhs = translations[lhs_index];
-                    let rhs = translations[rhs_index];
-                    let transform = lhs.lerp(rhs, slerp_factor);
-                    self.add_node_transform(cgmath::Matrix4::from_translation(transform), node_idx)
-                }
-
-
+            gltf::khr_lights_punctual::Kind::Point => {
+                Light::Point(PointLight::new(color, intensity, range))
             }
-
-
-
+            gltf::khr_lights_punctual::Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => Light::Spot(SpotLight::new(
+                color,
+                intensity,
+                range,
+                inner_cone_angle,
+                outer_cone_angle,
+            )),
         }
     }
+}
 
-    pub fn set_global_transform(&mut self, transform: cgmath::Matrix4<f32>) {
-        self.global_transform = transform;
-    }
-
-    pub fn init_buffers(&mut self, device: &wgpu::Device) {
-        for mesh in self.meshes.iter_mut() {
-            for primitive in mesh.iter_mut() {
-                primitive.instances = primitive
-                    .node_instance_map
-                    .iter()
-                    .map(|(_, p)| (self.global_transform * cgmath::Matrix4::from(p.clone())).into())
-                    .collect();
-                primitive.instance_buffer = Some(device.create_buffer_init(
-                    &wgpu::util::BufferInitDescriptor {
-                        label: None,
-                        contents: bytemuck::cast_slice(&primitive.instances),
-                        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-                    },
-                ));
-            }
+impl From<gltf::scene::Transform> for Trs {
+    fn from(transform: gltf::scene::Transform) -> Self {
+        let (translation, rotation, scale) = transform.decomposed();
+        Trs {
+            translation: translation.into(),
+            rotation: rotation.into(),
+            scale: scale.into(),
+            weights: MorphWeights::default(),
         }
     }
 }
 
-pub struct GltfMesh {
-    pub primitives: Vec<GltfPrimitive>,
-}
-
-#[repr(C)]
-#[derive(bytemuck::Pod, Copy, Clone, bytemuck::Zeroable)]
-pub struct Instance {
-    parent_node: usize,
-    transform: [[f32; 4]; 4],
-}
-
-pub struct GltfPrimitive {
-    pub signature: Signature,
-    pub vertex_buffers: Vec<wgpu::Buffer>,
-    pub index_buffer: wgpu::Buffer,
-    pub num_indices: u32,
-    pub instances: Vec<Instance>,
+pub struct GltfScene {
+    pub name: Option<String>,
+    pub roots: Vec<usize>,
 }
 
 pub struct GltfFile {
     pub path: String,
     pub document: gltf::Document,
     pub buffers: Vec<gltf::buffer::Data>,
-    pub images: Vec<gltf::image::Data>,
-    pub render_pipelines: HashMap<Signature, wgpu::RenderPipeline>,
-    pub meshes: Vec<GltfMesh>,
+    // Node meshes index into the meshes of this asset
+    pub mesh: MeshAsset,
+    pub lights: Vec<Light>,
+    pub nodes: Vec<GltfNode>,
+    pub scenes: Vec<GltfScene>,
+    pub default_scene: usize,
+    pub animations: Vec<AnimationClip>,
 }
 impl GltfFile {
-    pub fn new(path: &str, renderer: &Renderer) -> Self {
-        
-        let file = std::fs::File::open(path);
-        let (document, buffers, images) = gltf::import(path).unwrap();
-
-        Self {
+    // Accepts both .gltf and binary .glb files
+    pub fn new(path: &str) -> anyhow::Result<Self> {
+        let (document, buffers, images) =
+            gltf::import(path).with_context(|| format!("Failed to load glTF file {}", path))?;
+        Self::from_import(path, document, buffers, images)
+    }
+    // Loads a .gltf or .glb held in memory, e.g. from include_bytes!.
+    // Buffers and images must be embedded as data uris or in the glb binary chunk.
+    pub fn from_slice(label: &str, bytes: &[u8]) -> anyhow::Result<Self> {
+        let (document, buffers, images) = gltf::import_slice(bytes)
+            .with_context(|| format!("Failed to load glTF data {}", label))?;
+        Self::from_import(label, document, buffers, images)
+    }
+    fn from_import(
+        path: &str,
+        document: gltf::Document,
+        buffers: Vec<gltf::buffer::Data>,
+        images: Vec<gltf::image::Data>,
+    ) -> anyhow::Result<Self> {
+        let (scenes, default_scene) = Self::build_scenes(&document);
+        let (materials, tex_coords) = Self::build_materials(&document);
+        let mesh = MeshAsset::new(
+            path,
+            Self::build_meshes(&document, &buffers, &materials, &tex_coords)
+                .with_context(|| format!("Failed to load glTF meshes of {}", path))?,
+            materials,
+            Self::build_textures(&document, &images),
+        );
+        Ok(Self {
             path: String::from(path),
-            render_pipelines: Self::build_pipelines(&document, renderer),
-            meshes: Self::build_meshes(&document, &buffers, renderer),
+            nodes: Self::build_nodes(&document),
+            lights: Self::build_lights(&document),
+            animations: Self::build_animations(&document, &buffers),
+            mesh,
+            scenes,
+            default_scene,
             document,
             buffers,
-            images,
-        }
+        })
     }
-    fn sign_primitive(primitive: &gltf::Primitive) -> Signature {
-        let mut signature = String::new();
-        primitive.attributes().for_each(|(name, _)| {
-            signature.push_str(&name.to_string());
-        });
-        signature
+    // Draws the default scene in its rest pose
+    pub fn frame_state(&self) -> MeshFrameState<'_> {
+        let transforms = self.global_transforms(&self.rest_pose());
+        let scene_nodes = self.scene_nodes(self.default_scene);
+        MeshFrameState::with_instances(&self.mesh, |mesh_idx| {
+            scene_nodes
+                .iter()
+                .filter(|&&node_idx| self.nodes[node_idx].mesh == Some(mesh_idx))
+                .map(|&node_idx| (node_idx, transforms[node_idx], self.nodes[node_idx].rest.weights))
+                .collect()
+        })
     }
-    fn build_meshes(
-        document: &gltf::Document,
-        buffers: &[gltf::buffer::Data],
-        renderer: &Renderer,
-    ) -> Vec<GltfMesh> {
-        let mut meshes = Vec::new();
-        for mesh in document.meshes() {
-            let mut primitives = Vec::new();
-            for primitive in mesh.primitives() {
-                let primitive = Self::build_primitive(primitive, buffers, renderer);
-                primitives.push(primitive);
+    // Draws a single node's mesh, positioned only by the global transform
+    pub fn node_frame_state(&self, node_idx: usize) -> MeshFrameState<'_> {
+        let node = &self.nodes[node_idx];
+        MeshFrameState::with_instances(&self.mesh, |mesh_idx| {
+            if node.mesh == Some(mesh_idx) {
+                vec![(node_idx, cgmath::Matrix4::one(), node.rest.weights)]
+            } else {
+                Vec::new()
             }
-            meshes.push(GltfMesh { primitives });
+        })
+    }
+    pub fn apply_pose(&self, frame_state: &mut MeshFrameState, pose: &[Trs]) {
+        let weights = pose.iter().map(|trs| trs.weights).collect::<Vec<_>>();
+        frame_state.set_pose(&self.global_transforms(pose), &weights);
+    }
+    pub fn apply_animation(&self, frame_state: &mut MeshFrameState, animation: &AnimationState) {
+        self.apply_pose(frame_state, &self.sample_pose(animation));
+    }
+    pub fn add_animation_event(&mut self, animation: usize, name: &str, time: f32) {
+        self.animations
+            .get_mut(animation)
+            .expect("Invalid animation index")
+            .add_event(name, time);
+    }
+    pub fn rest_pose(&self) -> Vec<Trs> {
+        self.nodes.iter().map(|node| node.rest).collect()
+    }
+    pub fn sample_pose(&self, animation: &AnimationState) -> Vec<Trs> {
+        animation::sample_pose(&self.rest_pose(), &self.animations, animation)
+    }
+    pub fn scene_index(&self, name: &str) -> Option<usize> {
+        self.scenes
+            .iter()
+            .position(|scene| scene.name.as_deref() == Some(name))
+    }
+    // All nodes reachable from the roots of a scene
+    pub fn scene_nodes(&self, scene: usize) -> Vec<usize> {
+        let mut scene_nodes = Vec::new();
+        let mut nodes = self.scenes[scene].roots.clone();
+        while let Some(node_idx) = nodes.pop() {
+            scene_nodes.push(node_idx);
+            nodes.extend(self.nodes[node_idx].children.iter().copied());
         }
-        let mut nodes = Vec::new();
-        let mut transforms = Vec::new();
-        for node in document.scenes().next().unwrap().nodes() {
-            transforms.push(cgmath::Matrix4::from(node.transform().matrix()));
-            nodes.push(node);
+        scene_nodes
+    }
+    // Propagates local node transforms down the default scene graph
+    pub fn global_transforms(&self, pose: &[Trs]) -> Vec<cgmath::Matrix4<f32>> {
+        let mut transforms = vec![cgmath::Matrix4::one(); self.nodes.len()];
+        let mut nodes: Vec<(usize, cgmath::Matrix4<f32>)> = self.scenes[self.default_scene]
+            .roots
+            .iter()
+            .map(|&root| (root, cgmath::Matrix4::one()))
+            .collect();
+        while let Some((node_idx, parent)) = nodes.pop() {
+            let transform = parent * pose[node_idx].to_matrix();
+            transforms[node_idx] = transform;
+            for &child in self.nodes[node_idx].children.iter() {
+                nodes.push((child, transform));
+            }
         }
-        // Traverse scene graph in order to populate transforms
-        while !nodes.is_empty() {
-            let node = nodes.pop().unwrap();
-            let transform = transforms.pop().unwrap();
-            if let Some(mesh) = node.mesh() {
-                let mesh = &mut meshes[mesh.index()];
-                for primitive in mesh.primitives.iter_mut() {
-                    primitive.instances.push(Instance {
-                        parent_node: node.index(),
-                        transform: transform.into(),
-                    });
-                }
+        transforms
+    }
+    // Spawns one entity per node of a scene, parented to a new scene root entity
+    pub fn spawn_scene(
+        &self,
+        scene: usize,
+        asset_handle: AssetHandle,
+        world: &mut World,
+        cm: &mut ComponentManager,
+    ) -> EntityHandle {
+        let root = world.spawn();
+        cm.add_component(Transform::default(), root);
+        let mut nodes: Vec<(usize, EntityHandle)> = self.scenes[scene]
+            .roots
+            .iter()
+            .map(|&node_idx| (node_idx, root))
+            .collect();
+        while let Some((node_idx, parent)) = nodes.pop() {
+            let entity = world.spawn();
+            let rest = self.nodes[node_idx].rest;
+            let mut transform = Transform::new(
+                Some(cgmath::Point3::from_vec(rest.translation)),
+                Some(rest.rotation),
+                None,
+            );
+            transform.scale = rest.scale;
+            cm.add_component(transform, entity);
+            cm.add_component(Parent::new(parent), entity);
+            cm.add_component(
+                SceneNode {
+                    asset_handle,
+                    node: node_idx,
+                },
+                entity,
+            );
+            if let Some(light) = self.nodes[node_idx].light {
+                self.lights[light].add_to(entity, cm);
             }
-            for child in node.children() {
-                transforms.push(cgmath::Matrix4::from(child.transform().matrix()) * transform);
-                nodes.push(child);
+            for &child in self.nodes[node_idx].children.iter() {
+                nodes.push((child, entity));
             }
         }
-        meshes
+        root
     }
-    fn build_primitive(
-        primitive: gltf::Primitive,
-        buffers: &[gltf::buffer::Data],
-        renderer: &Renderer,
-    ) -> GltfPrimitive {
-        let mut vertex_buffers = Vec::new();
-        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-        let positions = reader.read_positions().unwrap().collect::<Vec<[f32; 3]>>();
-        let buffer = renderer
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: bytemuck::cast_slice(&positions),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-        vertex_buffers.push(buffer);
-        let normals = match reader.read_normals() {
-            Some(normals) => normals.collect::<Vec<[f32; 3]>>(),
-            None => vec!([0.0, 0.0, 0.0])
-        };
-        let buffer = renderer
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: bytemuck::cast_slice(&normals),
-                usage: wgpu::BufferUsages::VERTEX,
+    // Spawns the default scene's lights as children of an entity drawing the whole file
+    pub fn spawn_lights(&self, parent: EntityHandle, world: &mut World, cm: &mut ComponentManager) {
+        let transforms = self.global_transforms(&self.rest_pose());
+        for node_idx in self.scene_nodes(self.default_scene) {
+            let light = match self.nodes[node_idx].light {
+                Some(light) => light,
+                None => continue,
+            };
+            let trs = Trs::from_matrix(transforms[node_idx]);
+            let entity = world.spawn();
+            let mut transform = Transform::new(
+                Some(cgmath::Point3::from_vec(trs.translation)),
+                Some(trs.rotation),
+                None,
+            );
+            transform.scale = trs.scale;
+            cm.add_component(transform, entity);
+            cm.add_component(Parent::new(parent), entity);
+            self.lights[light].add_to(entity, cm);
+        }
+    }
+    fn build_nodes(document: &gltf::Document) -> Vec<GltfNode> {
+        document
+            .nodes()
+            .map(|node| {
+                let mut rest = Trs::from(node.transform());
+                let weights = node
+                    .weights()
+                    .or_else(|| node.mesh().and_then(|mesh| mesh.weights()));
+                if let Some(weights) = weights {
+                    rest.weights = MorphWeights::from_slice(weights);
+                }
+                GltfNode {
+                    rest,
+                    mesh: node.mesh().map(|mesh| mesh.index()),
+                    light: node.light().map(|light| light.index()),
+                    children: node.children().map(|child| child.index()).collect(),
+                }
+            })
+            .collect()
+    }
+    fn build_scenes(document: &gltf::Document) -> (Vec<GltfScene>, usize) {
+        let mut scenes: Vec<GltfScene> = document
+            .scenes()
+            .map(|scene| GltfScene {
+                name: scene.name().map(String::from),
+                roots: scene.nodes().map(|node| node.index()).collect(),
+            })
+            .collect();
+        // Files without scenes still describe a hierarchy through their parentless nodes
+        if scenes.is_empty() {
+            let mut is_child = vec![false; document.nodes().len()];
+            for node in document.nodes() {
+                for child in node.children() {
+                    is_child[child.index()] = true;
+                }
+            }
+            scenes.push(GltfScene {
+                name: None,
+                roots: (0..is_child.len()).filter(|&node_idx| !is_child[node_idx]).collect(),
             });
-        vertex_buffers.push(buffer);
-        let (num_indices, index_buffer) = if let Some(indices) = reader.read_indices() {
-            let indices: Vec<u32> = indices.into_u32().collect();
-            (
-                indices.len() as u32,
-                renderer
-                    .device
-                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                        label: None,
-                        contents: bytemuck::cast_slice(&indices),
-                        usage: wgpu::BufferUsages::INDEX,
-                    }),
-            )
-        } else {
-            panic!("No indices");
-        };
-        GltfPrimitive {
-            vertex_buffers,
-            index_buffer,
-            signature: Self::sign_primitive(&primitive),
-            num_indices,
-            instances: Vec::new(),
         }
+        let default_scene = document.default_scene().map_or(0, |scene| scene.index());
+        (scenes, default_scene)
     }
-    fn build_pipelines(
+    fn build_animations(
         document: &gltf::Document,
-        renderer: &Renderer,
-    ) -> HashMap<Signature, wgpu::RenderPipeline> {
-        let mut render_pipelines = HashMap::new();
-        for mesh in document.meshes() {
-            for primitive in mesh.primitives() {
-                let signature = Self::sign_primitive(&primitive);
-                if !render_pipelines.contains_key(&signature) {
-                    let pipeline = Self::build_pipeline(primitive, renderer);
-                    render_pipelines.insert(signature, pipeline);
+        buffers: &[gltf::buffer::Data],
+    ) -> Vec<AnimationClip> {
+        use gltf::animation::util::ReadOutputs;
+        document
+            .animations()
+            .map(|animation| {
+                let channels = animation
+                    .channels()
+                    .filter_map(|channel| {
+                        let reader =
+                            channel.reader(|buffer| Some(&buffers[buffer.index()]));
+                        let inputs = reader.read_inputs()?.collect::<Vec<_>>();
+                        if inputs.is_empty() {
+                            return None;
+                        }
+                        let outputs = match reader.read_outputs()? {
+                            ReadOutputs::Translations(translations) => {
+                                Keyframes::Translations(translations.map(Into::into).collect())
+                            }
+                            ReadOutputs::Rotations(rotations) => {
+                                Keyframes::Rotations(rotations.into_f32().map(Into::into).collect())
+                            }
+                            ReadOutputs::Scales(scales) => {
+                                Keyframes::Scales(scales.map(Into::into).collect())
+                            }
+                            ReadOutputs::MorphTargetWeights(weights) => {
+                                let weights = weights.into_f32().collect::<Vec<_>>();
+                                let values_per_key = match channel.sampler().interpolation() {
+                                    gltf::animation::Interpolation::CubicSpline => 3,
+                                    _ => 1,
+                                };
+                                let target_count = weights.len() / (inputs.len() * values_per_key);
+                                if target_count == 0 {
+                                    return None;
+                                }
+                                Keyframes::Weights(
+                                    weights
+                                        .chunks(target_count)
+                                        .map(MorphWeights::from_slice)
+                                        .collect(),
+                                )
+                            }
+                        };
+                        let interpolation = match channel.sampler().interpolation() {
+                            gltf::animation::Interpolation::Step => Interpolation::Step,
+                            gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                            gltf::animation::Interpolation::CubicSpline => {
+                                Interpolation::CubicSpline
+                            }
+                        };
+                        Some(Channel {
+                            node: channel.target().node().index(),
+                            interpolation,
+                            inputs,
+                            outputs,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let duration = channels
+                    .iter()
+                    .filter_map(|channel| channel.inputs.last().copied())
+                    .fold(0.0, f32::max);
+                AnimationClip {
+                    name: animation.name().map(String::from),
+                    duration,
+                    channels,
+                    events: Vec::new(),
                 }
-            }
+            })
+            .collect()
+    }
+    fn build_lights(document: &gltf::Document) -> Vec<Light> {
+        match document.lights() {
+            Some(lights) => lights.map(Light::from).collect(),
+            None => Vec::new(),
         }
-        render_pipelines
     }
-    fn build_pipeline(primitive: gltf::Primitive, renderer: &Renderer) -> wgpu::RenderPipeline {
-        let positions = primitive
-            .get(&gltf::Semantic::Positions)
-            .unwrap_or_else(|| {
-                panic!("No positions for primitive");
-            });
-        let positions_buffer_layout = wgpu::VertexBufferLayout {
-            array_stride: positions.size() as wgpu::BufferAddress,
-            step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: &vertex_attr_array![4 => Float32x3],
+    fn build_textures(document: &gltf::Document, images: &[gltf::image::Data]) -> Vec<TextureData> {
+        document
+            .textures()
+            .map(|texture| {
+                let image = &images[texture.source().index()];
+                TextureData {
+                    name: texture.name().map(String::from),
+                    width: image.width,
+                    height: image.height,
+                    pixels: Self::rgba8(image),
+                    sampler: Self::sampler_descriptor(&texture.sampler()),
+                }
+            })
+            .collect()
+    }
+    fn rgba8(image: &gltf::image::Data) -> Vec<u8> {
+        use gltf::image::Format;
+        let (channels, size) = match image.format {
+            Format::R8 => (1, 1),
+            Format::R8G8 => (2, 1),
+            Format::R8G8B8 => (3, 1),
+            Format::R8G8B8A8 => (4, 1),
+            Format::R16 => (1, 2),
+            Format::R16G16 => (2, 2),
+            Format::R16G16B16 => (3, 2),
+            Format::R16G16B16A16 => (4, 2),
+            Format::R32G32B32FLOAT => (3, 4),
+            Format::R32G32B32A32FLOAT => (4, 4),
         };
-        let normals = match primitive.get(&gltf::Semantic::Normals) {
-            Some(accessor) => accessor.size(),
-            None => {
-                println!("Using default normals");
-                std::mem::size_of::<[f32;3]>()
-            }
+        let channel = |bytes: &[u8]| match size {
+            1 => bytes[0],
+            2 => (u16::from_ne_bytes([bytes[0], bytes[1]]) >> 8) as u8,
+            _ => (f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).clamp(0.0, 1.0)
+                * 255.0)
+                .round() as u8,
         };
-        let normals_buffer_layout = wgpu::VertexBufferLayout {
-            array_stride: normals as wgpu::BufferAddress,
-            step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: &vertex_attr_array![5 => Float32x3],
+        image
+            .pixels
+            .chunks_exact(channels * size)
+            .flat_map(|pixel| {
+                let values = pixel.chunks_exact(size).map(channel).collect::<Vec<u8>>();
+                // One and two channel images are luminance with optional alpha
+                match channels {
+                    1 => [values[0], values[0], values[0], 255],
+                    2 => [values[0], values[0], values[0], values[1]],
+                    3 => [values[0], values[1], values[2], 255],
+                    _ => [values[0], values[1], values[2], values[3]],
+                }
+            })
+            .collect()
+    }
+    fn sampler_descriptor(sampler: &gltf::texture::Sampler) -> wgpu::SamplerDescriptor<'static> {
+        use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+        let address_mode = |mode: WrappingMode| match mode {
+            WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+            WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+            WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+        };
+        let mag_filter = match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => wgpu::FilterMode::Nearest,
+            _ => wgpu::FilterMode::Linear,
+        };
+        let min_filter = match sampler.min_filter() {
+            Some(MinFilter::Nearest)
+            | Some(MinFilter::NearestMipmapNearest)
+            | Some(MinFilter::NearestMipmapLinear) => wgpu::FilterMode::Nearest,
+            _ => wgpu::FilterMode::Linear,
         };
-        let instances_buffer_layout = wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<[[f32; 4]; 4]>() as wgpu::BufferAddress,
-            step_mode: wgpu::VertexStepMode::Instance,
-            attributes: &vertex_attr_array![0 => Float32x4, 1 => Float32x4, 2 => Float32x4, 3 => Float32x4],
+        wgpu::SamplerDescriptor {
+            address_mode_u: address_mode(sampler.wrap_s()),
+            address_mode_v: address_mode(sampler.wrap_t()),
+            mag_filter,
+            min_filter,
+            ..Default::default()
+        }
+    }
+    // Texture coordinate set and KHR_texture_transform matrix of a texture reference
+    fn texture_transform(info: &gltf::texture::Info) -> (u32, cgmath::Matrix3<f32>) {
+        let transform = match info.texture_transform() {
+            Some(transform) => transform,
+            None => return (info.tex_coord(), cgmath::Matrix3::one()),
         };
-        let targets = &[Some(wgpu::ColorTargetState::from(
-            renderer.surface_config.format,
-        ))];
-        let fragment = wgpu::FragmentState {
-            module: &renderer.default_pipeline.pn_shader,
-            entry_point: "fs_main",
-            targets,
+        let [x, y] = transform.offset();
+        let (sin, cos) = transform.rotation().sin_cos();
+        let [scale_x, scale_y] = transform.scale();
+        let rotation = cgmath::Matrix3::new(cos, -sin, 0.0, sin, cos, 0.0, 0.0, 0.0, 1.0);
+        (
+            transform.tex_coord().unwrap_or(info.tex_coord()),
+            cgmath::Matrix3::from_translation(cgmath::vec2(x, y))
+                * rotation
+                * cgmath::Matrix3::from_nonuniform_scale(scale_x, scale_y),
+        )
+    }
+    // Also returns the texture coordinate set each material samples
+    fn build_materials(document: &gltf::Document) -> (Vec<Material>, Vec<u32>) {
+        document
+            .materials()
+            .map(|material| {
+                let pbr = material.pbr_metallic_roughness();
+                let (base_color_texture, tex_coord, uv_transform) = match pbr.base_color_texture() {
+                    Some(info) => {
+                        let (tex_coord, uv_transform) = Self::texture_transform(&info);
+                        (Some(info.texture().index()), tex_coord, uv_transform)
+                    }
+                    None => (None, 0, cgmath::Matrix3::one()),
+                };
+                let material = Material {
+                    name: material.name().map(String::from),
+                    base_color: pbr.base_color_factor(),
+                    base_color_texture,
+                    normal_texture: material
+                        .normal_texture()
+                        .map(|normal| normal.texture().index()),
+                    uv_transform,
+                    unlit: material.unlit(),
+                    alpha_mode: match material.alpha_mode() {
+                        gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                        gltf::material::AlphaMode::Mask => {
+                            AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5))
+                        }
+                        gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+                    },
+                    double_sided: material.double_sided(),
+                    ..Default::default()
+                };
+                (material, tex_coord)
+            })
+            .unzip()
+    }
+    fn build_meshes(
+        document: &gltf::Document,
+        buffers: &[gltf::buffer::Data],
+        materials: &[Material],
+        tex_coords: &[u32],
+    ) -> anyhow::Result<Vec<Mesh>> {
+        document
+            .meshes()
+            .map(|mesh| {
+                // Only triangle lists are drawn, points and lines are left out
+                let primitives = mesh
+                    .primitives()
+                    .filter(|primitive| {
+                        let triangles = primitive.mode() == gltf::mesh::Mode::Triangles;
+                        if !triangles {
+                            log::warn!(
+                                "Skipping {:?} primitive {} of mesh {}",
+                                primitive.mode(),
+                                primitive.index(),
+                                mesh.index()
+                            );
+                        }
+                        triangles
+                    })
+                    .map(|primitive| {
+                        Self::build_primitive(primitive, buffers, materials, tex_coords)
+                    })
+                    .collect::<anyhow::Result<_>>()
+                    .with_context(|| format!("Failed to load mesh {}", mesh.index()))?;
+                Ok(Mesh {
+                    name: mesh.name().map(String::from),
+                    primitives,
+                })
+            })
+            .collect()
+    }
+    fn build_primitive(
+        primitive: gltf::Primitive,
+        buffers: &[gltf::buffer::Data],
+        materials: &[Material],
+        tex_coords: &[u32],
+    ) -> anyhow::Result<Primitive> {
+        let material = primitive.material().index();
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let positions = reader
+            .read_positions()
+            .with_context(|| format!("Primitive {} has no positions", primitive.index()))?
+            .collect::<Vec<[f32; 3]>>();
+        let normals = reader.read_normals().map(|normals| normals.collect::<Vec<[f32; 3]>>());
+        let tangents = reader.read_tangents().map(|tangents| tangents.collect::<Vec<[f32; 4]>>());
+        let tex_coord = material.map_or(0, |material| tex_coords[material]);
+        let uvs = match reader.read_tex_coords(tex_coord) {
+            Some(uvs) => uvs.into_f32().collect::<Vec<[f32; 2]>>(),
+            None => vec![[0.0, 0.0]; positions.len()],
         };
-        let vertex = wgpu::VertexState {
-            module: &renderer.default_pipeline.pn_shader,
-            entry_point: "vs_main",
-            buffers: &[
-                instances_buffer_layout,
-                positions_buffer_layout,
-                normals_buffer_layout,
-            ],
+        // Primitives without indices draw their vertices in order
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
         };
-        renderer
-            .device
-            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: None,
-                layout: Some(&renderer.default_pipeline.layout),
-                vertex,
-                fragment: Some(fragment),
-                primitive: renderer.default_pipeline.primitive,
-                depth_stencil: Some(renderer.default_pipeline.depth_stencil.clone()),
-                multisample: renderer.default_pipeline.multisample,
-                multiview: renderer.default_pipeline.multiview,
+        let morph_targets = reader
+            .read_morph_targets()
+            .map(|(target_positions, target_normals, _)| MorphTarget {
+                positions: match target_positions {
+                    Some(deltas) => deltas.collect(),
+                    None => vec![[0.0; 3]; positions.len()],
+                },
+                normals: match target_normals {
+                    Some(deltas) => deltas.collect(),
+                    None => vec![[0.0; 3]; positions.len()],
+                },
             })
+            .collect();
+        let normal_mapped =
+            material.is_some_and(|material| materials[material].normal_texture.is_some());
+        let generate_normals = normals.is_none();
+        let generate_tangents = normal_mapped && (tangents.is_none() || generate_normals);
+        let mut primitive = Primitive {
+            positions,
+            normals: normals.unwrap_or_default(),
+            uvs,
+            tangents: tangents.unwrap_or_default(),
+            indices,
+            material,
+            morph_targets,
+            dropped_morph_targets: 0,
+            lods: Vec::new(),
+            bounds: Bounds::default(),
+        };
+        primitive.limit_morph_targets();
+        // The spec asks for flat normals when they are missing, and tangents computed
+        // from them when a normal map needs them
+        if generate_normals {
+            primitive.generate_normals(Shading::Flat);
+        }
+        if generate_tangents {
+            primitive.generate_tangents();
+        }
+        mesh_processing::process(&mut primitive, ProcessSettings::IMPORT);
+        Ok(primitive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::*;
+    use crate::animation::MAX_MORPH_TARGETS;
+
+    fn document(json: &str) -> gltf::Document {
+        gltf::Gltf::from_slice(json.as_bytes()).unwrap().document
+    }
+
+    #[test]
+    fn default_scene_is_respected() {
+        let document = document(
+            r#"{
+                "asset": { "version": "2.0" },
+                "scene": 1,
+                "scenes": [{ "name": "a", "nodes": [0] }, { "name": "b", "nodes": [1] }],
+                "nodes": [{}, { "children": [2] }, {}]
+            }"#,
+        );
+        let (scenes, default_scene) = GltfFile::build_scenes(&document);
+        assert_eq!(scenes.len(), 2);
+        assert_eq!(default_scene, 1);
+        assert_eq!(scenes[1].name.as_deref(), Some("b"));
+        assert_eq!(scenes[1].roots, vec![1]);
+    }
+
+    #[test]
+    fn missing_scenes_use_parentless_nodes() {
+        let document = document(
+            r#"{
+                "asset": { "version": "2.0" },
+                "nodes": [{ "children": [1] }, {}, { "translation": [1.0, 2.0, 3.0] }]
+            }"#,
+        );
+        let (scenes, default_scene) = GltfFile::build_scenes(&document);
+        assert_eq!(default_scene, 0);
+        assert_eq!(scenes[0].roots, vec![0, 2]);
+        let nodes = GltfFile::build_nodes(&document);
+        assert_eq!(nodes[0].children, vec![1]);
+        assert_eq!(nodes[2].rest.translation, cgmath::vec3(1.0, 2.0, 3.0));
+    }
+
+    // A node sliding two units along x over one second, with its buffer either
+    // in the glb binary chunk or embedded as a data uri
+    fn sliding_node(uri: Option<&str>) -> String {
+        let uri = uri.map_or(String::new(), |uri| format!(r#""uri": "{}", "#, uri));
+        format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "nodes": [{{}}],
+                "buffers": [{{ {}"byteLength": 32 }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteLength": 8 }},
+                    {{ "buffer": 0, "byteOffset": 8, "byteLength": 24 }}
+                ],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 2, "type": "SCALAR", "min": [0.0], "max": [1.0] }},
+                    {{ "bufferView": 1, "componentType": 5126, "count": 2, "type": "VEC3" }}
+                ],
+                "animations": [{{
+                    "samplers": [{{ "input": 0, "output": 1 }}],
+                    "channels": [{{ "sampler": 0, "target": {{ "node": 0, "path": "translation" }} }}]
+                }}]
+            }}"#,
+            uri
+        )
+    }
+
+    fn glb(json: &str, bin: &[u8]) -> Vec<u8> {
+        let mut json = json.as_bytes().to_vec();
+        while json.len() % 4 != 0 {
+            json.push(b' ');
+        }
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        let mut glb = Vec::new();
+        glb.extend_from_slice(b"glTF");
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&(length as u32).to_le_bytes());
+        glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"JSON");
+        glb.extend_from_slice(&json);
+        glb.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"BIN\0");
+        glb.extend_from_slice(bin);
+        glb
+    }
+
+    fn slide_at(bytes: &[u8], time: f32) -> cgmath::Vector3<f32> {
+        let gltf_file = GltfFile::from_slice("sliding node", bytes).unwrap();
+        gltf_file.animations[0].translation_at(0, time).unwrap()
+    }
+
+    #[test]
+    fn load_glb_from_memory() {
+        let bin: Vec<u8> = [0.0f32, 1.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let glb = glb(&sliding_node(None), &bin);
+        assert_eq!(slide_at(&glb, 0.5), cgmath::vec3(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn corrupted_glb_is_an_error() {
+        let mut glb = glb(&sliding_node(None), &[0; 32]);
+        glb.truncate(glb.len() / 2);
+        let error = GltfFile::from_slice("truncated", &glb).err().unwrap();
+        assert!(error.to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn load_data_uri_from_memory() {
+        let json = sliding_node(Some(
+            "data:application/octet-stream;base64,AAAAAAAAgD8AAAAAAAAAAAAAAAAAAABAAAAAAAAAAAA=",
+        ));
+        assert_eq!(slide_at(json.as_bytes(), 1.0), cgmath::vec3(2.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn missing_normals_are_flat_and_normal_maps_get_tangents() {
+        let document = document(
+            r#"{
+                "asset": { "version": "2.0" },
+                "images": [{ "uri": "normal.png" }],
+                "textures": [{ "source": 0 }],
+                "materials": [{ "normalTexture": { "index": 0 } }],
+                "buffers": [{ "byteLength": 104 }],
+                "bufferViews": [
+                    { "buffer": 0, "byteLength": 48 },
+                    { "buffer": 0, "byteOffset": 48, "byteLength": 32 },
+                    { "buffer": 0, "byteOffset": 80, "byteLength": 24 }
+                ],
+                "accessors": [
+                    { "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3", "min": [-1, 0, -1], "max": [1, 0, 1] },
+                    { "bufferView": 1, "componentType": 5126, "count": 4, "type": "VEC2" },
+                    { "bufferView": 2, "componentType": 5125, "count": 6, "type": "SCALAR" }
+                ],
+                "meshes": [{
+                    "primitives": [{ "attributes": { "POSITION": 0, "TEXCOORD_0": 1 }, "indices": 2, "material": 0 }]
+                }]
+            }"#,
+        );
+        let positions = [-1.0f32, 0.0, -1.0, -1.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0, 0.0, -1.0];
+        let uvs = [0.0f32, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 0.0];
+        let mut bin: Vec<u8> = positions
+            .iter()
+            .chain(uvs.iter())
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        bin.extend([0u32, 1, 2, 0, 2, 3].iter().flat_map(|index| index.to_le_bytes()));
+        let (materials, tex_coords) = GltfFile::build_materials(&document);
+        assert_eq!(materials[0].normal_texture, Some(0));
+        let buffers = [gltf::buffer::Data(bin)];
+        let meshes = GltfFile::build_meshes(&document, &buffers, &materials, &tex_coords).unwrap();
+        let primitive = &meshes[0].primitives[0];
+        // The flat shaded corners are welded again as both triangles face the same way
+        assert_eq!(primitive.positions.len(), 4);
+        assert_eq!(primitive.indices.len(), 6);
+        assert!(primitive.normals.iter().all(|&normal| normal == [0.0, 1.0, 0.0]));
+        assert!(primitive.tangents.iter().all(|&tangent| tangent == [1.0, 0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn morph_targets_past_the_limit_are_dropped() {
+        let targets = vec![r#"{ "POSITION": 0 }"#; MAX_MORPH_TARGETS + 1].join(", ");
+        let document = document(&format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{ "byteLength": 48 }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 12 }}
+                ],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }},
+                    {{ "bufferView": 1, "componentType": 5125, "count": 3, "type": "SCALAR" }}
+                ],
+                "meshes": [{{
+                    "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "targets": [{}] }}]
+                }}]
+            }}"#,
+            targets
+        ));
+        let mut bin: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        bin.extend([0u32, 1, 2].iter().flat_map(|index| index.to_le_bytes()));
+        let buffers = [gltf::buffer::Data(bin)];
+        let meshes = GltfFile::build_meshes(&document, &buffers, &[], &[]).unwrap();
+        let primitive = &meshes[0].primitives[0];
+        assert_eq!(primitive.morph_targets.len(), MAX_MORPH_TARGETS);
+        assert_eq!(primitive.dropped_morph_targets, 1);
+    }
+
+    #[test]
+    fn line_primitives_are_skipped() {
+        let document = document(
+            r#"{
+                "asset": { "version": "2.0" },
+                "buffers": [{ "byteLength": 48 }],
+                "bufferViews": [
+                    { "buffer": 0, "byteLength": 36 },
+                    { "buffer": 0, "byteOffset": 36, "byteLength": 12 }
+                ],
+                "accessors": [
+                    { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] },
+                    { "bufferView": 1, "componentType": 5125, "count": 3, "type": "SCALAR" }
+                ],
+                "meshes": [{
+                    "primitives": [
+                        { "attributes": { "POSITION": 0 }, "indices": 1, "mode": 1 },
+                        { "attributes": { "POSITION": 0 }, "indices": 1 }
+                    ]
+                }]
+            }"#,
+        );
+        let mut bin: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        bin.extend([0u32, 1, 2].iter().flat_map(|index| index.to_le_bytes()));
+        let buffers = [gltf::buffer::Data(bin)];
+        let meshes = GltfFile::build_meshes(&document, &buffers, &[], &[]).unwrap();
+        assert_eq!(meshes[0].primitives.len(), 1);
+        assert_eq!(meshes[0].primitives[0].indices.len(), 3);
+    }
+
+    // A triangle without indices, whose primitive has the given attributes
+    fn unindexed_triangle(attributes: &str) -> anyhow::Result<Vec<Mesh>> {
+        let json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{ "byteLength": 36 }}],
+                "bufferViews": [{{ "buffer": 0, "byteLength": 36 }}],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }}
+                ],
+                "meshes": [{{ "primitives": [{{ "attributes": {{ {} }} }}] }}]
+            }}"#,
+            attributes
+        );
+        // Validation would already reject primitives without positions
+        let document = gltf::Gltf::from_slice_without_validation(json.as_bytes())
+            .unwrap()
+            .document;
+        let bin: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        GltfFile::build_meshes(&document, &[gltf::buffer::Data(bin)], &[], &[])
+    }
+
+    #[test]
+    fn unindexed_primitives_draw_their_vertices_in_order() {
+        let meshes = unindexed_triangle(r#""POSITION": 0"#).unwrap();
+        let primitive = &meshes[0].primitives[0];
+        assert_eq!(primitive.positions.len(), 3);
+        assert_eq!(primitive.indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn primitives_without_positions_are_an_error() {
+        let error = unindexed_triangle(r#""NORMAL": 0"#).err().unwrap();
+        assert_eq!(format!("{:#}", error), "Failed to load mesh 0: Primitive 0 has no positions");
+    }
+
+    #[test]
+    fn texture_transform_offsets_rotates_and_scales() {
+        let document = document(
+            r#"{
+                "asset": { "version": "2.0" },
+                "extensionsUsed": ["KHR_texture_transform"],
+                "images": [{ "uri": "texture.png" }],
+                "textures": [{ "source": 0 }],
+                "materials": [{
+                    "pbrMetallicRoughness": {
+                        "baseColorTexture": {
+                            "index": 0,
+                            "extensions": {
+                                "KHR_texture_transform": {
+                                    "offset": [0.5, 0.0],
+                                    "rotation": 1.5707964,
+                                    "scale": [2.0, 2.0],
+                                    "texCoord": 1
+                                }
+                            }
+                        }
+                    }
+                }]
+            }"#,
+        );
+        let material = document.materials().next().unwrap();
+        let info = material.pbr_metallic_roughness().base_color_texture().unwrap();
+        let (tex_coord, transform) = GltfFile::texture_transform(&info);
+        assert_eq!(tex_coord, 1);
+        let origin = transform * cgmath::vec3(0.0, 0.0, 1.0);
+        assert_eq!(origin.truncate(), cgmath::vec2(0.5, 0.0));
+        let u = transform * cgmath::vec3(1.0, 0.0, 1.0);
+        assert!((u.truncate() - cgmath::vec2(0.5, -2.0)).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn unlit_materials_and_punctual_lights() {
+        let document = document(
+            r#"{
+                "asset": { "version": "2.0" },
+                "extensionsUsed": ["KHR_materials_unlit", "KHR_lights_punctual"],
+                "extensions": {
+                    "KHR_lights_punctual": {
+                        "lights": [
+                            { "type": "directional", "color": [1.0, 0.5, 0.0], "intensity": 3.0 },
+                            { "type": "spot", "range": 10.0, "spot": { "outerConeAngle": 0.5 } }
+                        ]
+                    }
+                },
+                "materials": [{ "extensions": { "KHR_materials_unlit": {} } }, {}],
+                "nodes": [{}, { "extensions": { "KHR_lights_punctual": { "light": 1 } } }]
+            }"#,
+        );
+        let unlit = document.materials().map(|material| material.unlit()).collect::<Vec<_>>();
+        assert_eq!(unlit, vec![true, false]);
+        let lights = GltfFile::build_lights(&document);
+        assert_eq!(
+            lights[0],
+            Light::Directional(DirectionalLight::new(cgmath::vec3(1.0, 0.5, 0.0), 3.0))
+        );
+        assert_eq!(
+            lights[1],
+            Light::Spot(SpotLight::new(cgmath::vec3(1.0, 1.0, 1.0), 1.0, Some(10.0), 0.0, 0.5))
+        );
+        let nodes = GltfFile::build_nodes(&document);
+        assert_eq!(nodes[0].light, None);
+        assert_eq!(nodes[1].light, Some(1));
+    }
+
+    #[test]
+    fn images_expand_to_rgba8() {
+        let luminance_alpha = gltf::image::Data {
+            pixels: vec![10, 20, 30, 40],
+            format: gltf::image::Format::R8G8,
+            width: 2,
+            height: 1,
+        };
+        assert_eq!(GltfFile::rgba8(&luminance_alpha), vec![10, 10, 10, 20, 30, 30, 30, 40]);
+        let rgb16 = gltf::image::Data {
+            pixels: [0xffffu16, 0x8000, 0]
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect(),
+            format: gltf::image::Format::R16G16B16,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(GltfFile::rgba8(&rgb16), vec![255, 128, 0, 255]);
     }
 }
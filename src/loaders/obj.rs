@@ -1,134 +1,238 @@
-use std::io;
-use std::fs;
-use crate::renderer::Vertex;
+use std::path::Path;
 
-use super::{Material, Mesh, Model};
+use crate::{
+    mesh::{AlphaMode, Material, Mesh, MeshAsset, Primitive, Shading, TextureData},
+    mesh_processing::{self, Bounds, ProcessSettings},
+};
 
-// pub async fn load_texture(
-//     queue: &Queue,
-//     device: &Device,
-//     filename: &str,
-// ) -> anyhow::Result<Texture> {
-//     let path = Path::new("./assets/textures/").join(filename);
-//     let file = File::open(path)?;
-//     let reader = BufReader::new(file);
-//     let image = image::load(reader, image::ImageFormat::Png)?; // support other types
-//     let rgba = image.to_rgba8();
-
-//     use image::GenericImageView;
-//     let dimensions = image.dimensions();
-//     let texture_size = wgpu::Extent3d {
-//         width: dimensions.0,
-//         height: dimensions.1,
-//         depth_or_array_layers: 1,
-//     };
-//     let texture = device.create_texture(&wgpu::TextureDescriptor {
-//         // All textures are stored as 3D, we represent our 2D texture
-//         // by setting depth to 1.
-//         size: texture_size,
-//         mip_level_count: 1, // We'll talk about this a little later
-//         sample_count: 1,
-//         dimension: wgpu::TextureDimension::D2,
-//         // Most images are stored using sRGB so we need to reflect that here.
-//         format: wgpu::TextureFormat::Rgba8UnormSrgb,
-//         // TEXTURE_BINDING tells wgpu that we want to use this texture in shaders
-//         // COPY_DST means that we want to copy data to this texture
-//         usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-//         label: Some(filename),
-//         // This is the same as with the SurfaceConfig. It
-//         // specifies what texture formats can be used to
-//         // create TextureViews for this texture. The base
-//         // texture format (Rgba8UnormSrgb in this case) is
-//         // always supported. Note that using a different
-//         // texture format is not supported on the WebGL2
-//         // backend.
-//         view_formats: &[],
-//     });
-//     queue.write_texture(
-//         // Tells wgpu where to copy the pixel data
-//         wgpu::ImageCopyTexture {
-//             texture: &texture,
-//             mip_level: 0,
-//             origin: wgpu::Origin3d::ZERO,
-//             aspect: wgpu::TextureAspect::All,
-//         },
-//         // The actual pixel data
-//         &rgba,
-//         // The layout of the texture
-//         wgpu::ImageDataLayout {
-//             offset: 0,
-//             bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
-//             rows_per_image: std::num::NonZeroU32::new(dimensions.1),
-//         },
-//         texture_size,
-//     );
-//     Ok(texture)
-// }
+fn load_texture(path: &Path) -> anyhow::Result<TextureData> {
+    let image = image::open(path)?.to_rgba8();
+    Ok(TextureData {
+        name: path.file_name().map(|name| name.to_string_lossy().into_owned()),
+        width: image.width(),
+        height: image.height(),
+        pixels: image.into_raw(),
+        sampler: wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        },
+    })
+}
 
-pub fn load_model(path: &std::path::Path) -> Model {
-    let file = fs::File::open(&path).unwrap();
-    let mut model_file = io::BufReader::new(file);
-    let (models, obj_mats) = tobj::load_obj_buf(
-        &mut model_file,
+// MTL files and textures are resolved relative to the OBJ file. Missing ones are
+// reported and replaced by defaults rather than failing the whole model.
+pub fn load_model(path: &Path) -> anyhow::Result<MeshAsset> {
+    let (models, obj_mats) = tobj::load_obj(
+        path,
         &tobj::LoadOptions {
             triangulate: true,
             single_index: true,
             ..Default::default()
         },
-        |p| {
-            let p2 = std::path::Path::new("./assets/").join(p);
-            let mut mat = io::BufReader::new(fs::File::open(p2).unwrap());
-            tobj::load_mtl_buf(&mut mat)
-        },
-    ).unwrap();
+    )?;
+    let obj_mats = obj_mats.unwrap_or_else(|err| {
+        log::warn!("Using default materials for {}: {}", path.display(), err);
+        Vec::new()
+    });
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut textures = Vec::new();
     let mut materials = Vec::new();
-    for mat in &obj_mats.unwrap() {
-        let new_mat = Material {
-            name: mat.name.to_string(),
-        };
-        materials.push(new_mat);
+    let mut texture = |file: &str| {
+        if file.is_empty() {
+            return None;
+        }
+        let texture_path = directory.join(file);
+        match load_texture(&texture_path) {
+            Ok(texture) => {
+                textures.push(texture);
+                Some(textures.len() - 1)
+            }
+            Err(err) => {
+                log::warn!("Failed to load texture {}: {}", texture_path.display(), err);
+                None
+            }
+        }
+    };
+    for mat in obj_mats {
+        let base_color_texture = texture(&mat.diffuse_texture);
+        let normal_texture = texture(&mat.normal_texture);
+        materials.push(Material {
+            name: Some(mat.name),
+            base_color: [mat.diffuse[0], mat.diffuse[1], mat.diffuse[2], mat.dissolve],
+            base_color_texture,
+            normal_texture,
+            ambient: mat.ambient,
+            specular: mat.specular,
+            shininess: mat.shininess,
+            alpha_mode: if mat.dissolve < 1.0 {
+                AlphaMode::Blend
+            } else {
+                AlphaMode::Opaque
+            },
+            ..Default::default()
+        });
     }
 
     let meshes = models
         .into_iter()
         .map(|m| {
-            let vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| Vertex {
-                    position: [
-                        m.mesh.positions[i * 3 + 0],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    normals: [
-                        m.mesh.normals[i * 3 + 0],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                    color: if m.mesh.vertex_color.len() > 0 {
-                        [
-                            m.mesh.vertex_color[i * 3 + 0],
-                            m.mesh.vertex_color[i * 3 + 1],
-                            m.mesh.vertex_color[i * 3 + 2],
-                        ]
-                    } else {
-                        [1.0, 1.0, 1.0]
-                    },
-                })
-                .collect::<Vec<_>>();
-
-            Mesh {
-                vertices,
-                name: m.name,
-                num_indices: m.mesh.indices.len() as u32,
+            let vertex_count = m.mesh.positions.len() / 3;
+            let normals = m.mesh.normals.chunks_exact(3).map(|n| [n[0], n[1], n[2]]).collect();
+            // OBJ texture coordinates start at the bottom left
+            let uvs = if m.mesh.texcoords.is_empty() {
+                vec![[0.0, 0.0]; vertex_count]
+            } else {
+                m.mesh.texcoords.chunks_exact(2).map(|t| [t[0], 1.0 - t[1]]).collect()
+            };
+            let material = m.mesh.material_id.filter(|&id| id < materials.len());
+            let mut primitive = Primitive {
+                positions: m.mesh.positions.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect(),
+                normals,
+                uvs,
+                tangents: Vec::new(),
                 indices: m.mesh.indices,
-                material_id: m.mesh.material_id,
+                material,
+                morph_targets: Vec::new(),
+                dropped_morph_targets: 0,
+                lods: Vec::new(),
+                bounds: Bounds::default(),
+            };
+            if m.mesh.normals.is_empty() {
+                primitive.generate_normals(Shading::Smooth);
+            }
+            if material.is_some_and(|id| materials[id].normal_texture.is_some()) {
+                primitive.generate_tangents();
+            }
+            mesh_processing::process(&mut primitive, ProcessSettings::IMPORT);
+            Mesh {
+                name: Some(m.name),
+                primitives: vec![primitive],
             }
         })
-        .collect::<Vec<_>>();
+        .collect();
 
-    Model {
+    Ok(MeshAsset::new(
+        path.to_str().unwrap_or_default(),
         meshes,
         materials,
-        name: String::from(path.to_str().unwrap()),
+        textures,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::*;
+
+    const QUAD: &str = "mtllib quad.mtl
+v 0 0 0
+v 1 0 0
+v 1 0 1
+vt 0 0
+vt 1 0
+vt 1 1
+vn 0 1 0
+usemtl painted
+f 1/1/1 3/3/1 2/2/1
+";
+
+    fn write_files(name: &str, files: &[(&str, &[u8])]) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("obj_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for (file, contents) in files {
+            std::fs::write(directory.join(file), contents).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn load_mtl_colors_and_textures_relative_to_obj() {
+        let mut texture = Vec::new();
+        image::RgbaImage::from_pixel(2, 1, image::Rgba([255, 0, 0, 255]))
+            .write_to(&mut std::io::Cursor::new(&mut texture), image::ImageOutputFormat::Png)
+            .unwrap();
+        let mtl = "newmtl painted
+Ka 0.5 0.5 0.5
+Kd 0.8 0.6 0.4
+Ks 0.25 0.25 0.25
+Ns 32
+map_Kd textures/paint.png
+";
+        let directory = write_files("textured", &[("quad.obj", QUAD.as_bytes()), ("quad.mtl", mtl.as_bytes())]);
+        std::fs::create_dir_all(directory.join("textures")).unwrap();
+        std::fs::write(directory.join("textures/paint.png"), texture).unwrap();
+
+        let mesh_asset = load_model(&directory.join("quad.obj")).unwrap();
+        let material = &mesh_asset.materials[0];
+        assert_eq!(material.base_color, [0.8, 0.6, 0.4, 1.0]);
+        assert_eq!(material.ambient, [0.5, 0.5, 0.5]);
+        assert_eq!(material.specular, [0.25, 0.25, 0.25]);
+        assert_eq!(material.shininess, 32.0);
+        let texture = &mesh_asset.textures[material.base_color_texture.unwrap()];
+        assert_eq!((texture.width, texture.height), (2, 1));
+        assert_eq!(&texture.pixels[..4], &[255, 0, 0, 255]);
+        let primitive = &mesh_asset.meshes[0].primitives[0];
+        assert_eq!(primitive.material, Some(0));
+        assert_eq!(primitive.uvs[0], [0.0, 1.0]);
+    }
+
+    #[test]
+    fn missing_mtl_uses_default_material() {
+        let directory = write_files("untextured", &[("quad.obj", QUAD.as_bytes())]);
+        let mesh_asset = load_model(&directory.join("quad.obj")).unwrap();
+        assert!(mesh_asset.materials.is_empty());
+        assert_eq!(mesh_asset.meshes[0].primitives[0].material, None);
+    }
+
+    #[test]
+    fn missing_normals_are_smoothed() {
+        // A floor and a wall meeting along the x axis
+        let corner = "v 0 0 0
+v 0 0 1
+v 1 0 0
+v 0 1 0
+f 1 2 3
+f 1 3 4
+";
+        let directory = write_files("smoothed", &[("corner.obj", corner.as_bytes())]);
+        let mesh_asset = load_model(&directory.join("corner.obj")).unwrap();
+        let primitive = &mesh_asset.meshes[0].primitives[0];
+        assert_eq!(primitive.normals.len(), primitive.positions.len());
+        assert!(primitive.tangents.is_empty());
+        // The vertex shared by both faces averages their normals
+        let shared = primitive.positions.iter().position(|&p| p == [1.0, 0.0, 0.0]).unwrap();
+        let normal = cgmath::Vector3::from(primitive.normals[shared]);
+        assert!((normal - cgmath::vec3(0.0, 1.0, 1.0).normalize()).magnitude() < 1e-5);
+        let floor = primitive.positions.iter().position(|&p| p == [0.0, 0.0, 1.0]).unwrap();
+        assert_eq!(primitive.normals[floor], [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn imported_meshes_get_lods() {
+        // A flat grid of 64 by 64 quads, fine enough for the coarsest LOD cells
+        let n = 64;
+        let mut grid = String::new();
+        for x in 0..=n {
+            for z in 0..=n {
+                grid += &format!("v {} 0 {}\n", x, z);
+            }
+        }
+        let vertex = |x: u32, z: u32| x * (n + 1) + z + 1;
+        for x in 0..n {
+            for z in 0..n {
+                let (a, b) = (vertex(x, z), vertex(x, z + 1));
+                let (c, d) = (vertex(x + 1, z + 1), vertex(x + 1, z));
+                grid += &format!("f {} {} {}\nf {} {} {}\n", a, b, c, a, c, d);
+            }
+        }
+        let directory = write_files("grid", &[("grid.obj", grid.as_bytes())]);
+        let mesh_asset = load_model(&directory.join("grid.obj")).unwrap();
+        let primitive = &mesh_asset.meshes[0].primitives[0];
+        assert!(!primitive.lods.is_empty());
+        assert!(primitive.lods[0].indices.len() < primitive.indices.len());
     }
 }
@@ -1,30 +1,39 @@
-use std::path::Path;
-
 use playground::{
     asset_manager::AssetManager,
     component_manager::ComponentManager,
-    components::{model::{Model, AnimationState}, transform::Transform, walk_to::WalkTo, walkable_surface::WalkableSurface, click_move::ClickMove, click::Click},
-    loaders::{self, gltf::{GltfFile, GltfFrameState}},
-    renderer::render::Renderer,
-    systems::{camera::CameraSystem, movement::MovementSystem, click::ClickSystem},
+    components::{model::Model, transform::Transform, walk_to::WalkTo, walkable_surface::WalkableSurface, click_move::ClickMove, click::Click, animation_state_machine::{AnimationStateMachine, Comparison}, morph_weights_override::MorphWeightsOverride, parent::{self, Parent}, scene_node::SceneNode, light::{DirectionalLight, Light, PointLight, SpotLight}, casts_shadow::CastsShadow, receives_shadow::ReceivesShadow},
+    loaders::{self, gltf::GltfFile},
+    mesh::{Material, MeshAsset, MeshFrameState},
+    renderer::{capture::CaptureSettings, passes::DepthOverlayPass, render::Renderer},
+    shapes::Shape,
+    systems::{camera::CameraSystem, movement::MovementSystem, click::ClickSystem, animation::AnimationSystem},
     world::World,
     *,
 };
-use winit::event::KeyboardInput;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
 fn main() {
+    // Warnings from every crate and this one's diagnostics, unless RUST_LOG says otherwise
+    let filter = env_logger::Env::default().default_filter_or("warn,playground=info");
+    env_logger::Builder::from_env(filter).init();
     pollster::block_on(run()).expect("Error");
 }
 
 pub async fn run() -> anyhow::Result<()> {
     let window = window::Window::new();
 
-    let renderer = Renderer::new(&window).await;
+    let mut renderer = Renderer::new(&window).await;
+    let environment_path = std::path::Path::new("./assets/environment.hdr");
+    if environment_path.exists() {
+        let environment = loaders::environment::load_equirectangular(environment_path)?;
+        renderer.set_environment(Some(&environment));
+    }
 
     let mut world = World::new();
     let size = window.window.inner_size();
     let mut camera_system = CameraSystem::new(size.width as f32, size.height as f32);
     let mut movement_system = MovementSystem::new();
     let mut click_system = ClickSystem::new();
+    let mut animation_system = AnimationSystem::new();
 
     let mut cm = ComponentManager::new();
     cm.register_component::<Model>();
@@ -33,22 +42,48 @@ pub async fn run() -> anyhow::Result<()> {
     cm.register_component::<WalkableSurface>();
     cm.register_component::<Click>();
     cm.register_component::<ClickMove>();
+    cm.register_component::<AnimationStateMachine>();
+    cm.register_component::<MorphWeightsOverride>();
+    cm.register_component::<Parent>();
+    cm.register_component::<SceneNode>();
+    cm.register_component::<DirectionalLight>();
+    cm.register_component::<PointLight>();
+    cm.register_component::<SpotLight>();
+    cm.register_component::<CastsShadow>();
+    cm.register_component::<ReceivesShadow>();
 
     let mut am = AssetManager::new();
 
     // let dyno_file = loaders::gltf::GltfFile::new("./assets/man/CesiumMan.gltf", &renderer);
     // let duck = loaders::gltf::GltfFile::new("./assets/duck.gltf", &renderer);
 
-    let box_model = loaders::gltf::GltfFile::new("./assets/AnimatedCube/AnimatedCube.gltf", &renderer);
+    let box_model = loaders::gltf::GltfFile::new("./assets/AnimatedCube/AnimatedCube.gltf")?;
 
     let asset_handle = am.create_asset(box_model);
     let player = world.spawn();
-    // let model = Model { asset_handle, animation: None };
-    let model = Model { asset_handle, animation: Some(AnimationState::new(0)) };
+    // The animation state machine starts the player's animation on the first update
+    let model = Model { asset_handle, animation: None };
     let transform = Transform::new(Some(cgmath::point3(0.0, 0.0, 0.0)), None, None);
     cm.add_component(model, player);
     cm.add_component(transform, player);
     cm.add_component(ClickMove::new(98.0), player);
+    // The cube only has one clip, so it holds still until it is sent somewhere
+    let mut locomotion = AnimationStateMachine::new();
+    locomotion.add_state("idle", 0).speed = 0.0;
+    locomotion.add_state("walk", 0);
+    locomotion
+        .add_transition("idle", "walk", 0.25)?
+        .when("moving", Comparison::Greater, 0.5);
+    locomotion
+        .add_transition("walk", "idle", 0.25)?
+        .when("moving", Comparison::Less, 0.5);
+    cm.add_component(locomotion, player);
+    cm.add_component(CastsShadow, player);
+    cm.add_component(ReceivesShadow, player);
+    am.get_asset::<GltfFile>(asset_handle)
+        .unwrap()
+        .asset
+        .spawn_lights(player, &mut world, &mut cm);
 
     // let player = world.spawn();
     // let model = Model { asset_handle, animation: None };
@@ -57,50 +92,79 @@ pub async fn run() -> anyhow::Result<()> {
     // cm.add_component(transform, player);
     // cm.add_component(ClickMove::new(98.0), player);
 
-    // let floor = world.spawn();
-    // let floor_model = loaders::obj::load_model(Path::new("./assets/floor.obj")).await?;
-    // let floor_asset_handle = am.create_asset(floor_model);
-    // let floor_model = Model {
-    //     asset_handle: floor_asset_handle,
-    // };
-    // let floor_transform = Transform::new(None, None, None);
-    // cm.add_component(floor_model, floor);
-    // cm.add_component(floor_transform, floor);
-    // cm.add_component(WalkableSurface {}, floor);
+    let floor = world.spawn();
+    let ground = Shape::Plane {
+        width: 250.0,
+        depth: 250.0,
+        subdivisions: 0,
+    };
+    let floor_asset_handle = ground.create_asset(
+        &mut am,
+        Material {
+            base_color: [0.2039, 0.8274, 0.6, 1.0],
+            ..Default::default()
+        },
+    );
+    let floor_model = Model {
+        asset_handle: floor_asset_handle,
+        animation: None,
+    };
+    let floor_transform = Transform::new(None, None, None);
+    cm.add_component(floor_model, floor);
+    cm.add_component(floor_transform, floor);
+    cm.add_component(WalkableSurface {}, floor);
+    cm.add_component(ReceivesShadow, floor);
 
     //TODO: Create drawstatebuilder in renderer and build the drawstate
     window.run(move |event| match event {
         window::Event::Redraw => {
             let entities = world.get_entities();
-            let mut gltfs = Vec::new();
+            let mut meshes = Vec::new();
             for &entity in entities {
-                let model = cm.get_component::<Model>(entity).unwrap();
-                let transform = cm.get_component::<Transform>(entity).unwrap();
-                let model_asset = am.get_asset::<GltfFile>(model.asset_handle).unwrap();
-                let mut frame_state = GltfFrameState::new(&model_asset.asset); 
-                frame_state.set_global_transform(transform.to_matrix());
-                if let Some(animation) = &model.animation {
-                    frame_state.set_animation(animation);
+                let casts_shadow = cm.get_component::<CastsShadow>(entity).is_some();
+                let receives_shadow = cm.get_component::<ReceivesShadow>(entity).is_some();
+                if let Some(model) = cm.get_component::<Model>(entity) {
+                    let mut frame_state = if let Some(mesh_asset) = am.get_asset::<MeshAsset>(model.asset_handle) {
+                        MeshFrameState::new(&mesh_asset.asset)
+                    } else {
+                        let model_asset = &am.get_asset::<GltfFile>(model.asset_handle).unwrap().asset;
+                        let mut frame_state = model_asset.frame_state();
+                        if let Some(animation) = &model.animation {
+                            model_asset.apply_animation(&mut frame_state, animation);
+                        }
+                        frame_state
+                    };
+                    frame_state.set_global_transform(parent::global_matrix(&cm, entity));
+                    if let Some(morph_weights) = cm.get_component::<MorphWeightsOverride>(entity) {
+                        frame_state.set_morph_weights(&morph_weights.weights);
+                    }
+                    frame_state.set_shadows(casts_shadow, receives_shadow);
+                    meshes.push(frame_state);
+                } else if let Some(scene_node) = cm.get_component::<SceneNode>(entity) {
+                    let node_asset = &am.get_asset::<GltfFile>(scene_node.asset_handle).unwrap().asset;
+                    if node_asset.nodes[scene_node.node].mesh.is_some() {
+                        let mut frame_state = node_asset.node_frame_state(scene_node.node);
+                        frame_state.set_global_transform(parent::global_matrix(&cm, entity));
+                        frame_state.set_shadows(casts_shadow, receives_shadow);
+                        meshes.push(frame_state);
+                    }
                 }
-                gltfs.push(frame_state);
             }
-            renderer.draw(&mut gltfs, camera_system.view_proj());
+            renderer.draw(&meshes, camera_system.view_proj(), &Light::collect(&cm));
+        }
+        window::Event::Resize { width, height } => {
+            renderer.resize(width, height);
+            camera_system.resize(width, height);
         }
-        window::Event::Resize { width, height } => {}
         window::Event::Loop {
             delta_time,
-            elapsed,
+            elapsed: _,
         } => {
             use crate::systems::System;
             camera_system.run(&mut world, &mut cm, &am, delta_time);
             movement_system.run(&mut world, &mut cm, &am, delta_time);
             click_system.run(&mut world, &mut cm, &am, delta_time);
-            for &entity in world.get_entities() {
-                let mut model = cm.mut_component::<Model>(entity).unwrap();
-                if let Some(animation) = &mut model.animation {
-                    animation.advance(delta_time);
-                }
-            }
+            animation_system.run(&mut world, &mut cm, &am, delta_time);
         }
         window::Event::CursorInput { state, button } => {
             click_system.process_click(state, button, &camera_system.camera);
@@ -110,8 +174,7 @@ pub async fn run() -> anyhow::Result<()> {
             y,
             modifiers: _,
         } => {
-            let norm_x = x / size.width as f32;
-            let norm_y = y / size.height as f32;
+            let (norm_x, norm_y) = camera_system.normalize_cursor(x, y);
             click_system.process_mousemove(norm_x, norm_y);
         }
         window::Event::Keyboard {
@@ -121,7 +184,57 @@ pub async fn run() -> anyhow::Result<()> {
                     virtual_keycode: Some(keycode),
                     ..
                 },
-        } => camera_system.process_keyboard(keycode, state),
+        } => {
+            if keycode == VirtualKeyCode::F3 && state == ElementState::Pressed {
+                let enabled = renderer.graph().is_enabled(DepthOverlayPass::NAME);
+                renderer.graph_mut().set_enabled(DepthOverlayPass::NAME, !enabled);
+            }
+            if state == ElementState::Pressed {
+                let mut post = renderer.post_settings();
+                match keycode {
+                    VirtualKeyCode::F4 => post.tonemapper = post.tonemapper.next(),
+                    VirtualKeyCode::F5 => post.bloom = !post.bloom,
+                    VirtualKeyCode::F6 => post.fxaa = !post.fxaa,
+                    VirtualKeyCode::PageUp => post.exposure *= 1.25,
+                    VirtualKeyCode::PageDown => post.exposure /= 1.25,
+                    _ => (),
+                }
+                if post != renderer.post_settings() {
+                    renderer.set_post_settings(post);
+                }
+                if keycode == VirtualKeyCode::F8 {
+                    let stats = renderer.frame_stats();
+                    log::info!(
+                        "Drew {} objects, culled {}, {} pipelines cached, {} gpu buffers allocated",
+                        stats.drawn_objects,
+                        stats.culled_objects,
+                        renderer.cached_pipelines(),
+                        stats.buffer_allocations
+                    );
+                }
+                if matches!(keycode, VirtualKeyCode::F9 | VirtualKeyCode::F10) {
+                    // A screenshot with its depth, or a second of frames for a GIF, in a
+                    // directory of their own
+                    let seconds = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |time| time.as_secs());
+                    let screenshot = keycode == VirtualKeyCode::F9;
+                    renderer.capture_frames(CaptureSettings {
+                        directory: format!("./captures/{}", seconds).into(),
+                        frames: if screenshot { 1 } else { 60 },
+                        depth: screenshot,
+                    });
+                }
+                if keycode == VirtualKeyCode::F7 {
+                    // Cycles through the sample counts the adapter supports
+                    let counts = renderer.supported_sample_counts();
+                    let current = counts.iter().position(|&count| count == renderer.sample_count());
+                    let next = counts[current.map_or(0, |index| (index + 1) % counts.len())];
+                    renderer.set_sample_count(next);
+                }
+            }
+            camera_system.process_keyboard(keycode, state)
+        }
         _ => (),
     });
 }
@@ -0,0 +1,1017 @@
+use std::{cell::OnceCell, collections::HashMap, ops::Range, rc::Rc};
+
+use cgmath::{InnerSpace, One, Zero};
+use crate::{
+    animation::{MorphWeights, MAX_MORPH_TARGETS},
+    mesh_processing::{self, Aabb, Bounds, Lod},
+    renderer::{
+        buffer_arena::BufferArena,
+        pipeline_cache::{MeshPipelineKey, VertexLayout, VertexSemantic},
+        render::Renderer,
+        texture::Texture,
+        MaterialUniform,
+    },
+};
+
+pub trait DrawMesh<'a> {
+    // Draws the primitives that are not blended
+    fn draw_mesh(&mut self, batch: &'a MeshBatch, instance_buffer: &'a wgpu::Buffer);
+    // Draws one instance of a blended primitive
+    fn draw_transparent(
+        &mut self,
+        batches: &'a [MeshBatch],
+        draw: &TransparentDraw,
+        instance_buffer: &'a wgpu::Buffer,
+    );
+    // Draws positions only, binding morph targets to group 1 of the morph pipeline
+    fn draw_mesh_depth(
+        &mut self,
+        batch: &'a MeshBatch,
+        instance_buffer: &'a wgpu::Buffer,
+        pipeline: &'a wgpu::RenderPipeline,
+        morph_pipeline: &'a wgpu::RenderPipeline,
+    );
+}
+// Sets the instance, vertex and index buffers of the primitive, and its morph targets
+fn bind_primitive<'a>(
+    render_pass: &mut wgpu::RenderPass<'a>,
+    primitive: &'a PrimitiveBuffers,
+    instance_buffer: &'a wgpu::Buffer,
+    (transforms, weights): &(Range<u64>, Range<u64>),
+) {
+    render_pass.set_vertex_buffer(0, instance_buffer.slice(transforms.clone()));
+    for (i, buffer) in primitive.vertex_buffers.iter().enumerate() {
+        render_pass.set_vertex_buffer((i + 1) as u32, buffer.slice(..));
+    }
+    if let Some(morph) = &primitive.morph {
+        render_pass.set_bind_group(3, morph, &[]);
+        render_pass.set_vertex_buffer(
+            (primitive.vertex_buffers.len() + 1) as u32,
+            instance_buffer.slice(weights.clone()),
+        );
+    }
+    render_pass.set_index_buffer(primitive.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+}
+
+impl<'a> DrawMesh<'a> for wgpu::RenderPass<'a> {
+    fn draw_mesh(&mut self, batch: &'a MeshBatch, instance_buffer: &'a wgpu::Buffer) {
+        let buffers = batch
+            .mesh_asset
+            .uploaded()
+            .expect("Mesh batch drawn before write_instances");
+        for (mesh_idx, mesh) in buffers.meshes.iter().enumerate() {
+            let num_instances = batch.transforms[mesh_idx].len() as u32;
+            if num_instances == 0 {
+                continue;
+            }
+            let opaque = mesh.iter().enumerate().filter(|(_, primitive)| !primitive.blended);
+            for (primitive_idx, primitive) in opaque {
+                self.set_pipeline(&batch.pipelines[mesh_idx][primitive_idx]);
+                self.set_bind_group(2, &buffers.materials[primitive.material], &[]);
+                bind_primitive(self, primitive, instance_buffer, &batch.ranges[mesh_idx]);
+                self.draw_indexed(0..primitive.num_indices, 0, 0..num_instances);
+            }
+        }
+    }
+    fn draw_transparent(
+        &mut self,
+        batches: &'a [MeshBatch],
+        draw: &TransparentDraw,
+        instance_buffer: &'a wgpu::Buffer,
+    ) {
+        let batch = &batches[draw.batch];
+        let buffers = batch
+            .mesh_asset
+            .uploaded()
+            .expect("Mesh batch drawn before write_instances");
+        let primitive = &buffers.meshes[draw.mesh][draw.primitive];
+        self.set_pipeline(&batch.pipelines[draw.mesh][draw.primitive]);
+        self.set_bind_group(2, &buffers.materials[primitive.material], &[]);
+        bind_primitive(self, primitive, instance_buffer, &batch.ranges[draw.mesh]);
+        self.draw_indexed(0..primitive.num_indices, 0, draw.instance..draw.instance + 1);
+    }
+    fn draw_mesh_depth(
+        &mut self,
+        batch: &'a MeshBatch,
+        instance_buffer: &'a wgpu::Buffer,
+        pipeline: &'a wgpu::RenderPipeline,
+        morph_pipeline: &'a wgpu::RenderPipeline,
+    ) {
+        let buffers = batch
+            .mesh_asset
+            .uploaded()
+            .expect("Mesh batch drawn before write_instances");
+        for (mesh_idx, mesh) in buffers.meshes.iter().enumerate() {
+            let num_instances = batch.transforms[mesh_idx].len() as u32;
+            if num_instances == 0 {
+                continue;
+            }
+            let (transforms, weights) = &batch.ranges[mesh_idx];
+            for primitive in mesh {
+                self.set_vertex_buffer(0, instance_buffer.slice(transforms.clone()));
+                self.set_vertex_buffer(1, primitive.vertex_buffers[0].slice(..));
+                match &primitive.morph {
+                    Some(morph) => {
+                        self.set_pipeline(morph_pipeline);
+                        self.set_bind_group(1, morph, &[]);
+                        self.set_vertex_buffer(2, instance_buffer.slice(weights.clone()));
+                    }
+                    None => self.set_pipeline(pipeline),
+                }
+                self.set_index_buffer(primitive.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                self.draw_indexed(0..primitive.num_indices, 0, 0..num_instances);
+            }
+        }
+    }
+}
+
+pub struct TextureData {
+    pub name: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    // Tightly packed sRGB rgba rows
+    pub pixels: Vec<u8>,
+    pub sampler: wgpu::SamplerDescriptor<'static>,
+}
+
+// How the alpha of the base color is used
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+    Opaque,
+    // Fragments with less alpha than the cutoff are discarded
+    Mask(f32),
+    // Blended over what is behind, drawn after opaque primitives
+    Blend,
+}
+
+pub struct Material {
+    pub name: Option<String>,
+    pub base_color: [f32; 4],
+    pub base_color_texture: Option<usize>,
+    // Applied to primitives with tangents, which loaders generate when it is set
+    pub normal_texture: Option<usize>,
+    pub uv_transform: cgmath::Matrix3<f32>,
+    // Scales the scene's ambient light
+    pub ambient: [f32; 3],
+    pub specular: [f32; 3],
+    pub shininess: f32,
+    pub unlit: bool,
+    pub alpha_mode: AlphaMode,
+    // Back faces are drawn too
+    pub double_sided: bool,
+}
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            name: None,
+            base_color: [1.0; 4],
+            base_color_texture: None,
+            normal_texture: None,
+            uv_transform: cgmath::Matrix3::one(),
+            ambient: [1.0; 3],
+            specular: [0.0; 3],
+            shininess: 0.0,
+            unlit: false,
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+        }
+    }
+}
+impl From<&Material> for MaterialUniform {
+    fn from(material: &Material) -> Self {
+        let [r, g, b] = material.specular;
+        let column = |c: cgmath::Vector3<f32>| [c.x, c.y, c.z, 0.0];
+        let alpha_cutoff = match material.alpha_mode {
+            AlphaMode::Mask(cutoff) => cutoff,
+            _ => 0.0,
+        };
+        Self {
+            base_color: material.base_color,
+            ambient: [material.ambient[0], material.ambient[1], material.ambient[2], alpha_cutoff],
+            specular: [r, g, b, material.shininess],
+            uv_transform: [
+                column(material.uv_transform.x),
+                column(material.uv_transform.y),
+                column(material.uv_transform.z),
+            ],
+        }
+    }
+}
+
+pub struct MorphTarget {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+}
+
+// An unwelded primitive as MikkTSpace sees it, with a face per triangle
+struct TangentSpace<'a>(&'a mut Primitive);
+impl bevy_mikktspace::Geometry for TangentSpace<'_> {
+    fn num_faces(&self) -> usize {
+        self.0.indices.len() / 3
+    }
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.0.positions[face * 3 + vert]
+    }
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.0.normals[face * 3 + vert]
+    }
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        let [u, v] = self.0.uvs[face * 3 + vert];
+        [u, 1.0 - v]
+    }
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.0.tangents[face * 3 + vert] = tangent;
+    }
+}
+
+pub struct Primitive {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    // Tangent directions with the bitangent sign in w, empty without a normal map
+    pub tangents: Vec<[f32; 4]>,
+    pub indices: Vec<u32>,
+    pub material: Option<usize>,
+    pub morph_targets: Vec<MorphTarget>,
+    // Targets past MAX_MORPH_TARGETS that limit_morph_targets dropped
+    pub dropped_morph_targets: usize,
+    // Coarser index lists over the same vertices, from finest to coarsest. Generated
+    // for imported meshes, see ProcessSettings.
+    pub lods: Vec<Lod>,
+    pub bounds: Bounds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shading {
+    Smooth,
+    Flat,
+}
+
+impl Primitive {
+    // Keeps the first MAX_MORPH_TARGETS targets, the most the morph shader blends,
+    // and counts the rest
+    pub fn limit_morph_targets(&mut self) {
+        if self.morph_targets.len() > MAX_MORPH_TARGETS {
+            self.dropped_morph_targets += self.morph_targets.len() - MAX_MORPH_TARGETS;
+            self.morph_targets.truncate(MAX_MORPH_TARGETS);
+        }
+    }
+    // Replaces the normals with area weighted face normals. Flat shading first gives
+    // every triangle its own vertices so that no normal is shared between faces.
+    pub fn generate_normals(&mut self, shading: Shading) {
+        if shading == Shading::Flat {
+            self.unweld();
+        }
+        let mut normals = vec![cgmath::Vector3::zero(); self.positions.len()];
+        for (triangle, [a, b, c]) in self.indices.chunks_exact(3).zip(self.triangles()) {
+            let normal = (b - a).cross(c - a);
+            for &index in triangle {
+                normals[index as usize] += normal;
+            }
+        }
+        self.normals = normals
+            .into_iter()
+            .map(|normal| {
+                if normal.magnitude2() > 0.0 {
+                    normal.normalize().into()
+                } else {
+                    [0.0, 1.0, 0.0]
+                }
+            })
+            .collect();
+    }
+
+    // Per vertex tangents from MikkTSpace, which glTF asks for: xyz is orthogonal to
+    // the normal and w is the sign such that bitangent = cross(normal, tangent) * w.
+    // Texture coordinates have their origin at the top left, so v is flipped to point up.
+    pub fn generate_tangents(&mut self) {
+        // A vertex may get a different tangent in each of its triangles, so every
+        // triangle gets vertices of its own, welded again where the tangents agree
+        self.unweld();
+        self.tangents = vec![[1.0, 0.0, 0.0, 1.0]; self.positions.len()];
+        bevy_mikktspace::generate_tangents(&mut TangentSpace(self));
+        mesh_processing::weld(self);
+    }
+
+    // Expands indexed vertices so that each index refers to a vertex of its own
+    fn unweld(&mut self) {
+        let indices = std::mem::take(&mut self.indices);
+        fn expand<T: Copy>(values: &[T], indices: &[u32]) -> Vec<T> {
+            if values.is_empty() {
+                return Vec::new();
+            }
+            indices.iter().map(|&index| values[index as usize]).collect()
+        }
+        self.positions = expand(&self.positions, &indices);
+        self.normals = expand(&self.normals, &indices);
+        self.uvs = expand(&self.uvs, &indices);
+        self.tangents = expand(&self.tangents, &indices);
+        for target in &mut self.morph_targets {
+            target.positions = expand(&target.positions, &indices);
+            target.normals = expand(&target.normals, &indices);
+        }
+        self.indices = (0..indices.len() as u32).collect();
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = [cgmath::Point3<f32>; 3]> + '_ {
+        self.indices.chunks_exact(3).map(|triangle| {
+            [triangle[0], triangle[1], triangle[2]].map(|index| self.positions[index as usize].into())
+        })
+    }
+    fn is_unlit(&self, materials: &[Material]) -> bool {
+        self.material.is_some_and(|material| materials[material].unlit)
+    }
+    fn is_blended(&self, materials: &[Material]) -> bool {
+        self.material
+            .is_some_and(|material| materials[material].alpha_mode == AlphaMode::Blend)
+    }
+    // Positions, normals, texture coordinates and any tangents are uploaded as they
+    // are stored
+    pub fn vertex_layout(&self) -> VertexLayout {
+        let layout = VertexLayout::default()
+            .with(VertexSemantic::Position, wgpu::VertexFormat::Float32x3)
+            .with(VertexSemantic::Normal, wgpu::VertexFormat::Float32x3)
+            .with(VertexSemantic::TexCoord, wgpu::VertexFormat::Float32x2);
+        if self.tangents.is_empty() {
+            layout
+        } else {
+            layout.with(VertexSemantic::Tangent, wgpu::VertexFormat::Float32x4)
+        }
+    }
+    pub fn pipeline_key(
+        &self,
+        materials: &[Material],
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> MeshPipelineKey {
+        let double_sided = self
+            .material
+            .is_some_and(|material| materials[material].double_sided);
+        MeshPipelineKey {
+            vertex_layout: self.vertex_layout(),
+            morph: !self.morph_targets.is_empty(),
+            unlit: self.is_unlit(materials),
+            blend: self.is_blended(materials),
+            cull_mode: if double_sided {
+                None
+            } else {
+                Some(wgpu::Face::Back)
+            },
+            format,
+            sample_count,
+        }
+    }
+}
+
+pub struct Mesh {
+    pub name: Option<String>,
+    pub primitives: Vec<Primitive>,
+}
+impl Mesh {
+    // The box around every primitive, None without primitives
+    pub fn aabb(&self) -> Option<Aabb> {
+        self.primitives
+            .iter()
+            .map(|primitive| primitive.bounds.aabb)
+            .reduce(|aabb, other| aabb.union(&other))
+    }
+}
+
+#[repr(C)]
+#[derive(bytemuck::Pod, Copy, Clone, bytemuck::Zeroable)]
+struct MorphDelta {
+    position: [f32; 4],
+    normal: [f32; 4],
+}
+
+pub struct PrimitiveBuffers {
+    pub vertex_buffers: Vec<wgpu::Buffer>,
+    pub index_buffer: wgpu::Buffer,
+    pub num_indices: u32,
+    pub material: usize,
+    pub morph: Option<wgpu::BindGroup>,
+    // Drawn in the transparent pass rather than with the rest of the mesh
+    pub blended: bool,
+}
+
+pub struct MeshBuffers {
+    pub textures: Vec<Texture>,
+    // The asset's materials followed by the default material
+    pub materials: Vec<wgpu::BindGroup>,
+    pub meshes: Vec<Vec<PrimitiveBuffers>>,
+}
+
+pub struct MeshAsset {
+    pub name: String,
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<Material>,
+    pub textures: Vec<TextureData>,
+    buffers: OnceCell<MeshBuffers>,
+}
+impl MeshAsset {
+    pub fn new(
+        name: &str,
+        meshes: Vec<Mesh>,
+        materials: Vec<Material>,
+        textures: Vec<TextureData>,
+    ) -> Self {
+        Self {
+            name: String::from(name),
+            meshes,
+            materials,
+            textures,
+            buffers: OnceCell::new(),
+        }
+    }
+    // Uploads the asset to the gpu the first time it is needed
+    pub fn buffers(&self, renderer: &Renderer) -> &MeshBuffers {
+        self.buffers.get_or_init(|| self.upload(renderer))
+    }
+    pub fn uploaded(&self) -> Option<&MeshBuffers> {
+        self.buffers.get()
+    }
+    fn upload(&self, renderer: &Renderer) -> MeshBuffers {
+        // Normal maps hold directions rather than colors, so they aren't sRGB
+        let normal_maps = self
+            .materials
+            .iter()
+            .filter_map(|material| material.normal_texture)
+            .collect::<Vec<_>>();
+        let mut textures: Vec<Texture> = self
+            .textures
+            .iter()
+            .enumerate()
+            .map(|(index, texture)| {
+                Texture::from_rgba8(
+                    &renderer.device,
+                    &renderer.queue,
+                    texture.name.as_deref(),
+                    (texture.width, texture.height),
+                    &texture.pixels,
+                    if normal_maps.contains(&index) {
+                        wgpu::TextureFormat::Rgba8Unorm
+                    } else {
+                        wgpu::TextureFormat::Rgba8UnormSrgb
+                    },
+                    &texture.sampler,
+                )
+            })
+            .collect();
+        textures.push(Texture::white(&renderer.device, &renderer.queue));
+        let white = textures.len() - 1;
+        textures.push(Texture::flat_normal(&renderer.device, &renderer.queue));
+        let flat_normal = textures.len() - 1;
+        let default_material = Material::default();
+        let materials = self
+            .materials
+            .iter()
+            .chain(std::iter::once(&default_material))
+            .map(|material| {
+                let uniform = MaterialUniform::from(material);
+                renderer.default_pipeline.create_material_bind_group(
+                    &renderer.device,
+                    &renderer.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Material buffer"),
+                        contents: bytemuck::cast_slice(&[uniform]),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    }),
+                    &textures[material.base_color_texture.unwrap_or(white)],
+                    &textures[material.normal_texture.unwrap_or(flat_normal)],
+                )
+            })
+            .collect();
+        let meshes = self
+            .meshes
+            .iter()
+            .map(|mesh| {
+                mesh.primitives
+                    .iter()
+                    .map(|primitive| self.upload_primitive(primitive, renderer))
+                    .collect()
+            })
+            .collect();
+        MeshBuffers {
+            textures,
+            materials,
+            meshes,
+        }
+    }
+    fn upload_primitive(
+        &self,
+        primitive: &Primitive,
+        renderer: &Renderer,
+    ) -> PrimitiveBuffers {
+        let vertex_buffer = |contents: &[u8]| {
+            renderer.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents,
+                usage: wgpu::BufferUsages::VERTEX,
+            })
+        };
+        let mut vertex_buffers = vec![
+            vertex_buffer(bytemuck::cast_slice(&primitive.positions)),
+            vertex_buffer(bytemuck::cast_slice(&primitive.normals)),
+            vertex_buffer(bytemuck::cast_slice(&primitive.uvs)),
+        ];
+        // In the order of VertexSemantic::ALL, tangents only for normal mapped primitives
+        if !primitive.tangents.is_empty() {
+            vertex_buffers.push(vertex_buffer(bytemuck::cast_slice(&primitive.tangents)));
+        }
+        let index_buffer = renderer.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&primitive.indices),
+                usage: wgpu::BufferUsages::INDEX,
+            });
+        PrimitiveBuffers {
+            vertex_buffers,
+            index_buffer,
+            num_indices: primitive.indices.len() as u32,
+            material: primitive.material.unwrap_or(self.materials.len()),
+            morph: Self::upload_morph_targets(primitive, renderer),
+            blended: primitive.is_blended(&self.materials),
+        }
+    }
+    fn upload_morph_targets(primitive: &Primitive, renderer: &Renderer) -> Option<wgpu::BindGroup> {
+        if primitive.morph_targets.is_empty() {
+            return None;
+        }
+        assert!(
+            primitive.morph_targets.len() <= MAX_MORPH_TARGETS,
+            "Primitives may have at most {} morph targets, see Primitive::limit_morph_targets",
+            MAX_MORPH_TARGETS
+        );
+        // Deltas are laid out target by target so the shader can index them by vertex
+        let deltas = primitive
+            .morph_targets
+            .iter()
+            .flat_map(|target| {
+                target
+                    .positions
+                    .iter()
+                    .zip(target.normals.iter())
+                    .map(|(p, n)| MorphDelta {
+                        position: [p[0], p[1], p[2], 0.0],
+                        normal: [n[0], n[1], n[2], 0.0],
+                    })
+            })
+            .collect::<Vec<_>>();
+        let deltas_buffer = renderer.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Morph target buffer"),
+                contents: bytemuck::cast_slice(&deltas),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let info = [primitive.positions.len() as u32, primitive.morph_targets.len() as u32, 0, 0];
+        let info_buffer = renderer.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Morph info buffer"),
+                contents: bytemuck::cast_slice(&info),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        Some(renderer.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Morph target bind group"),
+            layout: &renderer.default_pipeline.morph_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: deltas_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: info_buffer.as_entire_binding(),
+                },
+            ],
+        }))
+    }
+}
+
+// A node, or mesh index for plain meshes, with its transform and morph weights
+type Instance = (usize, cgmath::Matrix4<f32>, MorphWeights);
+
+// Per instance vertex data, followed by morph weights for meshes with targets
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct InstanceData {
+    pub transform: [[f32; 4]; 4],
+    // 1 when shadows darken the instance
+    pub receives_shadow: f32,
+}
+
+const INSTANCE_ATTRIBUTES: [wgpu::VertexAttribute; 5] = wgpu::vertex_attr_array![
+    0 => Float32x4, 1 => Float32x4, 2 => Float32x4, 3 => Float32x4, 9 => Float32
+];
+impl InstanceData {
+    pub fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<InstanceData>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &INSTANCE_ATTRIBUTES,
+        }
+    }
+}
+
+// The instances of a mesh asset drawn for one entity in one frame. Instances are
+// keyed by node so that poses can move them, plain meshes use their mesh index.
+pub struct MeshFrameState<'a> {
+    pub meshes: Vec<Vec<Instance>>,
+    pub mesh_asset: &'a MeshAsset,
+    global_transform: cgmath::Matrix4<f32>,
+    casts_shadow: bool,
+    receives_shadow: bool,
+}
+
+impl<'a> MeshFrameState<'a> {
+    // Draws every mesh once, positioned only by the global transform
+    pub fn new(mesh_asset: &'a MeshAsset) -> Self {
+        Self::with_instances(mesh_asset, |mesh_idx| {
+            vec![(mesh_idx, cgmath::Matrix4::one(), MorphWeights::default())]
+        })
+    }
+    pub fn with_instances(
+        mesh_asset: &'a MeshAsset,
+        instances: impl Fn(usize) -> Vec<Instance>,
+    ) -> Self {
+        Self {
+            meshes: (0..mesh_asset.meshes.len()).map(instances).collect(),
+            mesh_asset,
+            global_transform: cgmath::Matrix4::one(),
+            casts_shadow: false,
+            receives_shadow: false,
+        }
+    }
+
+    // Moves existing instances to the transforms and weights of their nodes
+    pub fn set_pose(&mut self, transforms: &[cgmath::Matrix4<f32>], weights: &[MorphWeights]) {
+        for (node_idx, transform, node_weights) in self.meshes.iter_mut().flatten() {
+            *transform = transforms[*node_idx];
+            *node_weights = weights[*node_idx];
+        }
+    }
+
+    // Overrides the morph target weights of every mesh instance
+    pub fn set_morph_weights(&mut self, weights: &[f32]) {
+        let weights = MorphWeights::from_slice(weights);
+        for (_, _, node_weights) in self.meshes.iter_mut().flatten() {
+            *node_weights = weights;
+        }
+    }
+
+    pub fn set_global_transform(&mut self, transform: cgmath::Matrix4<f32>) {
+        self.global_transform = transform;
+    }
+
+    pub fn set_shadows(&mut self, casts_shadow: bool, receives_shadow: bool) {
+        self.casts_shadow = casts_shadow;
+        self.receives_shadow = receives_shadow;
+    }
+}
+
+// One instance of a blended primitive, drawn on its own so that transparent
+// surfaces can be sorted across batches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransparentDraw {
+    pub batch: usize,
+    pub mesh: usize,
+    pub primitive: usize,
+    // Index of the instance in the mesh's transforms
+    pub instance: u32,
+    // From the camera to the center of the primitive's bounds
+    pub distance: f32,
+}
+
+// The instances of every entity drawing the same mesh asset, so that each
+// primitive is drawn once per frame however many entities share it
+pub struct MeshBatch<'a> {
+    pub mesh_asset: &'a MeshAsset,
+    // Casters and other entities are batched apart so the shadow pass can skip the latter
+    pub casts_shadow: bool,
+    // Per mesh, in the order the frame states were given
+    transforms: Vec<Vec<InstanceData>>,
+    weights: Vec<Vec<[f32; MAX_MORPH_TARGETS]>>,
+    // Where each mesh's transforms and weights were written in the instance arena
+    ranges: Vec<(Range<u64>, Range<u64>)>,
+    // The pipeline of every primitive, for the renderer's sample count
+    pipelines: Vec<Vec<Rc<wgpu::RenderPipeline>>>,
+}
+
+impl<'a> MeshBatch<'a> {
+    fn new(mesh_asset: &'a MeshAsset, casts_shadow: bool) -> Self {
+        let meshes = mesh_asset.meshes.len();
+        Self {
+            mesh_asset,
+            casts_shadow,
+            transforms: vec![Vec::new(); meshes],
+            weights: vec![Vec::new(); meshes],
+            ranges: vec![(0..0, 0..0); meshes],
+            pipelines: Vec::new(),
+        }
+    }
+
+    // Groups frame states by mesh asset, keeping the order assets first appear in
+    pub fn from_frame_states(frame_states: &[MeshFrameState<'a>]) -> Vec<Self> {
+        let mut batches: Vec<Self> = Vec::new();
+        let mut asset_batches = HashMap::new();
+        for frame_state in frame_states {
+            let key = (frame_state.mesh_asset as *const MeshAsset, frame_state.casts_shadow);
+            let batch_idx = *asset_batches.entry(key).or_insert_with(|| {
+                batches.push(Self::new(frame_state.mesh_asset, frame_state.casts_shadow));
+                batches.len() - 1
+            });
+            let batch = &mut batches[batch_idx];
+            for (mesh_idx, instances) in frame_state.meshes.iter().enumerate() {
+                for &(_, transform, weights) in instances {
+                    let transform = frame_state.global_transform * transform;
+                    batch.transforms[mesh_idx].push(InstanceData {
+                        transform: transform.into(),
+                        receives_shadow: if frame_state.receives_shadow { 1.0 } else { 0.0 },
+                    });
+                    batch.weights[mesh_idx].push(weights.0);
+                }
+            }
+        }
+        batches
+    }
+
+    // Every instance of a blended primitive, furthest from the camera first
+    pub fn transparent_draws(batches: &[Self], eye: cgmath::Point3<f32>) -> Vec<TransparentDraw> {
+        let mut draws = Vec::new();
+        for (batch_idx, batch) in batches.iter().enumerate() {
+            let asset = batch.mesh_asset;
+            for (mesh_idx, mesh) in asset.meshes.iter().enumerate() {
+                let blended = mesh
+                    .primitives
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, primitive)| primitive.is_blended(&asset.materials));
+                for (primitive_idx, primitive) in blended {
+                    let center = primitive.bounds.sphere.center.to_homogeneous();
+                    for (instance, data) in batch.transforms[mesh_idx].iter().enumerate() {
+                        let center = cgmath::Matrix4::from(data.transform) * center;
+                        draws.push(TransparentDraw {
+                            batch: batch_idx,
+                            mesh: mesh_idx,
+                            primitive: primitive_idx,
+                            instance: instance as u32,
+                            distance: (cgmath::Point3::from_homogeneous(center) - eye).magnitude(),
+                        });
+                    }
+                }
+            }
+        }
+        draws.sort_by(|a, b| b.distance.total_cmp(&a.distance));
+        draws
+    }
+
+    // Drops the instances whose world bounds are not visible, returning how many
+    // were kept and how many dropped. Every primitive of a mesh draws from the same
+    // instance range, so an instance is kept or dropped for all of them and is tested
+    // against the box around them all. Primitive bounds include their morph targets.
+    pub fn cull(&mut self, visible: impl Fn(&Aabb) -> bool) -> (usize, usize) {
+        let (mut kept, mut culled) = (0, 0);
+        for (mesh_idx, mesh) in self.mesh_asset.meshes.iter().enumerate() {
+            let aabb = match mesh.aabb() {
+                Some(aabb) => aabb,
+                None => continue,
+            };
+            let transforms = &self.transforms[mesh_idx];
+            let keep = transforms
+                .iter()
+                .map(|instance| visible(&aabb.transform(instance.transform.into())))
+                .collect::<Vec<_>>();
+            let mut keep_iter = keep.iter();
+            self.transforms[mesh_idx].retain(|_| *keep_iter.next().unwrap());
+            let mut keep_iter = keep.iter();
+            self.weights[mesh_idx].retain(|_| *keep_iter.next().unwrap());
+            let visible_count = keep.iter().filter(|&&keep| keep).count();
+            kept += visible_count;
+            culled += keep.len() - visible_count;
+        }
+        (kept, culled)
+    }
+
+    pub fn num_instances(&self, mesh_idx: usize) -> usize {
+        self.transforms[mesh_idx].len()
+    }
+
+    // Uploads the asset if needed, looks up the pipelines of its primitives and
+    // stages this frame's instances in the arena
+    pub fn write_instances(&mut self, renderer: &Renderer, arena: &mut BufferArena) {
+        let mesh_buffers = self.mesh_asset.buffers(renderer);
+        let asset = self.mesh_asset;
+        self.pipelines = asset
+            .meshes
+            .iter()
+            .map(|mesh| {
+                mesh.primitives
+                    .iter()
+                    .map(|primitive| {
+                        renderer.mesh_pipeline(primitive.pipeline_key(
+                            &asset.materials,
+                            renderer.color_format(),
+                            renderer.sample_count(),
+                        ))
+                    })
+                    .collect()
+            })
+            .collect();
+        for (mesh_idx, mesh) in mesh_buffers.meshes.iter().enumerate() {
+            if self.transforms[mesh_idx].is_empty() {
+                continue;
+            }
+            let transforms = arena.push(bytemuck::cast_slice(&self.transforms[mesh_idx]));
+            let weights = if mesh.iter().any(|primitive| primitive.morph.is_some()) {
+                arena.push(bytemuck::cast_slice(&self.weights[mesh_idx]))
+            } else {
+                0..0
+            };
+            self.ranges[mesh_idx] = (transforms, weights);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quad(uvs: Vec<[f32; 2]>) -> Primitive {
+        Primitive {
+            positions: vec![
+                [-1.0, 0.0, -1.0],
+                [-1.0, 0.0, 1.0],
+                [1.0, 0.0, 1.0],
+                [1.0, 0.0, -1.0],
+            ],
+            normals: Vec::new(),
+            uvs,
+            tangents: Vec::new(),
+            indices: vec![0, 1, 2, 0, 2, 3],
+            material: None,
+            morph_targets: Vec::new(),
+            dropped_morph_targets: 0,
+            lods: Vec::new(),
+            bounds: Bounds::default(),
+        }
+    }
+
+    #[test]
+    fn smooth_normals_keep_shared_vertices() {
+        let mut primitive = quad(vec![[0.0, 0.0]; 4]);
+        primitive.generate_normals(Shading::Smooth);
+        assert_eq!(primitive.positions.len(), 4);
+        assert_eq!(primitive.normals, vec![[0.0, 1.0, 0.0]; 4]);
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_tangent_handedness() {
+        let uvs = vec![[0.0, 0.0], [0.0, 1.0], [1.0, 1.0], [1.0, 0.0]];
+        let mut primitive = quad(uvs.clone());
+        primitive.generate_normals(Shading::Smooth);
+        primitive.generate_tangents();
+        assert_eq!(primitive.tangents, vec![[1.0, 0.0, 0.0, 1.0]; 4]);
+
+        let mut mirrored = quad(uvs.iter().map(|&[u, v]| [1.0 - u, v]).collect());
+        mirrored.generate_normals(Shading::Smooth);
+        mirrored.generate_tangents();
+        assert_eq!(mirrored.tangents, vec![[-1.0, 0.0, 0.0, -1.0]; 4]);
+    }
+
+    #[test]
+    fn tangents_follow_u_across_the_surface() {
+        // u runs along z and v along x, so the bitangent, which points up the
+        // texture, is -x and mirrored relative to cross(normal, tangent) = +x
+        let uvs = vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+        let mut primitive = quad(uvs);
+        primitive.generate_normals(Shading::Smooth);
+        primitive.generate_tangents();
+        assert_eq!(primitive.positions.len(), 4);
+        assert_eq!(primitive.tangents, vec![[0.0, 0.0, 1.0, -1.0]; 4]);
+        assert!(primitive.vertex_layout().contains(VertexSemantic::Tangent));
+        assert!(!quad(Vec::new()).vertex_layout().contains(VertexSemantic::Tangent));
+    }
+
+    #[test]
+    fn entities_sharing_an_asset_are_batched() {
+        let asset = |shape: crate::shapes::Shape| {
+            MeshAsset::new(shape.name(), vec![shape.mesh()], Vec::new(), Vec::new())
+        };
+        let cube = asset(crate::shapes::Shape::Cube { size: 1.0 });
+        let plane = asset(crate::shapes::Shape::Plane {
+            width: 1.0,
+            depth: 1.0,
+            subdivisions: 0,
+        });
+        let frame_states = (0..5)
+            .map(|i| {
+                let mut frame_state = MeshFrameState::new(if i == 2 { &plane } else { &cube });
+                let offset = cgmath::vec3(i as f32, 0.0, 0.0);
+                frame_state.set_global_transform(cgmath::Matrix4::from_translation(offset));
+                frame_state
+            })
+            .collect::<Vec<_>>();
+        let batches = MeshBatch::from_frame_states(&frame_states);
+        assert_eq!(batches.len(), 2);
+        assert!(std::ptr::eq(batches[0].mesh_asset, &cube));
+        assert_eq!(batches[0].num_instances(0), 4);
+        assert_eq!(batches[1].num_instances(0), 1);
+        let offsets = batches[0].transforms[0]
+            .iter()
+            .map(|instance| instance.transform[3][0])
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![0.0, 1.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn shadow_casters_are_batched_apart() {
+        let shape = crate::shapes::Shape::Cube { size: 1.0 };
+        let cube = MeshAsset::new(shape.name(), vec![shape.mesh()], Vec::new(), Vec::new());
+        let frame_states = [(true, true), (false, true), (true, false)]
+            .into_iter()
+            .map(|(casts_shadow, receives_shadow)| {
+                let mut frame_state = MeshFrameState::new(&cube);
+                frame_state.set_shadows(casts_shadow, receives_shadow);
+                frame_state
+            })
+            .collect::<Vec<_>>();
+        let batches = MeshBatch::from_frame_states(&frame_states);
+        assert_eq!(batches.len(), 2);
+        assert!(batches[0].casts_shadow && !batches[1].casts_shadow);
+        let receives = batches[0].transforms[0]
+            .iter()
+            .map(|instance| instance.receives_shadow)
+            .collect::<Vec<_>>();
+        assert_eq!(receives, vec![1.0, 0.0]);
+    }
+
+    #[test]
+    fn transparent_instances_are_drawn_back_to_front() {
+        let mut window = quad(vec![[0.0, 0.0]; 4]);
+        window.material = Some(0);
+        let solid = quad(vec![[0.0, 0.0]; 4]);
+        let mesh = Mesh {
+            name: None,
+            primitives: vec![solid, window],
+        };
+        let material = Material {
+            alpha_mode: AlphaMode::Blend,
+            ..Default::default()
+        };
+        let asset = MeshAsset::new("windows", vec![mesh], vec![material], Vec::new());
+        let frame_states = [2.0, 8.0, 5.0]
+            .into_iter()
+            .map(|z| {
+                let mut frame_state = MeshFrameState::new(&asset);
+                let offset = cgmath::vec3(0.0, 0.0, z);
+                frame_state.set_global_transform(cgmath::Matrix4::from_translation(offset));
+                frame_state
+            })
+            .collect::<Vec<_>>();
+        let batches = MeshBatch::from_frame_states(&frame_states);
+        let draws = MeshBatch::transparent_draws(&batches, cgmath::point3(0.0, 0.0, 10.0));
+        let order = draws
+            .iter()
+            .map(|draw| (draw.primitive, draw.instance))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![(1, 0), (1, 2), (1, 1)]);
+    }
+
+    #[test]
+    fn culled_instances_are_dropped_with_their_weights() {
+        let shape = crate::shapes::Shape::Cube { size: 1.0 };
+        let cube = MeshAsset::new(shape.name(), vec![shape.mesh()], Vec::new(), Vec::new());
+        let frame_states = [0.0, 10.0, 2.0]
+            .into_iter()
+            .map(|x| {
+                let mut frame_state = MeshFrameState::new(&cube);
+                let offset = cgmath::vec3(x, 0.0, 0.0);
+                frame_state.set_global_transform(cgmath::Matrix4::from_translation(offset));
+                frame_state
+            })
+            .collect::<Vec<_>>();
+        let mut batches = MeshBatch::from_frame_states(&frame_states);
+        let (kept, culled) = batches[0].cull(|aabb| aabb.max.x < 5.0);
+        assert_eq!((kept, culled), (2, 1));
+        assert_eq!(batches[0].num_instances(0), 2);
+        assert_eq!(batches[0].weights[0].len(), 2);
+        assert_eq!(batches[0].transforms[0][1].transform[3][0], 2.0);
+    }
+
+    #[test]
+    fn primitives_alike_share_a_pipeline_key() {
+        let format = wgpu::TextureFormat::Rgba16Float;
+        let plain = [Material::default()];
+        let double_sided = [Material {
+            double_sided: true,
+            ..Default::default()
+        }];
+        let mut a = quad(vec![[0.0, 0.0]; 4]);
+        let mut b = quad(Vec::new());
+        a.material = Some(0);
+        b.material = Some(0);
+        assert_eq!(a.pipeline_key(&plain, format, 4), b.pipeline_key(&plain, format, 4));
+        assert_ne!(a.pipeline_key(&plain, format, 4), a.pipeline_key(&plain, format, 1));
+        let key = a.pipeline_key(&double_sided, format, 4);
+        assert_eq!(key.cull_mode, None);
+        assert_ne!(key, b.pipeline_key(&plain, format, 4));
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+
+use cgmath::{EuclideanSpace, InnerSpace, MetricSpace};
+
+use crate::mesh::Primitive;
+
+const MAX_LODS: usize = 3;
+// Finest LOD grid, in cells along the longest side of the bounds
+const LOD_RESOLUTION: f32 = 32.0;
+// A LOD is only kept if it drops at least this share of its parent's triangles
+const LOD_MIN_REDUCTION: f32 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: cgmath::Point3<f32>,
+    pub max: cgmath::Point3<f32>,
+}
+impl Aabb {
+    pub fn from_points(points: impl IntoIterator<Item = cgmath::Point3<f32>>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |aabb, point| Self {
+                min: cgmath::point3(
+                    aabb.min.x.min(point.x),
+                    aabb.min.y.min(point.y),
+                    aabb.min.z.min(point.z),
+                ),
+                max: cgmath::point3(
+                    aabb.max.x.max(point.x),
+                    aabb.max.y.max(point.y),
+                    aabb.max.z.max(point.z),
+                ),
+            },
+        ))
+    }
+    pub fn center(&self) -> cgmath::Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+    pub fn size(&self) -> cgmath::Vector3<f32> {
+        self.max - self.min
+    }
+    pub fn corners(&self) -> [cgmath::Point3<f32>; 8] {
+        let (min, max) = (self.min, self.max);
+        [
+            cgmath::point3(min.x, min.y, min.z),
+            cgmath::point3(max.x, min.y, min.z),
+            cgmath::point3(min.x, max.y, min.z),
+            cgmath::point3(max.x, max.y, min.z),
+            cgmath::point3(min.x, min.y, max.z),
+            cgmath::point3(max.x, min.y, max.z),
+            cgmath::point3(min.x, max.y, max.z),
+            cgmath::point3(max.x, max.y, max.z),
+        ]
+    }
+    pub fn union(&self, other: &Self) -> Self {
+        Self::from_points([self.min, self.max, other.min, other.max]).unwrap()
+    }
+    // The box around the transformed corners
+    pub fn transform(&self, matrix: cgmath::Matrix4<f32>) -> Self {
+        Self::from_points(
+            self.corners()
+                .map(|corner| cgmath::Transform::transform_point(&matrix, corner)),
+        )
+        .unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: cgmath::Point3<f32>,
+    pub radius: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub aabb: Aabb,
+    // Centered on the box, so it is not always the tightest sphere
+    pub sphere: BoundingSphere,
+}
+impl Default for Bounds {
+    fn default() -> Self {
+        let origin = cgmath::Point3::origin();
+        Self {
+            aabb: Aabb {
+                min: origin,
+                max: origin,
+            },
+            sphere: BoundingSphere {
+                center: origin,
+                radius: 0.0,
+            },
+        }
+    }
+}
+impl Bounds {
+    pub fn from_points(points: impl IntoIterator<Item = cgmath::Point3<f32>> + Clone) -> Self {
+        let aabb = match Aabb::from_points(points.clone()) {
+            Some(aabb) => aabb,
+            None => return Self::default(),
+        };
+        let center = aabb.center();
+        let radius = points
+            .into_iter()
+            .map(|point| point.distance(center))
+            .fold(0.0, f32::max);
+        Self {
+            aabb,
+            sphere: BoundingSphere { center, radius },
+        }
+    }
+    // Includes every blend of the morph targets with weights between 0 and 1, by
+    // moving each vertex by all of its negative deltas, then all of its positive ones
+    pub fn from_primitive(primitive: &Primitive) -> Self {
+        let extents = primitive.positions.iter().enumerate().flat_map(|(index, &p)| {
+            let p = cgmath::Point3::from(p);
+            let (mut min, mut max) = (p, p);
+            for target in &primitive.morph_targets {
+                let delta = target.positions[index];
+                for axis in 0..3 {
+                    min[axis] += delta[axis].min(0.0);
+                    max[axis] += delta[axis].max(0.0);
+                }
+            }
+            [p, min, max]
+        });
+        Self::from_points(extents)
+    }
+}
+
+// A coarser set of triangles over the primitive's vertices
+pub struct Lod {
+    pub indices: Vec<u32>,
+    // Size of the grid cells the vertices were merged in, in model units
+    pub error: f32,
+}
+
+// Import steps that are only taken when asked for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessSettings {
+    pub lods: bool,
+}
+impl ProcessSettings {
+    // What the OBJ and glTF loaders apply. Built-in shapes are coarse enough without LODs.
+    pub const IMPORT: Self = Self { lods: true };
+}
+
+// Everything applied to primitives at import time
+pub fn process(primitive: &mut Primitive, settings: ProcessSettings) {
+    weld(primitive);
+    let vertex_count = primitive.positions.len();
+    optimize_vertex_cache(&mut primitive.indices, vertex_count);
+    optimize_vertex_fetch(primitive);
+    primitive.bounds = Bounds::from_primitive(primitive);
+    if settings.lods {
+        primitive.lods = generate_lods(primitive);
+    }
+}
+
+// Replaces the vertices with the ones listed in `order` by their old index
+fn reorder_vertices(primitive: &mut Primitive, order: &[u32]) {
+    fn reorder<T: Copy>(values: &mut Vec<T>, order: &[u32]) {
+        if !values.is_empty() {
+            *values = order.iter().map(|&index| values[index as usize]).collect();
+        }
+    }
+    reorder(&mut primitive.positions, order);
+    reorder(&mut primitive.normals, order);
+    reorder(&mut primitive.uvs, order);
+    reorder(&mut primitive.tangents, order);
+    for target in &mut primitive.morph_targets {
+        reorder(&mut target.positions, order);
+        reorder(&mut target.normals, order);
+    }
+}
+
+// Merges vertices whose attributes are all bitwise equal, in triangle lists
+pub fn weld(primitive: &mut Primitive) {
+    if !primitive.indices.len().is_multiple_of(3) {
+        return;
+    }
+    let key = |index: usize| {
+        let mut key = Vec::new();
+        key.extend(primitive.positions[index].map(f32::to_bits));
+        if let Some(normal) = primitive.normals.get(index) {
+            key.extend(normal.map(f32::to_bits));
+        }
+        if let Some(uv) = primitive.uvs.get(index) {
+            key.extend(uv.map(f32::to_bits));
+        }
+        if let Some(tangent) = primitive.tangents.get(index) {
+            key.extend(tangent.map(f32::to_bits));
+        }
+        for target in &primitive.morph_targets {
+            key.extend(target.positions[index].map(f32::to_bits));
+            key.extend(target.normals[index].map(f32::to_bits));
+        }
+        key
+    };
+    let mut unique = HashMap::new();
+    let mut order = Vec::new();
+    let remap = (0..primitive.positions.len())
+        .map(|index| {
+            *unique.entry(key(index)).or_insert_with(|| {
+                order.push(index as u32);
+                order.len() as u32 - 1
+            })
+        })
+        .collect::<Vec<u32>>();
+    if order.len() == primitive.positions.len() {
+        return;
+    }
+    reorder_vertices(primitive, &order);
+    for index in primitive.indices.iter_mut() {
+        *index = remap[*index as usize];
+    }
+}
+
+// Lays vertices out in the order the indices first use them, dropping unused ones
+pub fn optimize_vertex_fetch(primitive: &mut Primitive) {
+    let mut remap = vec![u32::MAX; primitive.positions.len()];
+    let mut order = Vec::new();
+    for index in primitive.indices.iter_mut() {
+        if remap[*index as usize] == u32::MAX {
+            remap[*index as usize] = order.len() as u32;
+            order.push(*index);
+        }
+        *index = remap[*index as usize];
+    }
+    reorder_vertices(primitive, &order);
+}
+
+const CACHE_SIZE: usize = 32;
+
+// Tom Forsyth's linear-speed vertex cache optimisation scores
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        None => 0.0,
+        // The last triangle's vertices are scored equally, whatever their order
+        Some(position) if position < 3 => 0.75,
+        Some(position) => (1.0 - (position - 3) as f32 / (CACHE_SIZE - 3) as f32).powf(1.5),
+    };
+    cache_score + 2.0 / (remaining_triangles as f32).sqrt()
+}
+
+// Reorders triangles so that consecutive ones reuse recently transformed vertices
+pub fn optimize_vertex_cache(indices: &mut [u32], vertex_count: usize) {
+    if !indices.len().is_multiple_of(3) {
+        return;
+    }
+    let triangle_count = indices.len() / 3;
+    let mut vertex_triangles = vec![Vec::new(); vertex_count];
+    for (triangle, vertices) in indices.chunks_exact(3).enumerate() {
+        for &vertex in vertices {
+            vertex_triangles[vertex as usize].push(triangle);
+        }
+    }
+    let mut cache_positions = vec![None; vertex_count];
+    let mut vertex_scores = vertex_triangles
+        .iter()
+        .map(|triangles| vertex_score(None, triangles.len()))
+        .collect::<Vec<_>>();
+    let mut emitted = vec![false; triangle_count];
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut output = Vec::with_capacity(indices.len());
+    let mut next = None;
+    // Dead ends continue from the first triangle not emitted yet
+    let mut cursor = 0;
+    for _ in 0..triangle_count {
+        let best = match next {
+            Some(triangle) => triangle,
+            None => {
+                while emitted[cursor] {
+                    cursor += 1;
+                }
+                cursor
+            }
+        };
+        emitted[best] = true;
+        let vertices = [
+            indices[best * 3],
+            indices[best * 3 + 1],
+            indices[best * 3 + 2],
+        ];
+        output.extend_from_slice(&vertices);
+        for vertex in vertices {
+            vertex_triangles[vertex as usize].retain(|&triangle| triangle != best);
+        }
+
+        cache.retain(|vertex| !vertices.contains(vertex));
+        cache.splice(0..0, vertices);
+        let evicted = cache.split_off(cache.len().min(CACHE_SIZE));
+        for (position, &vertex) in cache.iter().enumerate() {
+            cache_positions[vertex as usize] = Some(position);
+        }
+        for &vertex in &evicted {
+            cache_positions[vertex as usize] = None;
+        }
+
+        for &vertex in cache.iter().chain(&evicted) {
+            let vertex = vertex as usize;
+            vertex_scores[vertex] =
+                vertex_score(cache_positions[vertex], vertex_triangles[vertex].len());
+        }
+        next = None;
+        let mut best_score = f32::MIN;
+        for &vertex in cache.iter().chain(&evicted) {
+            for &triangle in &vertex_triangles[vertex as usize] {
+                let score: f32 = indices[triangle * 3..triangle * 3 + 3]
+                    .iter()
+                    .map(|&vertex| vertex_scores[vertex as usize])
+                    .sum();
+                if score > best_score {
+                    best_score = score;
+                    next = Some(triangle);
+                }
+            }
+        }
+    }
+    indices.copy_from_slice(&output);
+}
+
+// Vertex clustering: vertices in the same grid cell collapse into the one closest
+// to the cell's average and triangles that lose an edge are dropped
+pub fn simplify(primitive: &Primitive, indices: &[u32], cell_size: f32) -> Vec<u32> {
+    let cell =
+        |index: u32| primitive.positions[index as usize].map(|c| (c / cell_size).floor() as i32);
+    let mut sums: HashMap<[i32; 3], (cgmath::Vector3<f32>, f32)> = HashMap::new();
+    for &index in indices {
+        let (sum, count) = sums
+            .entry(cell(index))
+            .or_insert((cgmath::Vector3::new(0.0, 0.0, 0.0), 0.0));
+        *sum += cgmath::Vector3::from(primitive.positions[index as usize]);
+        *count += 1.0;
+    }
+    let mut representatives: HashMap<[i32; 3], (u32, f32)> = HashMap::new();
+    for &index in indices {
+        let (sum, count) = sums[&cell(index)];
+        let distance =
+            (cgmath::Vector3::from(primitive.positions[index as usize]) - sum / count).magnitude2();
+        let representative = representatives
+            .entry(cell(index))
+            .or_insert((index, distance));
+        if distance < representative.1 {
+            *representative = (index, distance);
+        }
+    }
+    indices
+        .chunks_exact(3)
+        .map(|triangle| {
+            [triangle[0], triangle[1], triangle[2]].map(|index| representatives[&cell(index)].0)
+        })
+        .filter(|[a, b, c]| a != b && b != c && a != c)
+        .flatten()
+        .collect()
+}
+
+// Progressively coarser LODs, each with clearly fewer triangles than the last
+pub fn generate_lods(primitive: &Primitive) -> Vec<Lod> {
+    let bounds = Bounds::from_primitive(primitive);
+    let size = bounds.aabb.size();
+    let extent = size.x.max(size.y).max(size.z);
+    let mut lods: Vec<Lod> = Vec::new();
+    if extent <= 0.0 {
+        return lods;
+    }
+    let mut cell_size = extent / LOD_RESOLUTION;
+    while lods.len() < MAX_LODS {
+        let previous = lods.last().map_or(&primitive.indices, |lod| &lod.indices);
+        let mut indices = simplify(primitive, previous, cell_size);
+        if indices.is_empty() {
+            break;
+        }
+        if (indices.len() as f32) <= previous.len() as f32 * (1.0 - LOD_MIN_REDUCTION) {
+            optimize_vertex_cache(&mut indices, primitive.positions.len());
+            lods.push(Lod {
+                indices,
+                error: cell_size,
+            });
+        }
+        cell_size *= 2.0;
+    }
+    lods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A flat grid of quads over [0, n] in x and z, with unshared vertices
+    fn grid(n: u32) -> Primitive {
+        let mut positions = Vec::new();
+        for x in 0..n {
+            for z in 0..n {
+                let (x, z) = (x as f32, z as f32);
+                positions.extend([[x, 0.0, z], [x, 0.0, z + 1.0], [x + 1.0, 0.0, z + 1.0]]);
+                positions.extend([[x, 0.0, z], [x + 1.0, 0.0, z + 1.0], [x + 1.0, 0.0, z]]);
+            }
+        }
+        Primitive {
+            normals: vec![[0.0, 1.0, 0.0]; positions.len()],
+            uvs: Vec::new(),
+            tangents: Vec::new(),
+            indices: (0..positions.len() as u32).collect(),
+            positions,
+            material: None,
+            morph_targets: Vec::new(),
+            dropped_morph_targets: 0,
+            lods: Vec::new(),
+            bounds: Bounds::default(),
+        }
+    }
+
+    fn triangles(primitive: &Primitive, indices: &[u32]) -> Vec<[[u32; 3]; 3]> {
+        let mut triangles = indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let mut corners = [triangle[0], triangle[1], triangle[2]]
+                    .map(|index| primitive.positions[index as usize].map(|c| c as u32));
+                // Rotate so that the smallest corner comes first, keeping the winding
+                let first = (0..3).min_by_key(|&i| corners[i]).unwrap();
+                corners.rotate_left(first);
+                corners
+            })
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn welding_merges_identical_vertices() {
+        let mut primitive = grid(4);
+        let before = triangles(&primitive, &primitive.indices);
+        weld(&mut primitive);
+        assert_eq!(primitive.positions.len(), 25);
+        assert_eq!(primitive.normals.len(), 25);
+        assert_eq!(triangles(&primitive, &primitive.indices), before);
+    }
+
+    #[test]
+    fn cache_optimisation_keeps_triangles_and_winding() {
+        let mut primitive = grid(8);
+        let before = triangles(&primitive, &primitive.indices);
+        process(&mut primitive, ProcessSettings::default());
+        assert!(primitive.lods.is_empty());
+        assert_eq!(triangles(&primitive, &primitive.indices), before);
+        // Vertices are laid out in the order they are first used
+        let mut seen = 0;
+        for &index in &primitive.indices {
+            assert!(index <= seen);
+            seen = seen.max(index + 1);
+        }
+    }
+
+    #[test]
+    fn index_lists_that_are_not_triangles_are_left_alone() {
+        let mut primitive = grid(2);
+        primitive.indices.truncate(primitive.indices.len() - 1);
+        let indices = primitive.indices.clone();
+        weld(&mut primitive);
+        optimize_vertex_cache(&mut primitive.indices, primitive.positions.len());
+        assert_eq!(primitive.indices, indices);
+    }
+
+    #[test]
+    fn bounds_contain_positions_and_morph_targets() {
+        let mut primitive = grid(2);
+        primitive.morph_targets.push(crate::mesh::MorphTarget {
+            positions: vec![[0.0, 3.0, 0.0]; primitive.positions.len()],
+            normals: vec![[0.0; 3]; primitive.positions.len()],
+        });
+        let bounds = Bounds::from_primitive(&primitive);
+        assert_eq!(bounds.aabb.min, cgmath::point3(0.0, 0.0, 0.0));
+        assert_eq!(bounds.aabb.max, cgmath::point3(2.0, 3.0, 2.0));
+        assert_eq!(bounds.sphere.center, cgmath::point3(1.0, 1.5, 1.0));
+        assert!((bounds.sphere.radius - 1.5f32.hypot(2f32.sqrt())).abs() < 1e-5);
+    }
+
+    #[test]
+    fn bounds_contain_blended_morph_targets() {
+        let mut primitive = grid(2);
+        for delta in [[0.0, 3.0, 0.0], [0.0, 2.0, -1.0]] {
+            primitive.morph_targets.push(crate::mesh::MorphTarget {
+                positions: vec![delta; primitive.positions.len()],
+                normals: vec![[0.0; 3]; primitive.positions.len()],
+            });
+        }
+        let bounds = Bounds::from_primitive(&primitive);
+        assert_eq!(bounds.aabb.min, cgmath::point3(0.0, 0.0, -1.0));
+        assert_eq!(bounds.aabb.max, cgmath::point3(2.0, 5.0, 2.0));
+    }
+
+    #[test]
+    fn lods_reduce_triangles() {
+        let mut primitive = grid(64);
+        process(&mut primitive, ProcessSettings { lods: true });
+        assert!(!primitive.lods.is_empty());
+        let mut previous = primitive.indices.len();
+        for lod in &primitive.lods {
+            assert!(lod.indices.len() < previous);
+            assert!(lod
+                .indices
+                .iter()
+                .all(|&index| (index as usize) < primitive.positions.len()));
+            previous = lod.indices.len();
+        }
+    }
+}
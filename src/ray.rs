@@ -1,11 +1,16 @@
 use crate::{
     asset_manager::AssetManager,
     component_manager::ComponentManager,
-    components::{model::Model, transform::Transform},
-    loaders, EntityHandle,
+    components::{model::Model, parent, scene_node::SceneNode},
+    loaders::gltf::GltfFile,
+    mesh::MeshAsset,
+    EntityHandle,
 };
 use cgmath::prelude::*;
 
+// A mesh asset and the meshes drawn from it with their transforms
+type MeshInstances<'a> = (&'a MeshAsset, Vec<(usize, cgmath::Matrix4<f32>)>);
+
 pub struct RayHit {
     pub entity: EntityHandle,
     pub position: cgmath::Point3<f32>,
@@ -39,33 +44,23 @@ impl Ray {
     ) -> Vec<RayHit> {
         let mut intersection_points = Vec::new();
         for ent in entities {
-            let model = cm
-                .get_component::<Model>(*ent)
-                .unwrap_or_else(|| panic!("Entity {:?} does not have a model component", ent));
-            let transform = cm
-                .get_component::<Transform>(*ent)
-                .unwrap_or_else(|| panic!("Entity {:?} does not have a transform component", ent));
-            let asset = am
-                .get_asset::<loaders::Model>(model.asset_handle)
-                .unwrap_or_else(|| panic!("Asset {:?} does not exist", model.asset_handle));
-
-            for mesh in asset.asset.meshes.iter() {
-                let vertices = &mesh.vertices;
-                for chunk in mesh.indices.chunks(3) {
-                    let transform = transform.to_matrix();
-                    let (v1, v2, v3) = (chunk[0], chunk[1], chunk[2]);
-                    let (v1, v2, v3) = (
-                        vertices[v1 as usize],
-                        vertices[v2 as usize],
-                        vertices[v3 as usize],
-                    );
-                    let triangle = Triangle(
-                        Ray::apply_transform(v1.position.into(), transform),
-                        Ray::apply_transform(v2.position.into(), transform),
-                        Ray::apply_transform(v3.position.into(), transform),
-                    );
-                    if let Some(p) = self.intersect(&triangle) {
-                        intersection_points.push(RayHit::new(*ent, p));
+            let (mesh_asset, instances) = match Ray::mesh_instances(*ent, cm, am) {
+                Some(mesh_instances) => mesh_instances,
+                None => continue,
+            };
+            let global = parent::global_matrix(cm, *ent);
+            for (mesh_idx, instance) in instances {
+                let transform = global * instance;
+                for primitive in mesh_asset.meshes[mesh_idx].primitives.iter() {
+                    for [v1, v2, v3] in primitive.triangles() {
+                        let triangle = Triangle(
+                            Ray::apply_transform(v1, transform),
+                            Ray::apply_transform(v2, transform),
+                            Ray::apply_transform(v3, transform),
+                        );
+                        if let Some(p) = self.intersect(&triangle) {
+                            intersection_points.push(RayHit::new(*ent, p));
+                        }
                     }
                 }
             }
@@ -77,6 +72,44 @@ impl Ray {
         });
         intersection_points
     }
+    // The meshes drawn for an entity's model or scene node, placed relative to the entity
+    fn mesh_instances<'a>(
+        entity: EntityHandle,
+        cm: &ComponentManager,
+        am: &'a AssetManager,
+    ) -> Option<MeshInstances<'a>> {
+        if let Some(model) = cm.get_component::<Model>(entity) {
+            if let Some(asset) = am.get_asset::<MeshAsset>(model.asset_handle) {
+                let mesh_asset = &asset.asset;
+                let instances = (0..mesh_asset.meshes.len())
+                    .map(|mesh_idx| (mesh_idx, cgmath::Matrix4::identity()))
+                    .collect();
+                return Some((mesh_asset, instances));
+            }
+            let gltf_file = &am
+                .get_asset::<GltfFile>(model.asset_handle)
+                .unwrap_or_else(|| panic!("Asset {:?} does not exist", model.asset_handle))
+                .asset;
+            let transforms = gltf_file.global_transforms(&gltf_file.rest_pose());
+            let instances = gltf_file
+                .scene_nodes(gltf_file.default_scene)
+                .into_iter()
+                .filter_map(|node_idx| {
+                    gltf_file.nodes[node_idx]
+                        .mesh
+                        .map(|mesh_idx| (mesh_idx, transforms[node_idx]))
+                })
+                .collect();
+            return Some((&gltf_file.mesh, instances));
+        }
+        let scene_node = cm.get_component::<SceneNode>(entity)?;
+        let gltf_file = &am
+            .get_asset::<GltfFile>(scene_node.asset_handle)
+            .unwrap_or_else(|| panic!("Asset {:?} does not exist", scene_node.asset_handle))
+            .asset;
+        let mesh_idx = gltf_file.nodes[scene_node.node].mesh?;
+        Some((&gltf_file.mesh, vec![(mesh_idx, cgmath::Matrix4::identity())]))
+    }
     fn apply_transform(v: cgmath::Point3<f32>, t: cgmath::Matrix4<f32>) -> cgmath::Point3<f32> {
         let v = cgmath::vec4(v.x, v.y, v.z, 1.0);
         let v = t * v;
@@ -127,6 +160,13 @@ impl Ray {
 
 #[cfg(test)]
 mod tests {
+    use crate::{
+        components::transform::Transform,
+        mesh::{Mesh, Primitive},
+        mesh_processing::Bounds,
+        world::World,
+    };
+
     use super::*;
 
     #[test]
@@ -212,4 +252,47 @@ mod tests {
         let result = Ray::apply_transform(p, geo.to_matrix());
         assert_eq!(result.distance(cgmath::point3(5.0, 5.0, 6.0)) < 0.1, true)
     }
+
+    #[test]
+    fn test_ray_hits_mesh_asset() {
+        let quad = Primitive {
+            positions: vec![
+                [-1.0, 0.0, -1.0],
+                [-1.0, 0.0, 1.0],
+                [1.0, 0.0, 1.0],
+                [1.0, 0.0, -1.0],
+            ],
+            normals: vec![[0.0, 1.0, 0.0]; 4],
+            uvs: vec![[0.0, 0.0]; 4],
+            tangents: Vec::new(),
+            indices: vec![0, 1, 2, 0, 2, 3],
+            material: None,
+            morph_targets: Vec::new(),
+            dropped_morph_targets: 0,
+            lods: Vec::new(),
+            bounds: Bounds::default(),
+        };
+        let mesh = Mesh {
+            name: None,
+            primitives: vec![quad],
+        };
+        let mut am = AssetManager::new();
+        let asset_handle = am.create_asset(MeshAsset::new("quad", vec![mesh], Vec::new(), Vec::new()));
+        let mut world = World::new();
+        let mut cm = ComponentManager::new();
+        cm.register_component::<Model>();
+        cm.register_component::<Transform>();
+        cm.register_component::<SceneNode>();
+        cm.register_component::<parent::Parent>();
+        let floor = world.spawn();
+        cm.add_component(Model { asset_handle, animation: None }, floor);
+        cm.add_component(Transform::new(Some(cgmath::point3(10.0, 2.0, 0.0)), None, None), floor);
+        let empty = world.spawn();
+
+        let ray = Ray::new(cgmath::point3(10.25, 5.0, 0.5), cgmath::vec3(0.0, -1.0, 0.0));
+        let hits = ray.test(&[floor, empty], &cm, &am);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].entity, floor);
+        assert_eq!(hits[0].position, cgmath::point3(10.25, 2.0, 0.5));
+    }
 }
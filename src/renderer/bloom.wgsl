@@ -0,0 +1,77 @@
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.uv = uv;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+struct Post {
+    // x is the exposure, y the bloom threshold, z the bloom intensity and w the
+    // softness of the threshold
+    exposure_bloom: vec4<f32>,
+    // x selects the tonemapper: 0 clamps, 1 is Reinhard and 2 is ACES
+    tonemapper: vec4<u32>,
+}
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+
+@group(1) @binding(0)
+var<uniform> post: Post;
+
+// Keeps what is brighter than the threshold, fading in over the soft knee
+fn threshold(color: vec3<f32>) -> vec3<f32> {
+    let brightness = max(color.r, max(color.g, color.b));
+    let knee = post.exposure_bloom.y * post.exposure_bloom.w;
+    var soft = clamp(brightness - post.exposure_bloom.y + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee + 0.00001);
+    let contribution = max(soft, brightness - post.exposure_bloom.y) / max(brightness, 0.00001);
+    return color * contribution;
+}
+
+// Downsamples the scene, averaging four bilinear taps that each cover four texels
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < 4u; i = i + 1u) {
+        let corner = vec2<f32>(f32(i & 1u), f32(i >> 1u)) * 2.0 - 1.0;
+        color = color + textureSample(source, source_sampler, in.uv + texel * corner).rgb;
+    }
+    return vec4<f32>(threshold(color * 0.25), 1.0);
+}
+
+// Nine tap gaussian using bilinear filtering to read two texels per tap
+fn blur(uv: vec2<f32>, direction: vec2<f32>) -> vec4<f32> {
+    let step = direction / vec2<f32>(textureDimensions(source));
+    var color = textureSample(source, source_sampler, uv).rgb * 0.2270270270;
+    var offsets = array<f32, 2>(1.3846153846, 3.2307692308);
+    var weights = array<f32, 2>(0.3162162162, 0.0702702703);
+    for (var i = 0; i < 2; i = i + 1) {
+        let offset = step * offsets[i];
+        let pair = textureSample(source, source_sampler, uv + offset).rgb
+            + textureSample(source, source_sampler, uv - offset).rgb;
+        color = color + pair * weights[i];
+    }
+    return vec4<f32>(color, 1.0);
+}
+
+@fragment
+fn fs_blur_horizontal(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.uv, vec2<f32>(1.0, 0.0));
+}
+
+@fragment
+fn fs_blur_vertical(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.uv, vec2<f32>(0.0, 1.0));
+}
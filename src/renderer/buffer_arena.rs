@@ -0,0 +1,116 @@
+use std::ops::Range;
+
+// Offsets are kept aligned for vertex and uniform reads alike
+const ALIGNMENT: u64 = 16;
+const MIN_CAPACITY: u64 = 4096;
+
+fn align(offset: u64) -> u64 {
+    offset.next_multiple_of(ALIGNMENT)
+}
+
+// Data written by one frame is packed into a staging vector and uploaded with a
+// single queue.write_buffer. The gpu buffer only ever grows, so once it fits a
+// frame's data it is reused by every following frame without allocating.
+pub struct BufferArena {
+    label: &'static str,
+    usage: wgpu::BufferUsages,
+    staging: Vec<u8>,
+    buffer: Option<wgpu::Buffer>,
+    capacity: u64,
+}
+
+impl BufferArena {
+    pub fn new(label: &'static str, usage: wgpu::BufferUsages) -> Self {
+        Self {
+            label,
+            usage: usage | wgpu::BufferUsages::COPY_DST,
+            staging: Vec::new(),
+            buffer: None,
+            capacity: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.staging.clear();
+    }
+
+    // Returns the byte range the data will occupy in the buffer
+    pub fn push(&mut self, data: &[u8]) -> Range<u64> {
+        let start = align(self.staging.len() as u64);
+        self.staging.resize(start as usize, 0);
+        self.staging.extend_from_slice(data);
+        start..self.staging.len() as u64
+    }
+
+    pub fn len(&self) -> u64 {
+        self.staging.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.staging.is_empty()
+    }
+
+    // The capacity to grow to when the pushed data no longer fits
+    fn grown_capacity(&self) -> Option<u64> {
+        let size = align(self.len());
+        (size > self.capacity).then(|| size.next_power_of_two().max(MIN_CAPACITY))
+    }
+
+    // Writes the pushed data to the gpu, returning whether a buffer was allocated
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        let grown = match self.grown_capacity() {
+            Some(capacity) => {
+                self.buffer = Some(device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(self.label),
+                    size: capacity,
+                    usage: self.usage,
+                    mapped_at_creation: false,
+                }));
+                self.capacity = capacity;
+                true
+            }
+            None => false,
+        };
+        if let Some(buffer) = &self.buffer {
+            if !self.staging.is_empty() {
+                let size = self.staging.len() as u64;
+                self.staging.resize(align(size) as usize, 0);
+                queue.write_buffer(buffer, 0, &self.staging);
+            }
+        }
+        grown
+    }
+
+    pub fn buffer(&self) -> Option<&wgpu::Buffer> {
+        self.buffer.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushes_are_aligned_and_packed() {
+        let mut arena = BufferArena::new("Test arena", wgpu::BufferUsages::VERTEX);
+        assert_eq!(arena.push(&[1; 64]), 0..64);
+        assert_eq!(arena.push(&[2; 4]), 64..68);
+        assert_eq!(arena.push(&[3; 8]), 80..88);
+        arena.clear();
+        assert!(arena.is_empty());
+        assert_eq!(arena.push(&[4; 8]), 0..8);
+    }
+
+    #[test]
+    fn capacity_only_grows_when_a_frame_does_not_fit() {
+        let mut arena = BufferArena::new("Test arena", wgpu::BufferUsages::VERTEX);
+        arena.push(&[0; 100]);
+        assert_eq!(arena.grown_capacity(), Some(MIN_CAPACITY));
+        arena.capacity = MIN_CAPACITY;
+        arena.clear();
+        arena.push(&[0; 4096]);
+        assert_eq!(arena.grown_capacity(), None);
+        arena.push(&[0; 1]);
+        assert_eq!(arena.grown_capacity(), Some(8192));
+    }
+}
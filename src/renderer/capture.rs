@@ -0,0 +1,272 @@
+use std::{num::NonZeroU32, path::PathBuf};
+
+use anyhow::Context;
+
+use super::{
+    graph::{Attachments, DEPTH, LDR},
+    passes::{
+        depth_pipeline, fullscreen_pipeline, linear_sampler, sources_bind_group,
+        sources_bind_group_layout,
+    },
+};
+
+const CAPTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+const DEPTH_CAPTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureSettings {
+    // Frames are written here as frame_0000.png and depth_0000.png onwards
+    pub directory: PathBuf,
+    // Consecutive frames to write, one for a screenshot
+    pub frames: u32,
+    // Also writes the depth attachment, shaded the way the depth overlay shows it
+    pub depth: bool,
+}
+
+// Copies the frames the renderer draws to PNG files. The last post process step is
+// drawn again into a texture that can be copied, since the surface can't be.
+pub struct FrameCapture {
+    settings: CaptureSettings,
+    written: u32,
+    fxaa: wgpu::RenderPipeline,
+    blit: wgpu::RenderPipeline,
+    sources_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    // Created again when the depth attachment starts or stops being multisampled
+    depth: Option<(u32, wgpu::RenderPipeline, wgpu::BindGroupLayout)>,
+}
+
+// A texture copied to a buffer, to be written to the path once the gpu is done
+pub struct Readback {
+    buffer: wgpu::Buffer,
+    size: (u32, u32),
+    path: PathBuf,
+}
+
+impl FrameCapture {
+    pub const NAME: &'static str = "Capture";
+
+    pub fn new(device: &wgpu::Device, settings: CaptureSettings) -> Self {
+        let sources_layout = sources_bind_group_layout(device, Self::NAME, 1);
+        let pipeline = |shader: &str| {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(Self::NAME),
+                source: wgpu::ShaderSource::Wgsl(shader.into()),
+            });
+            fullscreen_pipeline(
+                device,
+                Self::NAME,
+                &shader,
+                "fs_main",
+                &[&sources_layout],
+                CAPTURE_FORMAT,
+            )
+        };
+        Self {
+            settings,
+            written: 0,
+            fxaa: pipeline(include_str!("fxaa.wgsl")),
+            blit: pipeline(include_str!("blit.wgsl")),
+            sampler: linear_sampler(device),
+            sources_layout,
+            depth: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.written >= self.settings.frames
+    }
+
+    // Draws the frame's tonemapped image, and its depth if asked for, into textures
+    // and copies them to buffers
+    pub fn encode(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        attachments: &Attachments,
+        fxaa: bool,
+    ) -> Vec<Readback> {
+        let ldr = attachments.get(LDR);
+        let size = (ldr.texture.size().width, ldr.texture.size().height);
+        let bind_group =
+            sources_bind_group(device, &self.sources_layout, &[&ldr.view], &self.sampler);
+        let pipeline = if fxaa { &self.fxaa } else { &self.blit };
+        let color = draw_to_texture(device, encoder, size, CAPTURE_FORMAT, pipeline, &bind_group);
+        let mut readbacks = vec![Readback {
+            buffer: copy_to_buffer(device, encoder, &color, size),
+            size,
+            path: self.path("frame"),
+        }];
+        if self.settings.depth {
+            let depth = attachments.get(DEPTH);
+            let samples = depth.descriptor.samples;
+            if !matches!(&self.depth, Some((current, ..)) if *current == samples) {
+                let (pipeline, layout) =
+                    depth_pipeline(device, Self::NAME, samples, DEPTH_CAPTURE_FORMAT);
+                self.depth = Some((samples, pipeline, layout));
+            }
+            let (_, pipeline, layout) = self.depth.as_ref().unwrap();
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Depth capture bind group"),
+                layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&depth.view),
+                }],
+            });
+            let texture = draw_to_texture(
+                device,
+                encoder,
+                size,
+                DEPTH_CAPTURE_FORMAT,
+                pipeline,
+                &bind_group,
+            );
+            readbacks.push(Readback {
+                buffer: copy_to_buffer(device, encoder, &texture, size),
+                size,
+                path: self.path("depth"),
+            });
+        }
+        readbacks
+    }
+
+    // Waits for the copies to finish and writes them, counting the frame written
+    pub fn save(&mut self, device: &wgpu::Device, readbacks: Vec<Readback>) -> anyhow::Result<()> {
+        self.written += 1;
+        std::fs::create_dir_all(&self.settings.directory)
+            .with_context(|| format!("Failed to create {}", self.settings.directory.display()))?;
+        for readback in readbacks {
+            let slice = readback.buffer.slice(..);
+            let (sender, receiver) = std::sync::mpsc::channel();
+            slice.map_async(wgpu::MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+            device.poll(wgpu::Maintain::Wait);
+            receiver
+                .recv()?
+                .context("Failed to read back the captured frame")?;
+            let (width, height) = readback.size;
+            let pixels = unpad_rows(&slice.get_mapped_range(), width, height);
+            image::save_buffer(
+                &readback.path,
+                &pixels,
+                width,
+                height,
+                image::ColorType::Rgba8,
+            )
+            .with_context(|| format!("Failed to write {}", readback.path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, kind: &str) -> PathBuf {
+        self.settings
+            .directory
+            .join(format!("{}_{:04}.png", kind, self.written))
+    }
+}
+
+fn draw_to_texture(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    (width, height): (u32, u32),
+    format: wgpu::TextureFormat,
+    pipeline: &wgpu::RenderPipeline,
+    bind_group: &wgpu::BindGroup,
+) -> wgpu::Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(FrameCapture::NAME),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(FrameCapture::NAME),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: &view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: true,
+            },
+        })],
+        depth_stencil_attachment: None,
+    });
+    render_pass.set_pipeline(pipeline);
+    render_pass.set_bind_group(0, bind_group, &[]);
+    render_pass.draw(0..3, 0..1);
+    drop(render_pass);
+    texture
+}
+
+// Rows of a buffer copy are padded to a multiple of 256 bytes
+fn padded_bytes_per_row(width: u32) -> u32 {
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    (4 * width).div_ceil(align) * align
+}
+
+fn copy_to_buffer(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    texture: &wgpu::Texture,
+    (width, height): (u32, u32),
+) -> wgpu::Buffer {
+    let bytes_per_row = padded_bytes_per_row(width);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Capture buffer"),
+        size: (bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(bytes_per_row),
+                rows_per_image: NonZeroU32::new(height),
+            },
+        },
+        texture.size(),
+    );
+    buffer
+}
+
+// Drops the padding at the end of each row of rgba pixels
+fn unpad_rows(data: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let row = 4 * width as usize;
+    data.chunks(padded_bytes_per_row(width) as usize)
+        .take(height as usize)
+        .flat_map(|padded| &padded[..row])
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding_is_dropped_from_every_row() {
+        assert_eq!(padded_bytes_per_row(64), 256);
+        assert_eq!(padded_bytes_per_row(65), 512);
+        let mut data = vec![0; 2 * 256];
+        data[..12].copy_from_slice(&[1; 12]);
+        data[256..268].copy_from_slice(&[2; 12]);
+        let pixels = unpad_rows(&data, 3, 2);
+        assert_eq!(pixels.len(), 24);
+        assert_eq!(&pixels[..12], &[1; 12]);
+        assert_eq!(&pixels[12..], &[2; 12]);
+    }
+}
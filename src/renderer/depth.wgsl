@@ -1,36 +1,31 @@
-struct VertexInput {
-    @location(0) position: vec3<f32>,
-    @location(1) tex_coords: vec2<f32>,
-}
-
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
+    @location(0) uv: vec2<f32>,
 };
 
+// A single triangle covering the viewport, which the pass limits to a corner
 @vertex
-fn vs_main(
-    model: VertexInput,
-) -> VertexOutput {
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
     var out: VertexOutput;
-    out.tex_coords = model.tex_coords;
-    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.uv = uv;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
     return out;
 }
 
-
 @group(0) @binding(0)
-var dt: texture_2d<f32>;
-@group(0) @binding(1)
-var s: sampler;
+var depth: texture_depth_2d;
 
+// Matches the camera's clip planes
+const near: f32 = 0.1;
+const far: f32 = 100.0;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let near = 0.1;
-    let far = 1000.0;
-    let depth = textureSample(dt, s, in.tex_coords).x;
-    let k = depth / 10.0;
-    let r = (2.0 * near) / (far + near - depth * (far - near));
-    return vec4<f32>(vec3<f32>(r), 1.0);
-}
\ No newline at end of file
+    let size = vec2<f32>(textureDimensions(depth));
+    let texel = vec2<i32>(min(in.uv * size, size - 1.0));
+    let d = textureLoad(depth, texel, 0);
+    // Linear distance from the camera, scaled to the far plane
+    let z = near * far / (far - d * (far - near));
+    return vec4<f32>(vec3<f32>(z / far), 1.0);
+}
@@ -0,0 +1,505 @@
+use std::num::NonZeroU32;
+
+pub const ENVIRONMENT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+// Faces of the cubemap equirectangular images are projected onto
+const ENVIRONMENT_SIZE: u32 = 512;
+const IRRADIANCE_SIZE: u32 = 32;
+// Mip levels of the prefiltered map go from smooth to fully rough surfaces
+const PREFILTERED_SIZE: u32 = 128;
+const PREFILTERED_MIPS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 256;
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+// Linear rgba colors of the light arriving from every direction
+pub enum EnvironmentImage {
+    // Square +x, -x, +y, -y, +z and -z faces
+    Faces {
+        size: u32,
+        faces: Vec<Vec<[f32; 4]>>,
+    },
+    // Longitude along x and latitude along y
+    Equirectangular {
+        width: u32,
+        height: u32,
+        pixels: Vec<[f32; 4]>,
+    },
+}
+
+// The sky and the maps meshes are lit by, bound to the globals of the mesh pipelines
+pub struct Environment {
+    pub skybox: wgpu::TextureView,
+    // Diffuse light for every normal direction
+    pub irradiance: wgpu::TextureView,
+    // Specular reflections, blurrier in each mip level
+    pub prefiltered: wgpu::TextureView,
+    pub prefiltered_mips: u32,
+}
+
+impl Environment {
+    // Lights nothing, used until an environment is baked
+    pub fn black(device: &wgpu::Device) -> Self {
+        let black = |label| {
+            let texture = cube_texture(device, label, 1, 1, wgpu::TextureUsages::TEXTURE_BINDING);
+            cube_view(&texture)
+        };
+        Self {
+            skybox: black("Black skybox"),
+            irradiance: black("Black irradiance"),
+            prefiltered: black("Black prefiltered environment"),
+            prefiltered_mips: 1,
+        }
+    }
+}
+
+// Pipelines turning environment images into cubemaps and lighting maps
+pub struct EnvironmentBaker {
+    bind_group_layout: wgpu::BindGroupLayout,
+    equirectangular: wgpu::RenderPipeline,
+    downsample: wgpu::RenderPipeline,
+    irradiance: wgpu::RenderPipeline,
+    prefilter: wgpu::RenderPipeline,
+    brdf: wgpu::RenderPipeline,
+    pub sampler: wgpu::Sampler,
+    // Bound in place of the sources a bake doesn't read
+    unused_panorama: wgpu::TextureView,
+    unused_environment: wgpu::TextureView,
+}
+
+impl EnvironmentBaker {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let texture_entry = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Environment bake bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1, wgpu::TextureViewDimension::D2),
+                texture_entry(2, wgpu::TextureViewDimension::Cube),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Environment bake layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Environment bake shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("environment.wgsl").into()),
+        });
+        let pipeline = |entry_point, format| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(wgpu::ColorTargetState::from(format))],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let panorama = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Unused equirectangular environment"),
+            size: wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: ENVIRONMENT_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        Self {
+            bind_group_layout,
+            equirectangular: pipeline("fs_equirectangular", ENVIRONMENT_FORMAT),
+            downsample: pipeline("fs_downsample", ENVIRONMENT_FORMAT),
+            irradiance: pipeline("fs_irradiance", ENVIRONMENT_FORMAT),
+            prefilter: pipeline("fs_prefilter", ENVIRONMENT_FORMAT),
+            brdf: pipeline("fs_brdf", BRDF_LUT_FORMAT),
+            sampler,
+            unused_panorama: panorama.create_view(&wgpu::TextureViewDescriptor::default()),
+            unused_environment: Environment::black(device).skybox,
+        }
+    }
+
+    // Scale and bias of the specular color by view angle and roughness, the same
+    // for every environment
+    pub fn bake_brdf_lut(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> wgpu::TextureView {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("BRDF lookup table"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: BRDF_LUT_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = self.bind_group(device, [0.0; 4], &self.unused_environment);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("BRDF lookup table encoder"),
+        });
+        draw(&mut encoder, &self.brdf, &bind_group, &view, 0);
+        queue.submit(std::iter::once(encoder.finish()));
+        view
+    }
+
+    // Projects the image onto a cubemap if needed, then convolves it into the maps
+    // used for lighting
+    pub fn bake(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &EnvironmentImage,
+    ) -> Environment {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment bake encoder"),
+        });
+        let size = match image {
+            EnvironmentImage::Faces { size, .. } => *size,
+            EnvironmentImage::Equirectangular { .. } => ENVIRONMENT_SIZE,
+        };
+        let mips = u32::BITS - size.max(1).leading_zeros();
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_DST;
+        let skybox = cube_texture(device, "Skybox", size, mips, usage);
+        let params = [mips as f32, size as f32, PREFILTERED_MIPS as f32, 0.0];
+        match image {
+            EnvironmentImage::Faces { size, faces } => {
+                for (face, pixels) in faces.iter().enumerate() {
+                    write_texture(queue, &skybox, (*size, *size), face as u32, pixels);
+                }
+            }
+            EnvironmentImage::Equirectangular {
+                width,
+                height,
+                pixels,
+            } => {
+                let panorama = device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("Equirectangular environment"),
+                    size: wgpu::Extent3d {
+                        width: *width,
+                        height: *height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: ENVIRONMENT_FORMAT,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                    view_formats: &[],
+                });
+                write_texture(queue, &panorama, (*width, *height), 0, pixels);
+                let panorama = panorama.create_view(&wgpu::TextureViewDescriptor::default());
+                let bind_group =
+                    self.bind_group_with(device, params, &panorama, &self.unused_environment);
+                for face in 0..6 {
+                    let target = face_view(&skybox, face, 0);
+                    draw(
+                        &mut encoder,
+                        &self.equirectangular,
+                        &bind_group,
+                        &target,
+                        face,
+                    );
+                }
+            }
+        }
+        // Each level is filtered from the one above it
+        for mip in 1..mips {
+            let source = skybox.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::Cube),
+                base_mip_level: mip - 1,
+                mip_level_count: NonZeroU32::new(1),
+                ..Default::default()
+            });
+            let bind_group = self.bind_group(device, params, &source);
+            for face in 0..6 {
+                let target = face_view(&skybox, face, mip);
+                draw(&mut encoder, &self.downsample, &bind_group, &target, face);
+            }
+        }
+
+        let skybox = cube_view(&skybox);
+        let bind_group = self.bind_group(device, params, &skybox);
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING;
+        let irradiance = cube_texture(device, "Irradiance", IRRADIANCE_SIZE, 1, usage);
+        for face in 0..6 {
+            let target = face_view(&irradiance, face, 0);
+            draw(&mut encoder, &self.irradiance, &bind_group, &target, face);
+        }
+        let prefiltered = cube_texture(
+            device,
+            "Prefiltered environment",
+            PREFILTERED_SIZE,
+            PREFILTERED_MIPS,
+            usage,
+        );
+        for mip in 0..PREFILTERED_MIPS {
+            for face in 0..6 {
+                let target = face_view(&prefiltered, face, mip);
+                let instance = face + 6 * mip;
+                draw(
+                    &mut encoder,
+                    &self.prefilter,
+                    &bind_group,
+                    &target,
+                    instance,
+                );
+            }
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+        Environment {
+            skybox,
+            irradiance: cube_view(&irradiance),
+            prefiltered: cube_view(&prefiltered),
+            prefiltered_mips: PREFILTERED_MIPS,
+        }
+    }
+
+    fn bind_group(
+        &self,
+        device: &wgpu::Device,
+        params: [f32; 4],
+        environment: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        self.bind_group_with(device, params, &self.unused_panorama, environment)
+    }
+
+    fn bind_group_with(
+        &self,
+        device: &wgpu::Device,
+        params: [f32; 4],
+        panorama: &wgpu::TextureView,
+        environment: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        let buffer = wgpu::util::DeviceExt::create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Environment bake buffer"),
+                contents: bytemuck::cast_slice(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            },
+        );
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment bake bind group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(panorama),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(environment),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        })
+    }
+}
+
+// Draws one triangle covering the target, with the instance telling the shader which
+// face and mip level it is
+fn draw(
+    encoder: &mut wgpu::CommandEncoder,
+    pipeline: &wgpu::RenderPipeline,
+    bind_group: &wgpu::BindGroup,
+    target: &wgpu::TextureView,
+    instance: u32,
+) {
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Environment bake pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: true,
+            },
+        })],
+        depth_stencil_attachment: None,
+    });
+    render_pass.set_pipeline(pipeline);
+    render_pass.set_bind_group(0, bind_group, &[]);
+    render_pass.draw(0..3, instance..instance + 1);
+}
+
+fn cube_texture(
+    device: &wgpu::Device,
+    label: &str,
+    size: u32,
+    mips: u32,
+    usage: wgpu::TextureUsages,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count: mips,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: ENVIRONMENT_FORMAT,
+        usage,
+        view_formats: &[],
+    })
+}
+
+fn cube_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        ..Default::default()
+    })
+}
+
+// One mip level of one face, to render into
+fn face_view(texture: &wgpu::Texture, face: u32, mip: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_mip_level: mip,
+        mip_level_count: NonZeroU32::new(1),
+        base_array_layer: face,
+        array_layer_count: NonZeroU32::new(1),
+        ..Default::default()
+    })
+}
+
+// Uploads linear colors to one layer of a half float texture
+fn write_texture(
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    (width, height): (u32, u32),
+    layer: u32,
+    pixels: &[[f32; 4]],
+) {
+    let halves = pixels
+        .iter()
+        .flatten()
+        .map(|&value| f16_bits(value))
+        .collect::<Vec<_>>();
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: 0,
+                y: 0,
+                z: layer,
+            },
+            aspect: wgpu::TextureAspect::All,
+        },
+        bytemuck::cast_slice(&halves),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: NonZeroU32::new(8 * width),
+            rows_per_image: NonZeroU32::new(height),
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+}
+
+// The bits of the nearest half precision float, saturating to infinity
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    if value.is_nan() {
+        return sign | 0x7e00;
+    }
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent >= 31 {
+        sign | 0x7c00
+    } else if exponent <= 0 {
+        // Subnormal, with the implicit leading bit shifted into the mantissa
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        sign | ((mantissa + (1 << (shift - 1))) >> shift) as u16
+    } else {
+        // Rounding may carry into the exponent, up to infinity
+        sign | (((exponent as u32) << 10) + ((mantissa + 0x1000) >> 13)) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floats_round_to_half_precision() {
+        assert_eq!(f16_bits(1.0), 0x3c00);
+        assert_eq!(f16_bits(0.5), 0x3800);
+        assert_eq!(f16_bits(-2.0), 0xc000);
+        assert_eq!(f16_bits(65504.0), 0x7bff);
+        assert_eq!(f16_bits(1.0e6), 0x7c00);
+        assert_eq!(f16_bits(2.0f32.powi(-24)), 0x0001);
+        assert_eq!(f16_bits(2.0f32.powi(-14)), 0x0400);
+        assert_eq!(f16_bits(1.0e-10), 0);
+    }
+}
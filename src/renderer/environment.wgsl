@@ -0,0 +1,192 @@
+// Bakes the environment cubemap and the image based lighting maps from it. Every
+// draw covers one layer of a cubemap, picked by the instance index.
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) @interpolate(flat) face: u32,
+};
+
+struct Bake {
+    // x is the number of mip levels of the environment, y the size of its faces
+    // and z the number of mip levels of the prefiltered map
+    params: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> bake: Bake;
+@group(0) @binding(1)
+var equirectangular: texture_2d<f32>;
+@group(0) @binding(2)
+var environment: texture_cube<f32>;
+@group(0) @binding(3)
+var environment_sampler: sampler;
+
+const PI: f32 = 3.14159265359;
+const PREFILTER_SAMPLES: u32 = 64u;
+const BRDF_SAMPLES: u32 = 128u;
+
+// Instances are numbered face + 6 * mip level
+@vertex
+fn vs_main(
+    @builtin(vertex_index) index: u32,
+    @builtin(instance_index) instance: u32,
+) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.uv = uv;
+    out.face = instance;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+// Direction through a texel of a cubemap face, faces ordered +x, -x, +y, -y, +z, -z
+fn cube_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+    let s = uv.x * 2.0 - 1.0;
+    let t = uv.y * 2.0 - 1.0;
+    var direction: vec3<f32>;
+    switch (face % 6u) {
+        case 0u: { direction = vec3<f32>(1.0, -t, -s); }
+        case 1u: { direction = vec3<f32>(-1.0, -t, s); }
+        case 2u: { direction = vec3<f32>(s, 1.0, t); }
+        case 3u: { direction = vec3<f32>(s, -1.0, -t); }
+        case 4u: { direction = vec3<f32>(s, -t, 1.0); }
+        default: { direction = vec3<f32>(-s, -t, -1.0); }
+    }
+    return normalize(direction);
+}
+
+// A frame around the normal, for turning tangent space samples into world ones
+fn tangent_to_world(normal: vec3<f32>, sample: vec3<f32>) -> vec3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(normal.y) > 0.999) {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return tangent * sample.x + bitangent * sample.y + normal * sample.z;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// Half vector around the z axis, distributed by the GGX normal distribution
+fn importance_sample_ggx(xi: vec2<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a2 = roughness * roughness * roughness * roughness;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = roughness * roughness / 2.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+@fragment
+fn fs_equirectangular(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = cube_direction(in.face, in.uv);
+    let uv = vec2<f32>(
+        atan2(direction.z, direction.x) / (2.0 * PI) + 0.5,
+        acos(clamp(direction.y, -1.0, 1.0)) / PI,
+    );
+    let color = textureSampleLevel(equirectangular, environment_sampler, uv, 0.0);
+    return vec4<f32>(color.rgb, 1.0);
+}
+
+// Reads the previous mip level, bound as the only level of the environment
+@fragment
+fn fs_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = cube_direction(in.face, in.uv);
+    let color = textureSampleLevel(environment, environment_sampler, direction, 0.0);
+    return vec4<f32>(color.rgb, 1.0);
+}
+
+// Cosine weighted average of the light arriving from the hemisphere around the normal
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = cube_direction(in.face, in.uv);
+    // Coarse levels keep the sparse samples from aliasing
+    let level = max(bake.params.x - 6.0, 0.0);
+    var irradiance = vec3<f32>(0.0);
+    var count = 0.0;
+    let step = 0.05;
+    for (var phi = 0.0; phi < 2.0 * PI; phi = phi + step) {
+        for (var theta = 0.0; theta < 0.5 * PI; theta = theta + step) {
+            let sample = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let direction = tangent_to_world(normal, sample);
+            let radiance = textureSampleLevel(environment, environment_sampler, direction, level);
+            irradiance = irradiance + radiance.rgb * cos(theta) * sin(theta);
+            count = count + 1.0;
+        }
+    }
+    return vec4<f32>(PI * irradiance / count, 1.0);
+}
+
+// The environment reflected by surfaces as rough as the mip level, assuming the view
+// direction is the normal. Samples read coarser levels where they are sparse.
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = cube_direction(in.face, in.uv);
+    let mip = f32(in.face / 6u);
+    let roughness = mip / max(bake.params.z - 1.0, 1.0);
+    let texel_solid_angle = 4.0 * PI / (6.0 * bake.params.y * bake.params.y);
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < PREFILTER_SAMPLES; i = i + 1u) {
+        let half_vector = tangent_to_world(
+            normal,
+            importance_sample_ggx(hammersley(i, PREFILTER_SAMPLES), roughness),
+        );
+        let light = normalize(2.0 * dot(normal, half_vector) * half_vector - normal);
+        let n_dot_l = dot(normal, light);
+        if (n_dot_l > 0.0) {
+            let n_dot_h = max(dot(normal, half_vector), 0.0);
+            let pdf = distribution_ggx(n_dot_h, roughness) / 4.0 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(PREFILTER_SAMPLES) * pdf);
+            var level = 0.0;
+            if (roughness > 0.0) {
+                level = 0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0;
+            }
+            let radiance = textureSampleLevel(environment, environment_sampler, light, level);
+            color = color + radiance.rgb * n_dot_l;
+            weight = weight + n_dot_l;
+        }
+    }
+    return vec4<f32>(color / max(weight, 0.0001), 1.0);
+}
+
+// Scale and bias applied to the specular color by the split sum approximation, for
+// the cosine of the view angle along u and the roughness along v
+@fragment
+fn fs_brdf(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n_dot_v = max(in.uv.x, 0.001);
+    let roughness = in.uv.y;
+    let view = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < BRDF_SAMPLES; i = i + 1u) {
+        let half_vector = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), roughness);
+        let light = normalize(2.0 * dot(view, half_vector) * half_vector - view);
+        let n_dot_l = max(light.z, 0.0);
+        let n_dot_h = max(half_vector.z, 0.0);
+        let v_dot_h = max(dot(view, half_vector), 0.0);
+        if (n_dot_l > 0.0) {
+            let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+            let visibility = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fresnel = pow(1.0 - v_dot_h, 5.0);
+            scale = scale + (1.0 - fresnel) * visibility;
+            bias = bias + fresnel * visibility;
+        }
+    }
+    return vec4<f32>(scale / f32(BRDF_SAMPLES), bias / f32(BRDF_SAMPLES), 0.0, 1.0);
+}
@@ -0,0 +1,64 @@
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.uv = uv;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+
+const REDUCE_MIN: f32 = 0.0078125;
+const REDUCE_MUL: f32 = 0.125;
+const SPAN_MAX: f32 = 8.0;
+
+fn sample(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(source, source_sampler, uv, 0.0).rgb;
+}
+
+// Perceived brightness, taking the square root to approximate gamma since the
+// source holds linear colors
+fn luma(color: vec3<f32>) -> f32 {
+    return sqrt(dot(color, vec3<f32>(0.299, 0.587, 0.114)));
+}
+
+// Blurs along the edge through each pixel, found from the luma of its corners
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    let center = textureSampleLevel(source, source_sampler, in.uv, 0.0);
+    let luma_m = luma(center.rgb);
+    let luma_nw = luma(sample(in.uv + vec2<f32>(-1.0, -1.0) * texel));
+    let luma_ne = luma(sample(in.uv + vec2<f32>(1.0, -1.0) * texel));
+    let luma_sw = luma(sample(in.uv + vec2<f32>(-1.0, 1.0) * texel));
+    let luma_se = luma(sample(in.uv + vec2<f32>(1.0, 1.0) * texel));
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    var direction = vec2<f32>(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    let scale = 1.0 / (min(abs(direction.x), abs(direction.y)) + reduce);
+    direction = clamp(direction * scale, vec2<f32>(-SPAN_MAX), vec2<f32>(SPAN_MAX)) * texel;
+
+    let near = 0.5 * (sample(in.uv - direction / 6.0) + sample(in.uv + direction / 6.0));
+    let outer = sample(in.uv - direction * 0.5) + sample(in.uv + direction * 0.5);
+    let far = near * 0.5 + 0.25 * outer;
+    let luma_far = luma(far);
+    if (luma_far < luma_min || luma_far > luma_max) {
+        return vec4<f32>(near, center.a);
+    }
+    return vec4<f32>(far, center.a);
+}
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+
+use crate::mesh::{MeshBatch, TransparentDraw};
+
+use super::render::Renderer;
+
+pub type AttachmentName = &'static str;
+
+// The swap chain image of the frame, provided by the renderer rather than the graph
+pub const SURFACE: AttachmentName = "surface";
+pub const COLOR: AttachmentName = "color";
+pub const DEPTH: AttachmentName = "depth";
+// Multisampled target meshes are drawn to before resolving into the color
+// attachment, only present while multisampling
+pub const MSAA_COLOR: AttachmentName = "msaa color";
+// One layer per shadow cascade
+pub const SHADOW_MAP: AttachmentName = "shadow map";
+// The bright parts of the color attachment blurred, and the scratch target of the blur
+pub const BLOOM: AttachmentName = "bloom";
+pub const BLOOM_BLUR: AttachmentName = "bloom blur";
+// The tonemapped color attachment, ready for the surface
+pub const LDR: AttachmentName = "ldr";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentSize {
+    // Follows the surface when the window is resized
+    Surface,
+    // Follows the surface at a fraction of its size
+    Scaled(u32),
+    Fixed(u32, u32),
+}
+impl AttachmentSize {
+    pub fn extent(&self, surface_size: (u32, u32)) -> (u32, u32) {
+        let (width, height) = match *self {
+            AttachmentSize::Surface => surface_size,
+            AttachmentSize::Scaled(divisor) => (surface_size.0 / divisor, surface_size.1 / divisor),
+            AttachmentSize::Fixed(width, height) => (width, height),
+        };
+        (width.max(1), height.max(1))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttachmentDescriptor {
+    pub format: wgpu::TextureFormat,
+    pub size: AttachmentSize,
+    // Array layers, viewed as an array texture when more than one
+    pub layers: u32,
+    pub samples: u32,
+    pub usage: wgpu::TextureUsages,
+}
+
+pub struct Attachment {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub descriptor: AttachmentDescriptor,
+}
+
+pub struct Attachments {
+    attachments: HashMap<AttachmentName, Attachment>,
+}
+impl Attachments {
+    pub fn contains(&self, name: AttachmentName) -> bool {
+        self.attachments.contains_key(name)
+    }
+    pub fn get(&self, name: AttachmentName) -> &Attachment {
+        self.attachments
+            .get(name)
+            .unwrap_or_else(|| panic!("No attachment named {}", name))
+    }
+}
+
+// What the passes draw in one frame
+pub struct FrameData<'a> {
+    pub batches: &'a [MeshBatch<'a>],
+    // Instances of blended primitives across the batches, furthest first
+    pub transparent: &'a [TransparentDraw],
+    // Holds the instances of every batch, missing when nothing is drawn
+    pub instance_buffer: Option<&'a wgpu::Buffer>,
+    // Lights and shadows, bound to group 1 of the mesh pipelines
+    pub lighting: &'a wgpu::BindGroup,
+    // Whether casters are drawn into the shadow map
+    pub shadows: bool,
+}
+
+pub struct PassContext<'a> {
+    pub renderer: &'a Renderer,
+    pub attachments: &'a Attachments,
+    pub surface: &'a wgpu::TextureView,
+    pub frame: &'a FrameData<'a>,
+}
+impl<'a> PassContext<'a> {
+    pub fn view(&self, name: AttachmentName) -> &'a wgpu::TextureView {
+        if name == SURFACE {
+            self.surface
+        } else {
+            &self.attachments.get(name).view
+        }
+    }
+}
+
+// A step of the frame. Passes run after their explicit dependencies, after every
+// pass writing an attachment they read, and after passes added before them that
+// write the same attachments.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn reads(&self) -> Vec<AttachmentName> {
+        Vec::new()
+    }
+    fn writes(&self) -> Vec<AttachmentName>;
+    // Names of passes that must run first
+    fn dependencies(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+    // Called whenever the attachments are created again, to rebuild bind groups over them
+    fn attachments_changed(&mut self, _device: &wgpu::Device, _attachments: &Attachments) {}
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder);
+}
+
+struct GraphPass {
+    pass: Box<dyn Pass>,
+    enabled: bool,
+}
+
+pub struct RenderGraph {
+    descriptors: Vec<(AttachmentName, AttachmentDescriptor)>,
+    attachments: Attachments,
+    passes: Vec<GraphPass>,
+    // Indices of the enabled passes in execution order
+    order: Vec<usize>,
+    surface_size: (u32, u32),
+}
+
+impl RenderGraph {
+    pub fn new(surface_size: (u32, u32)) -> Self {
+        Self {
+            descriptors: Vec::new(),
+            attachments: Attachments {
+                attachments: HashMap::new(),
+            },
+            passes: Vec::new(),
+            order: Vec::new(),
+            surface_size,
+        }
+    }
+
+    pub fn add_attachment(
+        &mut self,
+        device: &wgpu::Device,
+        name: AttachmentName,
+        descriptor: AttachmentDescriptor,
+    ) {
+        self.descriptors.retain(|(existing, _)| *existing != name);
+        self.descriptors.push((name, descriptor));
+        let attachment = Self::create_attachment(device, name, descriptor, self.surface_size);
+        self.attachments.attachments.insert(name, attachment);
+        self.attachments_changed(device);
+    }
+
+    // Passes must no longer use the attachment
+    pub fn remove_attachment(&mut self, device: &wgpu::Device, name: AttachmentName) {
+        self.descriptors.retain(|(existing, _)| *existing != name);
+        if self.attachments.attachments.remove(name).is_some() {
+            self.attachments_changed(device);
+        }
+    }
+
+    pub fn add_pass(&mut self, device: &wgpu::Device, mut pass: impl Pass + 'static) {
+        pass.attachments_changed(device, &self.attachments);
+        self.passes.push(GraphPass {
+            pass: Box::new(pass),
+            enabled: true,
+        });
+        self.compile();
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        for graph_pass in self
+            .passes
+            .iter_mut()
+            .filter(|graph_pass| graph_pass.pass.name() == name)
+        {
+            graph_pass.enabled = enabled;
+        }
+        self.compile();
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.passes
+            .iter()
+            .any(|graph_pass| graph_pass.enabled && graph_pass.pass.name() == name)
+    }
+
+    // Creates the attachments following the surface again
+    pub fn resize(&mut self, device: &wgpu::Device, surface_size: (u32, u32)) {
+        self.surface_size = surface_size;
+        for &(name, descriptor) in &self.descriptors {
+            if !matches!(descriptor.size, AttachmentSize::Fixed(..)) {
+                let attachment = Self::create_attachment(device, name, descriptor, surface_size);
+                self.attachments.attachments.insert(name, attachment);
+            }
+        }
+        self.attachments_changed(device);
+    }
+
+    pub fn attachments(&self) -> &Attachments {
+        &self.attachments
+    }
+
+    // Names of the enabled passes in the order they run
+    pub fn order(&self) -> Vec<&'static str> {
+        self.order
+            .iter()
+            .map(|&index| self.passes[index].pass.name())
+            .collect()
+    }
+
+    pub fn execute(
+        &self,
+        renderer: &Renderer,
+        surface: &wgpu::TextureView,
+        frame: &FrameData,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let context = PassContext {
+            renderer,
+            attachments: &self.attachments,
+            surface,
+            frame,
+        };
+        for &index in &self.order {
+            self.passes[index].pass.execute(&context, encoder);
+        }
+    }
+
+    fn attachments_changed(&mut self, device: &wgpu::Device) {
+        for graph_pass in self.passes.iter_mut() {
+            graph_pass
+                .pass
+                .attachments_changed(device, &self.attachments);
+        }
+    }
+
+    fn create_attachment(
+        device: &wgpu::Device,
+        name: AttachmentName,
+        descriptor: AttachmentDescriptor,
+        surface_size: (u32, u32),
+    ) -> Attachment {
+        let (width, height) = descriptor.size.extent(surface_size);
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(name),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: descriptor.layers.max(1),
+            },
+            mip_level_count: 1,
+            sample_count: descriptor.samples,
+            dimension: wgpu::TextureDimension::D2,
+            format: descriptor.format,
+            usage: descriptor.usage,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(if descriptor.layers > 1 {
+                wgpu::TextureViewDimension::D2Array
+            } else {
+                wgpu::TextureViewDimension::D2
+            }),
+            ..Default::default()
+        });
+        Attachment {
+            texture,
+            view,
+            descriptor,
+        }
+    }
+
+    fn compile(&mut self) {
+        let enabled = self
+            .passes
+            .iter()
+            .enumerate()
+            .filter(|(_, graph_pass)| graph_pass.enabled)
+            .map(|(index, graph_pass)| {
+                let pass = &graph_pass.pass;
+                (
+                    index,
+                    pass.name(),
+                    pass.reads(),
+                    pass.writes(),
+                    pass.dependencies(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let nodes = enabled
+            .iter()
+            .map(|(_, name, reads, writes, dependencies)| ScheduleNode {
+                name,
+                reads,
+                writes,
+                dependencies,
+            })
+            .collect::<Vec<_>>();
+        self.order = schedule(&nodes)
+            .into_iter()
+            .map(|node| enabled[node].0)
+            .collect();
+    }
+}
+
+struct ScheduleNode<'a> {
+    name: &'a str,
+    reads: &'a [AttachmentName],
+    writes: &'a [AttachmentName],
+    dependencies: &'a [&'static str],
+}
+
+// Orders the nodes so that each runs after the ones it depends on, keeping the
+// order they were added in otherwise. Dependencies on missing passes are ignored.
+fn schedule(nodes: &[ScheduleNode]) -> Vec<usize> {
+    let depends_on =
+        |node: &ScheduleNode, index: usize, other: &ScheduleNode, other_index: usize| {
+            let writes = |name: &AttachmentName| other.writes.contains(name);
+            // Passes writing the same attachment keep the order they were added in,
+            // unless an explicit dependency asks otherwise
+            let added_before = other_index < index && !other.dependencies.contains(&node.name);
+            node.dependencies.contains(&other.name)
+                || node.reads.iter().any(writes)
+                || (added_before && node.writes.iter().any(writes))
+        };
+    let mut remaining = (0..nodes.len()).collect::<Vec<_>>();
+    let mut order = Vec::with_capacity(nodes.len());
+    while !remaining.is_empty() {
+        let ready = remaining.iter().position(|&index| {
+            remaining.iter().all(|&other| {
+                other == index || !depends_on(&nodes[index], index, &nodes[other], other)
+            })
+        });
+        match ready {
+            Some(position) => order.push(remaining.remove(position)),
+            None => panic!(
+                "Render graph has a cycle between passes {:?}",
+                remaining
+                    .iter()
+                    .map(|&index| nodes[index].name)
+                    .collect::<Vec<_>>()
+            ),
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node<'a>(
+        name: &'a str,
+        reads: &'a [AttachmentName],
+        writes: &'a [AttachmentName],
+        dependencies: &'a [&'static str],
+    ) -> ScheduleNode<'a> {
+        ScheduleNode {
+            name,
+            reads,
+            writes,
+            dependencies,
+        }
+    }
+
+    #[test]
+    fn passes_run_after_the_passes_they_depend_on() {
+        let nodes = [
+            node("Overlay", &[DEPTH], &[SURFACE], &["Post"]),
+            node("Post", &[COLOR], &[SURFACE], &[]),
+            node("Opaque", &[], &[COLOR, DEPTH], &["Shadow"]),
+            node("Shadow", &[], &["shadow map"], &[]),
+            node("Transparent", &[], &[COLOR, DEPTH], &[]),
+        ];
+        let order = schedule(&nodes)
+            .into_iter()
+            .map(|index| nodes[index].name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec!["Shadow", "Opaque", "Transparent", "Post", "Overlay"]
+        );
+    }
+
+    #[test]
+    fn scaled_attachments_follow_the_surface() {
+        assert_eq!(AttachmentSize::Surface.extent((1280, 720)), (1280, 720));
+        assert_eq!(AttachmentSize::Scaled(4).extent((1280, 720)), (320, 180));
+        assert_eq!(AttachmentSize::Scaled(4).extent((2, 2)), (1, 1));
+        assert_eq!(AttachmentSize::Fixed(64, 32).extent((1280, 720)), (64, 32));
+    }
+
+    #[test]
+    #[should_panic(expected = "cycle")]
+    fn cycles_are_rejected() {
+        let nodes = [
+            node("A", &["b"], &["a"], &[]),
+            node("B", &["a"], &["b"], &[]),
+        ];
+        schedule(&nodes);
+    }
+}
@@ -0,0 +1,300 @@
+use cgmath::{InnerSpace, Matrix4, Vector3, Vector4};
+
+use crate::components::light::Light;
+
+use super::ViewFrustum;
+
+// Clusters across the screen's width and height and along the view depth
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightSettings {
+    pub ambient_color: Vector3<f32>,
+    pub ambient_strength: f32,
+    // Scales the light of the environment, which replaces the ambient light
+    pub environment_intensity: f32,
+    // Lights with a range are only looked up in the clusters they reach once
+    // there are more of them than this
+    pub cluster_threshold: usize,
+}
+impl Default for LightSettings {
+    fn default() -> Self {
+        Self {
+            ambient_color: Vector3::new(1.0, 1.0, 1.0),
+            ambient_strength: 0.2,
+            environment_intensity: 1.0,
+            cluster_threshold: 32,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct GpuLight {
+    // w is 0 for directional lights
+    pub position: [f32; 4],
+    // w is the range of the light, 0 when unlimited
+    pub direction: [f32; 4],
+    // a is the intensity of the light
+    pub color: [f32; 4],
+    // Cosines of the inner and outer spot angles, z is 1 for spot lights
+    pub cone: [f32; 4],
+}
+impl GpuLight {
+    pub fn new(light: &Light, transform: Matrix4<f32>) -> Self {
+        let position = transform.w.truncate();
+        let direction = (transform * -Vector4::unit_z()).truncate().normalize();
+        let (directional, cone) = match light {
+            Light::Directional(_) => (true, [0.0; 4]),
+            Light::Point(_) => (false, [0.0; 4]),
+            Light::Spot(spot) => (
+                false,
+                [
+                    spot.inner_cone_angle.cos(),
+                    spot.outer_cone_angle.cos(),
+                    1.0,
+                    0.0,
+                ],
+            ),
+        };
+        let color = light.color();
+        Self {
+            position: position.extend(if directional { 0.0 } else { 1.0 }).into(),
+            direction: direction.extend(light.range().unwrap_or(0.0)).into(),
+            color: color.extend(light.intensity()).into(),
+            cone,
+        }
+    }
+
+    // Used when the scene has no lights: white sunlight falling from (30, 100, 0)
+    pub fn sun() -> Self {
+        let direction = -Vector3::new(30.0f32, 100.0, 0.0).normalize();
+        Self {
+            position: [0.0; 4],
+            direction: direction.extend(0.0).into(),
+            color: [1.0; 4],
+            cone: [0.0; 4],
+        }
+    }
+
+    fn is_directional(&self) -> bool {
+        self.position[3] == 0.0
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct LightingUniform {
+    // x lights shade every fragment and come first, y is the number of lights
+    // and z is 1 when the others are looked up per cluster
+    pub counts: [u32; 4],
+    pub cluster_grid: [u32; 4],
+    // Converts pixels to clusters on x and y, z converts the log of the depth over
+    // the near distance in w to slices
+    pub cluster_scale: [f32; 4],
+    pub camera_forward: [f32; 4],
+}
+
+// The lights of one frame, laid out the way the shaders read them
+pub struct FrameLights {
+    pub uniform: LightingUniform,
+    pub lights: Vec<GpuLight>,
+    // An offset and count into this same list for every cluster, followed by the
+    // light indices they point to
+    pub clusters: Vec<u32>,
+    // The first directional light casts shadows, and is always the first light
+    pub shadow_direction: Option<Vector3<f32>>,
+}
+
+impl FrameLights {
+    pub fn new(
+        lights: &[(Light, Matrix4<f32>)],
+        frustum: &ViewFrustum,
+        surface_size: (u32, u32),
+        settings: &LightSettings,
+    ) -> Self {
+        let mut gpu_lights = lights
+            .iter()
+            .map(|(light, transform)| GpuLight::new(light, *transform))
+            .collect::<Vec<_>>();
+        if gpu_lights.is_empty() {
+            gpu_lights.push(GpuLight::sun());
+        }
+        // Lights reaching every fragment go first, directional ones leading
+        gpu_lights.sort_by_key(|light| {
+            if light.is_directional() {
+                0
+            } else if light.direction[3] == 0.0 {
+                1
+            } else {
+                2
+            }
+        });
+        let global_count = gpu_lights
+            .iter()
+            .take_while(|light| light.direction[3] == 0.0)
+            .count();
+        let shadow_direction = gpu_lights
+            .first()
+            .filter(|light| light.is_directional())
+            .map(|light| Vector4::from(light.direction).truncate());
+
+        let clustered = gpu_lights.len() - global_count > settings.cluster_threshold;
+        let [x, y, z] = CLUSTER_GRID;
+        let (width, height) = (surface_size.0.max(1) as f32, surface_size.1.max(1) as f32);
+        let clusters = if clustered {
+            assign_clusters(&gpu_lights, global_count, frustum)
+        } else {
+            vec![0; 2]
+        };
+        Self {
+            uniform: LightingUniform {
+                counts: [global_count as u32, gpu_lights.len() as u32, clustered as u32, 0],
+                cluster_grid: [x, y, z, 0],
+                cluster_scale: [
+                    x as f32 / width,
+                    y as f32 / height,
+                    z as f32 / (frustum.far / frustum.near).ln(),
+                    frustum.near,
+                ],
+                camera_forward: frustum.forward.extend(0.0).into(),
+            },
+            lights: gpu_lights,
+            clusters,
+            shadow_direction,
+        }
+    }
+}
+
+// Lists the lights from first_local on reaching each cluster. Clusters are tiles
+// of the screen cut into slices growing exponentially with the depth, and lights
+// are added to every cluster overlapped by the screen bounds of their sphere.
+fn assign_clusters(lights: &[GpuLight], first_local: usize, frustum: &ViewFrustum) -> Vec<u32> {
+    let [grid_x, grid_y, grid_z] = CLUSTER_GRID.map(|count| count as usize);
+    let cluster_count = grid_x * grid_y * grid_z;
+    let depth_scale = grid_z as f32 / (frustum.far / frustum.near).ln();
+    let slice = |depth: f32| {
+        let slice = ((depth.max(frustum.near) / frustum.near).ln() * depth_scale).floor();
+        (slice.max(0.0) as usize).min(grid_z - 1)
+    };
+    let tile = |ndc: f32, count: usize| {
+        let tile = ((ndc * 0.5 + 0.5) * count as f32).floor();
+        (tile.max(0.0) as usize).min(count - 1)
+    };
+
+    let mut lists = vec![Vec::new(); cluster_count];
+    for (index, light) in lights.iter().enumerate().skip(first_local) {
+        let center = Vector4::from(light.position).truncate();
+        let radius = light.direction[3];
+        let depth = frustum.depth(center);
+        if depth + radius < frustum.near || depth - radius > frustum.far {
+            continue;
+        }
+        // Corners of the sphere's bounding box, whose projection bounds it on screen
+        let (x_range, y_range) = if depth - radius * 3f32.sqrt() <= frustum.near {
+            // The box reaches behind the near plane, where projecting it fails
+            ((0, grid_x - 1), (0, grid_y - 1))
+        } else {
+            let mut min = cgmath::vec2(f32::MAX, f32::MAX);
+            let mut max = cgmath::vec2(f32::MIN, f32::MIN);
+            for corner in 0..8 {
+                let offset = Vector3::new(
+                    if corner & 1 == 0 { -radius } else { radius },
+                    if corner & 2 == 0 { -radius } else { radius },
+                    if corner & 4 == 0 { -radius } else { radius },
+                );
+                let clip = frustum.view_proj * (center + offset).extend(1.0);
+                let ndc = clip.truncate().truncate() / clip.w;
+                min = cgmath::vec2(min.x.min(ndc.x), min.y.min(ndc.y));
+                max = cgmath::vec2(max.x.max(ndc.x), max.y.max(ndc.y));
+            }
+            if min.x > 1.0 || min.y > 1.0 || max.x < -1.0 || max.y < -1.0 {
+                continue;
+            }
+            // Tiles count rows from the top of the screen
+            (
+                (tile(min.x, grid_x), tile(max.x, grid_x)),
+                (tile(-max.y, grid_y), tile(-min.y, grid_y)),
+            )
+        };
+        for z in slice(depth - radius)..=slice(depth + radius) {
+            for y in y_range.0..=y_range.1 {
+                for x in x_range.0..=x_range.1 {
+                    lists[(z * grid_y + y) * grid_x + x].push(index as u32);
+                }
+            }
+        }
+    }
+
+    let mut clusters = Vec::with_capacity(cluster_count * 2);
+    let mut offset = (cluster_count * 2) as u32;
+    for list in &lists {
+        clusters.extend_from_slice(&[offset, list.len() as u32]);
+        offset += list.len() as u32;
+    }
+    clusters.extend(lists.into_iter().flatten());
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::light::{DirectionalLight, PointLight},
+        systems::camera::Camera,
+    };
+
+    fn point_light(position: Vector3<f32>) -> (Light, Matrix4<f32>) {
+        let light = PointLight::new(Vector3::new(1.0, 1.0, 1.0), 1.0, Some(1.0));
+        (Light::Point(light), Matrix4::from_translation(position))
+    }
+
+    fn frustum() -> ViewFrustum {
+        ViewFrustum::new(Camera::new(16.0 / 9.0).build_view_projection_matrix())
+    }
+
+    #[test]
+    fn directional_lights_come_first_and_cast_shadows() {
+        let sun = DirectionalLight::new(Vector3::new(1.0, 1.0, 1.0), 2.0);
+        let lights = [
+            point_light(Vector3::new(0.0, 0.0, 0.0)),
+            (Light::Directional(sun), Matrix4::from_angle_x(cgmath::Deg(-90.0))),
+        ];
+        let frame_lights = FrameLights::new(&lights, &frustum(), (1600, 900), &Default::default());
+        assert_eq!(frame_lights.uniform.counts, [1, 2, 0, 0]);
+        assert!(frame_lights.lights[0].is_directional());
+        let direction = frame_lights.shadow_direction.unwrap();
+        assert!((direction - Vector3::new(0.0, -1.0, 0.0)).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn lights_are_only_listed_in_clusters_they_reach() {
+        let settings = LightSettings {
+            cluster_threshold: 0,
+            ..Default::default()
+        };
+        let frustum = frustum();
+        // The camera looks at the origin from (0, 10, 10)
+        let lights = [
+            point_light(Vector3::new(0.0, 0.0, 0.0)),
+            point_light(Vector3::new(0.0, 0.0, 500.0)),
+        ];
+        let frame_lights = FrameLights::new(&lights, &frustum, (1600, 900), &settings);
+        assert_eq!(frame_lights.uniform.counts, [0, 2, 1, 0]);
+        let clusters = &frame_lights.clusters;
+        let [grid_x, grid_y, grid_z] = CLUSTER_GRID.map(|count| count as usize);
+        let lists = (0..grid_x * grid_y * grid_z)
+            .map(|cluster| {
+                let (offset, count) = (clusters[cluster * 2], clusters[cluster * 2 + 1]);
+                &clusters[offset as usize..(offset + count) as usize]
+            })
+            .collect::<Vec<_>>();
+        assert!(lists.iter().flat_map(|list| list.iter()).all(|&light| light == 0));
+        // The screen center sees the light at the origin, the corners do not
+        let depth = frustum.depth(Vector3::new(0.0, 0.0, 0.0));
+        let z = ((depth / frustum.near).ln() * frame_lights.uniform.cluster_scale[2]) as usize;
+        let center = (z * grid_y + grid_y / 2) * grid_x + grid_x / 2;
+        assert_eq!(lists[center], &[0]);
+        assert!(lists[z * grid_y * grid_x].is_empty());
+    }
+}
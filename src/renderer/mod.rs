@@ -1,5 +1,31 @@
+use cgmath::{InnerSpace, Matrix, Matrix4, SquareMatrix, Vector3, Vector4};
+
+use crate::mesh_processing::Aabb;
+
+pub mod buffer_arena;
+pub mod capture;
+pub mod environment;
+pub mod graph;
+pub mod lights;
+pub mod passes;
+pub mod pipeline_cache;
 mod pipeline_default;
+pub mod post;
 pub mod render;
+pub mod shadow;
+pub mod texture;
+
+// Gpu work done by the last call to Renderer::draw
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    // Buffers created while drawing, zero once every asset is uploaded and the
+    // instance arena is large enough
+    pub buffer_allocations: u32,
+    pub bytes_written: u64,
+    // Mesh instances drawn and left out for being outside the view
+    pub drawn_objects: u32,
+    pub culled_objects: u32,
+}
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
@@ -7,13 +33,94 @@ pub struct Globals {
     pub view_proj: [[f32; 4]; 4],
     pub ambient_color: [f32; 4],
     pub ambient_strength: [f32; 4],
+    pub camera_position: [f32; 4],
+    pub inverse_view_proj: [[f32; 4]; 4],
+    // x scales the light of the environment, zero without one, and y is the last
+    // mip level of the prefiltered map
+    pub environment: [f32; 4],
+}
+
+// The camera's position and view direction, recovered from its view projection
+pub struct ViewFrustum {
+    pub view_proj: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+    // Left, right, bottom, top, near and far, with normals pointing inside
+    planes: [Vector4<f32>; 6],
+    pub eye: Vector3<f32>,
+    pub forward: Vector3<f32>,
+    // Depths of the near and far planes
+    pub near: f32,
+    pub far: f32,
+}
+impl ViewFrustum {
+    pub fn new(view_proj: Matrix4<f32>) -> Self {
+        let inverse = view_proj
+            .invert()
+            .expect("Unable to invert view projection matrix");
+        // The camera is the point projected to infinity in front of itself
+        let eye = inverse * Vector4::unit_z();
+        let row = |i| view_proj.row(i);
+        let mut frustum = Self {
+            view_proj,
+            inverse,
+            planes: [
+                row(3) + row(0),
+                row(3) - row(0),
+                row(3) + row(1),
+                row(3) - row(1),
+                row(2),
+                row(3) - row(2),
+            ],
+            eye: eye.truncate() / eye.w,
+            forward: Vector3::unit_z(),
+            near: 0.0,
+            far: 0.0,
+        };
+        let near = frustum.unproject(0.0, 0.0, 0.0);
+        let far = frustum.unproject(0.0, 0.0, 1.0);
+        frustum.forward = (far - near).normalize();
+        frustum.near = frustum.depth(near);
+        frustum.far = frustum.depth(far);
+        frustum
+    }
+
+    // The world position of a point in normalized device coordinates
+    pub fn unproject(&self, x: f32, y: f32, z: f32) -> Vector3<f32> {
+        let point = self.inverse * Vector4::new(x, y, z, 1.0);
+        point.truncate() / point.w
+    }
+
+    // Distance of a point in front of the camera along the view direction
+    pub fn depth(&self, point: Vector3<f32>) -> f32 {
+        (point - self.eye).dot(self.forward)
+    }
+
+    // False when the box is entirely outside one of the planes. Boxes near the
+    // frustum's corners may pass without being visible.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal
+            let corner = Vector4::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+                1.0,
+            );
+            plane.dot(corner) >= 0.0
+        })
+    }
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
-struct Locals {
-    pub diffuse_light_pos: [f32; 4],
-    pub diffuse_light_color: [f32; 4],
+pub struct MaterialUniform {
+    pub base_color: [f32; 4],
+    // a is the alpha cutoff of masked materials
+    pub ambient: [f32; 4],
+    // a is the specular exponent
+    pub specular: [f32; 4],
+    // Columns of the 3x3 texture coordinate transform
+    pub uv_transform: [[f32; 4]; 3],
 }
 
 #[repr(C)]
@@ -70,3 +177,23 @@ impl Instance {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::camera::Camera;
+
+    #[test]
+    fn boxes_outside_the_view_are_not_intersected() {
+        // The camera looks at the origin from (0, 10, 10)
+        let frustum = ViewFrustum::new(Camera::new(16.0 / 9.0).build_view_projection_matrix());
+        let aabb = |x: f32, y: f32, z: f32| Aabb {
+            min: cgmath::point3(x - 0.5, y - 0.5, z - 0.5),
+            max: cgmath::point3(x + 0.5, y + 0.5, z + 0.5),
+        };
+        assert!(frustum.intersects(&aabb(0.0, 0.0, 0.0)));
+        assert!(!frustum.intersects(&aabb(0.0, 20.0, 20.0)));
+        assert!(!frustum.intersects(&aabb(100.0, 0.0, 0.0)));
+        assert!(!frustum.intersects(&aabb(0.0, -200.0, -200.0)));
+    }
+}
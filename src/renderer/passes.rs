@@ -0,0 +1,741 @@
+use std::num::NonZeroU32;
+
+use wgpu::{util::DeviceExt, vertex_attr_array};
+
+use crate::{
+    animation::MAX_MORPH_TARGETS,
+    mesh::{DrawMesh, InstanceData},
+};
+
+use super::{
+    graph::{
+        AttachmentName, Attachments, Pass, PassContext, COLOR, DEPTH, MSAA_COLOR, SHADOW_MAP,
+        SURFACE,
+    },
+    pipeline_default::DefaultPipeline,
+    post::HDR_FORMAT,
+    shadow::{CASCADES, SHADOW_MAP_FORMAT},
+};
+
+// Draws shadow casters into each cascade of the shadow map
+pub struct ShadowPass {
+    pipeline: wgpu::RenderPipeline,
+    morph_pipeline: wgpu::RenderPipeline,
+    // Binds the shadow uniform along with the index of each cascade
+    cascade_bind_groups: Vec<wgpu::BindGroup>,
+    layer_views: Vec<wgpu::TextureView>,
+}
+
+impl ShadowPass {
+    pub const NAME: &'static str = "Shadow";
+
+    pub fn new(
+        device: &wgpu::Device,
+        default_pipeline: &DefaultPipeline,
+        shadow_buffer: &wgpu::Buffer,
+    ) -> Self {
+        let uniform_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Shadow bind group layout"),
+            entries: &[uniform_entry(0), uniform_entry(1)],
+        });
+        let cascade_bind_groups = (0..CASCADES as u32)
+            .map(|cascade| {
+                let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Shadow cascade index"),
+                    contents: bytemuck::cast_slice(&[cascade, 0, 0, 0]),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                });
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Shadow cascade bind group"),
+                    layout: &bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: shadow_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: index_buffer.as_entire_binding(),
+                        },
+                    ],
+                })
+            })
+            .collect();
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Shadow shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
+        });
+        let positions_buffer_layout = wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &vertex_attr_array![4 => Float32x3],
+        };
+        let weights_buffer_layout = wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<[f32; MAX_MORPH_TARGETS]>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &vertex_attr_array![6 => Float32x4, 7 => Float32x4],
+        };
+        let buffers = [
+            InstanceData::layout(),
+            positions_buffer_layout,
+            weights_buffer_layout,
+        ];
+        let create_pipeline = |morph: bool| {
+            let bind_group_layouts = [
+                &bind_group_layout,
+                &default_pipeline.morph_bind_group_layout,
+            ];
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Shadow pipeline layout"),
+                bind_group_layouts: if morph {
+                    &bind_group_layouts
+                } else {
+                    &bind_group_layouts[..1]
+                },
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(Self::NAME),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: if morph { "vs_morph" } else { "vs_main" },
+                    buffers: if morph { &buffers } else { &buffers[..2] },
+                },
+                fragment: None,
+                // Both faces are drawn so that planes and open meshes cast shadows too
+                primitive: wgpu::PrimitiveState {
+                    cull_mode: None,
+                    ..default_pipeline.primitive
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: SHADOW_MAP_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::LessEqual,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState {
+                        constant: 2,
+                        slope_scale: 2.0,
+                        clamp: 0.0,
+                    },
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        Self {
+            pipeline: create_pipeline(false),
+            morph_pipeline: create_pipeline(true),
+            cascade_bind_groups,
+            layer_views: Vec::new(),
+        }
+    }
+}
+
+impl Pass for ShadowPass {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+    fn writes(&self) -> Vec<AttachmentName> {
+        vec![SHADOW_MAP]
+    }
+    fn attachments_changed(&mut self, _device: &wgpu::Device, attachments: &Attachments) {
+        let texture = &attachments.get(SHADOW_MAP).texture;
+        self.layer_views = (0..CASCADES as u32)
+            .map(|layer| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("Shadow cascade view"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+    }
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder) {
+        for (view, bind_group) in self.layer_views.iter().zip(&self.cascade_bind_groups) {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            let instance_buffer = match context.frame.instance_buffer {
+                Some(instance_buffer) if context.frame.shadows => instance_buffer,
+                _ => continue,
+            };
+            render_pass.set_bind_group(0, bind_group, &[]);
+            for batch in context.frame.batches.iter().filter(|batch| batch.casts_shadow) {
+                render_pass.draw_mesh_depth(
+                    batch,
+                    instance_buffer,
+                    &self.pipeline,
+                    &self.morph_pipeline,
+                );
+            }
+        }
+    }
+}
+
+// Draws every mesh batch into the color and depth attachments, resolving into the
+// color attachment while multisampling
+pub struct OpaquePass {
+    pub clear_color: wgpu::Color,
+}
+
+impl Pass for OpaquePass {
+    fn name(&self) -> &'static str {
+        "Opaque"
+    }
+    fn reads(&self) -> Vec<AttachmentName> {
+        vec![SHADOW_MAP]
+    }
+    fn writes(&self) -> Vec<AttachmentName> {
+        vec![COLOR, DEPTH]
+    }
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder) {
+        let (view, resolve_target) = if context.attachments.contains(MSAA_COLOR) {
+            (context.view(MSAA_COLOR), Some(context.view(COLOR)))
+        } else {
+            (context.view(COLOR), None)
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Opaque pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(self.clear_color),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: context.view(DEPTH),
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        let instance_buffer = match context.frame.instance_buffer {
+            Some(instance_buffer) => instance_buffer,
+            None => return,
+        };
+        render_pass.set_bind_group(0, &context.renderer.globals_bind_group, &[]);
+        render_pass.set_bind_group(1, context.frame.lighting, &[]);
+        for batch in context.frame.batches {
+            render_pass.draw_mesh(batch, instance_buffer);
+        }
+    }
+}
+
+// Draws the environment behind the meshes, where the depth attachment is still
+// cleared to the far plane
+pub struct SkyboxPass {
+    layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    // Created again when the depth attachment starts or stops being multisampled
+    pipeline: Option<(u32, wgpu::RenderPipeline)>,
+}
+
+impl SkyboxPass {
+    pub const NAME: &'static str = "Skybox";
+
+    pub fn new(device: &wgpu::Device, default_pipeline: &DefaultPipeline) -> Self {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Skybox pipeline layout"),
+            bind_group_layouts: &[&default_pipeline.globals_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Skybox shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("skybox.wgsl").into()),
+        });
+        Self {
+            layout,
+            shader,
+            pipeline: None,
+        }
+    }
+}
+
+impl Pass for SkyboxPass {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+    fn writes(&self) -> Vec<AttachmentName> {
+        vec![COLOR, DEPTH]
+    }
+    fn attachments_changed(&mut self, device: &wgpu::Device, attachments: &Attachments) {
+        let samples = attachments.get(DEPTH).descriptor.samples;
+        if matches!(&self.pipeline, Some((current, _)) if *current == samples) {
+            return;
+        }
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(Self::NAME),
+            layout: Some(&self.layout),
+            vertex: wgpu::VertexState {
+                module: &self.shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &self.shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState::from(HDR_FORMAT))],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: samples,
+                ..Default::default()
+            },
+            multiview: None,
+        });
+        self.pipeline = Some((samples, pipeline));
+    }
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder) {
+        let (view, resolve_target) = if context.attachments.contains(MSAA_COLOR) {
+            (context.view(MSAA_COLOR), Some(context.view(COLOR)))
+        } else {
+            (context.view(COLOR), None)
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Skybox pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: context.view(DEPTH),
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        let (_, pipeline) = self.pipeline.as_ref().unwrap();
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &context.renderer.globals_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// Blends transparent primitives over the opaque ones, back to front, testing against
+// the depth attachment without writing it
+pub struct TransparentPass;
+
+impl Pass for TransparentPass {
+    fn name(&self) -> &'static str {
+        "Transparent"
+    }
+    fn reads(&self) -> Vec<AttachmentName> {
+        vec![SHADOW_MAP]
+    }
+    fn writes(&self) -> Vec<AttachmentName> {
+        vec![COLOR, DEPTH]
+    }
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder) {
+        let instance_buffer = match context.frame.instance_buffer {
+            Some(instance_buffer) if !context.frame.transparent.is_empty() => instance_buffer,
+            _ => return,
+        };
+        let (view, resolve_target) = if context.attachments.contains(MSAA_COLOR) {
+            (context.view(MSAA_COLOR), Some(context.view(COLOR)))
+        } else {
+            (context.view(COLOR), None)
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Transparent pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: context.view(DEPTH),
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_bind_group(0, &context.renderer.globals_bind_group, &[]);
+        render_pass.set_bind_group(1, context.frame.lighting, &[]);
+        for draw in context.frame.transparent {
+            render_pass.draw_transparent(context.frame.batches, draw, instance_buffer);
+        }
+    }
+}
+
+// Draws one triangle covering the target, with a shader sampling the sources in
+// group 0 and reading an optional uniform in group 1
+pub struct FullscreenPass {
+    name: &'static str,
+    sources: Vec<AttachmentName>,
+    target: AttachmentName,
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    bind_group: Option<wgpu::BindGroup>,
+    uniform_bind_group: Option<wgpu::BindGroup>,
+}
+
+impl FullscreenPass {
+    // The shader's entry points are vs_main and fs_main
+    pub fn new(
+        device: &wgpu::Device,
+        name: &'static str,
+        shader: &str,
+        sources: &[AttachmentName],
+        target: AttachmentName,
+        format: wgpu::TextureFormat,
+        uniform: Option<&wgpu::Buffer>,
+    ) -> Self {
+        let bind_group_layout = sources_bind_group_layout(device, name, sources.len());
+        let uniform = uniform.map(|buffer| uniform_bind_group(device, name, buffer));
+        let mut bind_group_layouts = vec![&bind_group_layout];
+        bind_group_layouts.extend(uniform.as_ref().map(|(layout, _)| layout));
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(name),
+            source: wgpu::ShaderSource::Wgsl(shader.into()),
+        });
+        let pipeline =
+            fullscreen_pipeline(device, name, &shader, "fs_main", &bind_group_layouts, format);
+        Self {
+            name,
+            sources: sources.to_vec(),
+            target,
+            pipeline,
+            bind_group_layout,
+            sampler: linear_sampler(device),
+            bind_group: None,
+            uniform_bind_group: uniform.map(|(_, bind_group)| bind_group),
+        }
+    }
+}
+
+impl Pass for FullscreenPass {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn reads(&self) -> Vec<AttachmentName> {
+        self.sources.clone()
+    }
+    fn writes(&self) -> Vec<AttachmentName> {
+        vec![self.target]
+    }
+    fn attachments_changed(&mut self, device: &wgpu::Device, attachments: &Attachments) {
+        let views = self
+            .sources
+            .iter()
+            .map(|&source| &attachments.get(source).view)
+            .collect::<Vec<_>>();
+        self.bind_group = Some(sources_bind_group(
+            device,
+            &self.bind_group_layout,
+            &views,
+            &self.sampler,
+        ));
+    }
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(self.name),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: context.view(self.target),
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, self.bind_group.as_ref().unwrap(), &[]);
+        if let Some(uniform_bind_group) = &self.uniform_bind_group {
+            render_pass.set_bind_group(1, uniform_bind_group, &[]);
+        }
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// Shows the depth attachment in the bottom right corner of the surface
+pub struct DepthOverlayPass {
+    format: wgpu::TextureFormat,
+    // Created again when the depth attachment starts or stops being multisampled
+    pipeline: Option<(u32, wgpu::RenderPipeline, wgpu::BindGroupLayout)>,
+    bind_group: Option<wgpu::BindGroup>,
+}
+
+impl DepthOverlayPass {
+    pub const NAME: &'static str = "Depth overlay";
+
+    pub fn new(format: wgpu::TextureFormat) -> Self {
+        Self {
+            format,
+            pipeline: None,
+            bind_group: None,
+        }
+    }
+}
+
+impl Pass for DepthOverlayPass {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+    fn reads(&self) -> Vec<AttachmentName> {
+        vec![DEPTH]
+    }
+    fn writes(&self) -> Vec<AttachmentName> {
+        vec![SURFACE]
+    }
+    fn attachments_changed(&mut self, device: &wgpu::Device, attachments: &Attachments) {
+        let depth = attachments.get(DEPTH);
+        let samples = depth.descriptor.samples;
+        if !matches!(&self.pipeline, Some((current, ..)) if *current == samples) {
+            let (pipeline, bind_group_layout) =
+                depth_pipeline(device, Self::NAME, samples, self.format);
+            self.pipeline = Some((samples, pipeline, bind_group_layout));
+        }
+        let (_, _, bind_group_layout) = self.pipeline.as_ref().unwrap();
+        self.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Depth overlay bind group"),
+            layout: bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&depth.view),
+            }],
+        }));
+    }
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder) {
+        let config = &context.renderer.surface_config;
+        let (width, height) = (config.width as f32 / 4.0, config.height as f32 / 4.0);
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(Self::NAME),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: context.view(SURFACE),
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_viewport(
+            config.width as f32 - width,
+            config.height as f32 - height,
+            width,
+            height,
+            0.0,
+            1.0,
+        );
+        let (_, pipeline, _) = self.pipeline.as_ref().unwrap();
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, self.bind_group.as_ref().unwrap(), &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// Shows the depth attachment's distance from the camera in shades of gray, with a
+// bind group layout for the attachment
+pub(super) fn depth_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    samples: u32,
+    format: wgpu::TextureFormat,
+) -> (wgpu::RenderPipeline, wgpu::BindGroupLayout) {
+    let multisampled = samples > 1;
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Depth,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled,
+            },
+            count: None,
+        }],
+    });
+    // Loading the first sample of a multisampled texture reads the same as a mip level
+    let mut source = include_str!("depth.wgsl").to_string();
+    if multisampled {
+        source = source.replace("texture_depth_2d", "texture_depth_multisampled_2d");
+    }
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(label),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    });
+    let pipeline = fullscreen_pipeline(
+        device,
+        label,
+        &shader,
+        "fs_main",
+        &[&bind_group_layout],
+        format,
+    );
+    (pipeline, bind_group_layout)
+}
+
+pub(super) fn fullscreen_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    shader: &wgpu::ShaderModule,
+    fragment_entry_point: &str,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some(label),
+        bind_group_layouts,
+        push_constant_ranges: &[],
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: fragment_entry_point,
+            targets: &[Some(wgpu::ColorTargetState::from(format))],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+pub(super) fn linear_sampler(device: &wgpu::Device) -> wgpu::Sampler {
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    })
+}
+
+// Filterable textures in the first bindings, followed by a sampler
+pub(super) fn sources_bind_group_layout(
+    device: &wgpu::Device,
+    label: &str,
+    count: usize,
+) -> wgpu::BindGroupLayout {
+    let mut entries = (0..count as u32)
+        .map(|binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        })
+        .collect::<Vec<_>>();
+    entries.push(wgpu::BindGroupLayoutEntry {
+        binding: count as u32,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+        count: None,
+    });
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &entries,
+    })
+}
+
+pub(super) fn sources_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    views: &[&wgpu::TextureView],
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    let mut entries = views
+        .iter()
+        .enumerate()
+        .map(|(binding, view)| wgpu::BindGroupEntry {
+            binding: binding as u32,
+            resource: wgpu::BindingResource::TextureView(view),
+        })
+        .collect::<Vec<_>>();
+    entries.push(wgpu::BindGroupEntry {
+        binding: views.len() as u32,
+        resource: wgpu::BindingResource::Sampler(sampler),
+    });
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &entries,
+    })
+}
+
+// A bind group holding only the uniform buffer, visible to fragment shaders
+pub(super) fn uniform_bind_group(
+    device: &wgpu::Device,
+    label: &str,
+    buffer: &wgpu::Buffer,
+) -> (wgpu::BindGroupLayout, wgpu::BindGroup) {
+    let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some(label),
+        layout: &layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        }],
+    });
+    (layout, bind_group)
+}
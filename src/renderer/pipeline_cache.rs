@@ -0,0 +1,86 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VertexSemantic {
+    Position,
+    Normal,
+    TexCoord,
+    // Only provided by normal mapped primitives
+    Tangent,
+}
+impl VertexSemantic {
+    pub const ALL: [VertexSemantic; 4] = [
+        VertexSemantic::Position,
+        VertexSemantic::Normal,
+        VertexSemantic::TexCoord,
+        VertexSemantic::Tangent,
+    ];
+
+    // Where the mesh shaders read the attribute
+    pub fn shader_location(self) -> u32 {
+        match self {
+            VertexSemantic::Position => 4,
+            VertexSemantic::Normal => 5,
+            VertexSemantic::TexCoord => 8,
+            VertexSemantic::Tangent => 10,
+        }
+    }
+}
+
+// The format of every attribute a primitive provides, each in a vertex buffer of its
+// own, bound in the order of VertexSemantic::ALL
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VertexLayout([Option<wgpu::VertexFormat>; 4]);
+impl VertexLayout {
+    pub fn with(mut self, semantic: VertexSemantic, format: wgpu::VertexFormat) -> Self {
+        self.0[semantic as usize] = Some(format);
+        self
+    }
+    pub fn contains(&self, semantic: VertexSemantic) -> bool {
+        self.0[semantic as usize].is_some()
+    }
+    pub fn attributes(&self) -> impl Iterator<Item = (VertexSemantic, wgpu::VertexFormat)> + '_ {
+        VertexSemantic::ALL
+            .into_iter()
+            .filter_map(|semantic| Some((semantic, self.0[semantic as usize]?)))
+    }
+}
+
+// Everything a mesh pipeline is created from, so that primitives of any asset
+// describing the same one share it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshPipelineKey {
+    pub vertex_layout: VertexLayout,
+    pub morph: bool,
+    pub unlit: bool,
+    // Blended primitives are tested against the depth of opaque ones without writing it
+    pub blend: bool,
+    pub cull_mode: Option<wgpu::Face>,
+    pub format: wgpu::TextureFormat,
+    pub sample_count: u32,
+}
+
+// Mesh pipelines shared by every asset, created the first time their key is drawn
+#[derive(Default)]
+pub struct PipelineCache {
+    pipelines: RefCell<HashMap<MeshPipelineKey, Rc<wgpu::RenderPipeline>>>,
+}
+impl PipelineCache {
+    pub fn get_or_create(
+        &self,
+        key: MeshPipelineKey,
+        create: impl FnOnce(&MeshPipelineKey) -> wgpu::RenderPipeline,
+    ) -> Rc<wgpu::RenderPipeline> {
+        self.pipelines
+            .borrow_mut()
+            .entry(key)
+            .or_insert_with(|| Rc::new(create(&key)))
+            .clone()
+    }
+    pub fn len(&self) -> usize {
+        self.pipelines.borrow().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pipelines.borrow().is_empty()
+    }
+}
@@ -1,19 +1,43 @@
 use std::num::NonZeroU32;
 
-use wgpu::MultisampleState;
+use wgpu::{vertex_attr_array, MultisampleState};
+
+use crate::{animation::MAX_MORPH_TARGETS, mesh::InstanceData};
+
+use super::{
+    pipeline_cache::{MeshPipelineKey, VertexSemantic},
+    texture::Texture,
+};
 
 pub struct DefaultPipeline {
     pub shader: wgpu::ShaderModule,
     pub pn_shader: wgpu::ShaderModule,
+    pub pn_morph_shader: wgpu::ShaderModule,
     pub depth_stencil: wgpu::DepthStencilState,
     pub globals_bind_group_layout: wgpu::BindGroupLayout,
     pub locals_bind_group_layout: wgpu::BindGroupLayout,
+    pub material_bind_group_layout: wgpu::BindGroupLayout,
+    pub morph_bind_group_layout: wgpu::BindGroupLayout,
     pub layout: wgpu::PipelineLayout,
+    pub morph_layout: wgpu::PipelineLayout,
     pub multisample: wgpu::MultisampleState,
     pub multiview: Option<NonZeroU32>,
     pub primitive: wgpu::PrimitiveState,
 }
 
+// Marks where pn.wgsl's vertex stage starts
+const VERTEX_STAGE: &str = "// Vertex stage";
+
+// Lighting and fragment stages are shared with pn.wgsl, followed by the vertex stage
+// that applies morph targets
+fn morph_shader_source() -> String {
+    let source = include_str!("pn.wgsl");
+    let vertex_stage = source
+        .find(VERTEX_STAGE)
+        .expect("pn.wgsl is missing its vertex stage marker");
+    format!("{}{}", &source[..vertex_stage], include_str!("pn_morph.wgsl"))
+}
+
 impl DefaultPipeline {
     pub fn new(device: &wgpu::Device) -> Self {
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
@@ -24,6 +48,10 @@ impl DefaultPipeline {
             label: Some("Basic PipelineShader"),
             source: wgpu::ShaderSource::Wgsl(include_str!("pn.wgsl").into()),
         });
+        let pn_morph_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Morph Target PipelineShader"),
+            source: wgpu::ShaderSource::Wgsl(morph_shader_source().into()),
+        });
 
         let depth_stencil = wgpu::DepthStencilState {
             bias: wgpu::DepthBiasState::default(),
@@ -33,39 +61,194 @@ impl DefaultPipeline {
             stencil: wgpu::StencilState::default(),
         };
         
+        let texture_entry = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            count: None,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension,
+                multisampled: false,
+            },
+            visibility: wgpu::ShaderStages::FRAGMENT,
+        };
+        // Globals, then the skybox, irradiance and prefiltered environment cubemaps,
+        // the BRDF lookup table and the sampler they share
         let globals_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    count: None,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::all(),
                     },
-                    visibility: wgpu::ShaderStages::all(),
-                }],
+                    texture_entry(1, wgpu::TextureViewDimension::Cube),
+                    texture_entry(2, wgpu::TextureViewDimension::Cube),
+                    texture_entry(3, wgpu::TextureViewDimension::Cube),
+                    texture_entry(4, wgpu::TextureViewDimension::D2),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        count: None,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                ],
                 label: Some("Globals Bind Group"),
             });
 
+        // Lighting counts, shadows, then the lights and the clusters listing them
         let locals_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    count: None,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::all(),
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::FRAGMENT,
                     },
-                    visibility: wgpu::ShaderStages::all(),
-                }],
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        count: None,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Depth,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            multisampled: false,
+                        },
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        count: None,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                ],
                 label: Some("Locals Bind Group"),
             });
 
+        let material_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        count: None,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        count: None,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                    texture_entry(3, wgpu::TextureViewDimension::D2),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        count: None,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                    },
+                ],
+                label: Some("Material Bind Group"),
+            });
+
+        let morph_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::VERTEX,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        visibility: wgpu::ShaderStages::VERTEX,
+                    },
+                ],
+                label: Some("Morph Target Bind Group"),
+            });
+
         let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Basic Render Layout"),
-            bind_group_layouts: &[&globals_bind_group_layout, &locals_bind_group_layout],
+            bind_group_layouts: &[
+                &globals_bind_group_layout,
+                &locals_bind_group_layout,
+                &material_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let morph_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Morph Target Render Layout"),
+            bind_group_layouts: &[
+                &globals_bind_group_layout,
+                &locals_bind_group_layout,
+                &material_bind_group_layout,
+                &morph_bind_group_layout,
+            ],
             push_constant_ranges: &[],
         });
 
@@ -74,7 +257,10 @@ impl DefaultPipeline {
             depth_stencil,
             globals_bind_group_layout,
             locals_bind_group_layout,
+            material_bind_group_layout,
+            morph_bind_group_layout,
             layout, 
+            morph_layout,
             multisample: MultisampleState::default(),
             multiview: None,
             primitive: wgpu::PrimitiveState {
@@ -87,6 +273,131 @@ impl DefaultPipeline {
                 conservative: false,
             },
             pn_shader,
+            pn_morph_shader,
+        }
+    }
+    // Meshes provide each vertex attribute in a buffer of its own after the instance
+    // buffer, followed by morph weights when they have targets
+    pub fn create_mesh_pipeline(
+        &self,
+        device: &wgpu::Device,
+        key: &MeshPipelineKey,
+    ) -> wgpu::RenderPipeline {
+        let attributes = key
+            .vertex_layout
+            .attributes()
+            .map(|(semantic, format)| wgpu::VertexAttribute {
+                format,
+                offset: 0,
+                shader_location: semantic.shader_location(),
+            })
+            .collect::<Vec<_>>();
+        let weights_attributes = vertex_attr_array![6 => Float32x4, 7 => Float32x4];
+        let mut buffers = vec![InstanceData::layout()];
+        buffers.extend(attributes.iter().map(|attribute| wgpu::VertexBufferLayout {
+            array_stride: attribute.format.size(),
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: std::slice::from_ref(attribute),
+        }));
+        if key.morph {
+            buffers.push(wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<[f32; MAX_MORPH_TARGETS]>()
+                    as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Instance,
+                attributes: &weights_attributes,
+            });
         }
+        let (shader, layout) = if key.morph {
+            (&self.pn_morph_shader, &self.morph_layout)
+        } else {
+            (&self.pn_shader, &self.layout)
+        };
+        let targets = &[Some(wgpu::ColorTargetState {
+            format: key.format,
+            blend: key.blend.then_some(wgpu::BlendState::ALPHA_BLENDING),
+            write_mask: wgpu::ColorWrites::ALL,
+        })];
+        let fragment = wgpu::FragmentState {
+            module: shader,
+            entry_point: if key.unlit { "fs_unlit" } else { "fs_main" },
+            targets,
+        };
+        // Primitives with tangents are normal mapped by a vertex stage that reads them
+        let vertex = wgpu::VertexState {
+            module: shader,
+            entry_point: if key.vertex_layout.contains(VertexSemantic::Tangent) {
+                "vs_tangent"
+            } else {
+                "vs_main"
+            },
+            buffers: &buffers,
+        };
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(layout),
+            vertex,
+            fragment: Some(fragment),
+            primitive: wgpu::PrimitiveState {
+                cull_mode: key.cull_mode,
+                ..self.primitive
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                depth_write_enabled: !key.blend,
+                ..self.depth_stencil.clone()
+            }),
+            multisample: wgpu::MultisampleState {
+                count: key.sample_count,
+                ..self.multisample
+            },
+            multiview: self.multiview,
+        })
+    }
+    pub fn create_material_bind_group(
+        &self,
+        device: &wgpu::Device,
+        material_buffer: &wgpu::Buffer,
+        texture: &Texture,
+        normal_texture: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Material bind group"),
+            layout: &self.material_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: material_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                },
+            ],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn morph_shader_replaces_only_the_vertex_stage() {
+        let source = morph_shader_source();
+        assert_eq!(source.matches("fn vs_main").count(), 1);
+        assert_eq!(source.matches("fn vs_tangent").count(), 1);
+        assert_eq!(source.matches("fn fs_main").count(), 1);
+        assert!(source.contains("morph_deltas"));
     }
 }
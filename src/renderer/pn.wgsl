@@ -3,42 +3,311 @@ struct Globals {
     view_proj: mat4x4<f32>,
     ambient_color: vec4<f32>,
     ambient_strength: f32,
+    camera_position: vec4<f32>,
+    inverse_view_proj: mat4x4<f32>,
+    // x scales the light of the environment, zero without one, and y is the last
+    // mip level of the prefiltered map
+    environment: vec4<f32>,
 }
 
-struct Locals {
-    diffuse_light_position: vec4<f32>,
-    diffuse_light_color: vec4<f32>,
+struct Light {
+    // w is 0 for directional lights
+    position: vec4<f32>,
+    // w is the range of the light, 0 when unlimited
+    direction: vec4<f32>,
+    // a is the intensity of the light
+    color: vec4<f32>,
+    // Cosines of the inner and outer spot angles, z is 1 for spot lights
+    cone: vec4<f32>,
+}
+
+struct Lighting {
+    // x lights shade every fragment and come first, y is the number of lights
+    // and z is 1 when the others are looked up per cluster
+    counts: vec4<u32>,
+    cluster_grid: vec4<u32>,
+    // Converts pixels to clusters on x and y, z converts the log of the depth over
+    // the near distance in w to slices
+    cluster_scale: vec4<f32>,
+    camera_forward: vec4<f32>,
+}
+
+struct Shadows {
+    // Light projections of the 3 cascades
+    cascades: array<mat4x4<f32>, 3>,
+    // Distance along the view direction where each cascade ends
+    splits: vec4<f32>,
+    // World size of one shadow map texel in each cascade
+    texel_sizes: vec4<f32>,
+    camera_forward: vec4<f32>,
+    // x is 1 when shadows are drawn, y is the depth bias and z the normal bias
+    params: vec4<f32>,
+}
+
+struct Material {
+    base_color: vec4<f32>,
+    // a is the alpha below which fragments are discarded
+    ambient: vec4<f32>,
+    // a is the specular exponent
+    specular: vec4<f32>,
+    uv_transform: mat3x3<f32>,
 }
 
 @group(0) @binding(0)
 var<uniform> globals: Globals;
 
+@group(0) @binding(2)
+var irradiance_map: texture_cube<f32>;
+
+@group(0) @binding(3)
+var prefiltered_map: texture_cube<f32>;
+
+@group(0) @binding(4)
+var brdf_lut: texture_2d<f32>;
+
+@group(0) @binding(5)
+var environment_sampler: sampler;
+
 @group(1) @binding(0)
-var<uniform> locals: Locals;
+var<uniform> lighting: Lighting;
+
+@group(1) @binding(1)
+var<uniform> shadows: Shadows;
+
+@group(1) @binding(2)
+var shadow_map: texture_depth_2d_array;
+
+@group(1) @binding(3)
+var shadow_sampler: sampler_comparison;
+
+@group(1) @binding(4)
+var<storage, read> lights: array<Light>;
+
+// An offset and count into this same array for every cluster, followed by the
+// light indices they point to
+@group(1) @binding(5)
+var<storage, read> clusters: array<u32>;
+
+@group(2) @binding(0)
+var<uniform> material: Material;
+
+@group(2) @binding(1)
+var base_color_texture: texture_2d<f32>;
+
+@group(2) @binding(2)
+var base_color_sampler: sampler;
+
+@group(2) @binding(3)
+var normal_texture: texture_2d<f32>;
+
+@group(2) @binding(4)
+var normal_sampler: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_normal: vec3<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) receives_shadow: f32,
+    // Zero for primitives without tangents, which aren't normal mapped
+    @location(4) world_tangent: vec4<f32>,
+};
+
+fn base_color(in: VertexOutput) -> vec4<f32> {
+    let color = material.base_color * textureSample(base_color_texture, base_color_sampler, in.uv);
+    if (color.a < material.ambient.a) {
+        discard;
+    }
+    return color;
+}
+
+// The normal read from the normal map, turned from tangent space to world space
+fn surface_normal(in: VertexOutput, normal: vec3<f32>) -> vec3<f32> {
+    let sample = textureSample(normal_texture, normal_sampler, in.uv).xyz * 2.0 - 1.0;
+    if (dot(in.world_tangent.xyz, in.world_tangent.xyz) == 0.0) {
+        return normal;
+    }
+    let tangent = normalize(in.world_tangent.xyz - normal * dot(in.world_tangent.xyz, normal));
+    let bitangent = cross(normal, tangent) * in.world_tangent.w;
+    return normalize(mat3x3<f32>(tangent, bitangent, normal) * sample);
+}
+
+struct LightSample {
+    direction: vec3<f32>,
+    radiance: vec3<f32>,
+}
+
+fn sample_light(light: Light, position: vec3<f32>) -> LightSample {
+    var light_dir = -light.direction.xyz;
+    var attenuation = 1.0;
+    if (light.position.w > 0.0) {
+        let offset = light.position.xyz - position;
+        let distance = length(offset);
+        light_dir = offset / distance;
+        attenuation = 1.0 / max(distance * distance, 0.0001);
+        if (light.direction.w > 0.0) {
+            let ratio = distance / light.direction.w;
+            attenuation = attenuation * clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+        }
+        if (light.cone.z > 0.0) {
+            let cos_angle = dot(light.direction.xyz, -light_dir);
+            attenuation = attenuation * smoothstep(light.cone.y, light.cone.x, cos_angle);
+        }
+    }
+    return LightSample(light_dir, light.color.rgb * light.color.a * attenuation);
+}
+
+struct Shading {
+    diffuse: vec3<f32>,
+    specular: vec3<f32>,
+}
 
+fn shade(light: LightSample, normal: vec3<f32>, view_dir: vec3<f32>) -> Shading {
+    let diffuse_intensity = max(dot(light.direction, normal), 0.0);
+    var specular = vec3<f32>(0.0, 0.0, 0.0);
+    if (diffuse_intensity > 0.0) {
+        let half_dir = normalize(light.direction + view_dir);
+        let shininess = max(material.specular.a, 1.0);
+        let specular_intensity = pow(max(dot(normal, half_dir), 0.0), shininess);
+        specular = light.radiance * material.specular.rgb * specular_intensity;
+    }
+    return Shading(light.radiance * diffuse_intensity, specular);
+}
+
+// Index of the cluster holding a fragment at some distance along the view direction
+// Light from the environment, read from the irradiance map for diffuse and from the
+// prefiltered map level matching the roughness for specular
+fn environment_lighting(normal: vec3<f32>, view_dir: vec3<f32>) -> Shading {
+    let roughness = sqrt(2.0 / (material.specular.a + 2.0));
+    let n_dot_v = max(dot(normal, view_dir), 0.0);
+    let reflected = reflect(-view_dir, normal);
+    let level = roughness * globals.environment.y;
+    let irradiance = textureSampleLevel(irradiance_map, environment_sampler, normal, 0.0);
+    let prefiltered = textureSampleLevel(prefiltered_map, environment_sampler, reflected, level);
+    let brdf_uv = vec2<f32>(n_dot_v, roughness);
+    let brdf = textureSampleLevel(brdf_lut, environment_sampler, brdf_uv, 0.0);
+    var shading: Shading;
+    shading.diffuse = irradiance.rgb * globals.environment.x;
+    shading.specular = prefiltered.rgb * material.specular.rgb * (brdf.x + brdf.y)
+        * globals.environment.x;
+    return shading;
+}
+
+fn cluster_index(frag_coord: vec2<f32>, depth: f32) -> u32 {
+    let grid = lighting.cluster_grid;
+    let scale = lighting.cluster_scale;
+    let x = min(u32(frag_coord.x * scale.x), grid.x - 1u);
+    let y = min(u32(frag_coord.y * scale.y), grid.y - 1u);
+    let slice = log(max(depth, scale.w) / scale.w) * scale.z;
+    let z = min(u32(max(slice, 0.0)), grid.z - 1u);
+    return (z * grid.y + y) * grid.x + x;
+}
+
+// Fraction of the light reaching the position, filtered over 3x3 shadow map texels
+fn shadow_factor(position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if (shadows.params.x == 0.0) {
+        return 1.0;
+    }
+    let depth = dot(position - globals.camera_position.xyz, shadows.camera_forward.xyz);
+    var cascade = 0u;
+    for (; cascade < 3u; cascade = cascade + 1u) {
+        if (depth <= shadows.splits[cascade]) {
+            break;
+        }
+    }
+    if (cascade == 3u) {
+        return 1.0;
+    }
+    let offset = normal * shadows.texel_sizes[cascade] * shadows.params.z;
+    let clip = shadows.cascades[cascade] * vec4<f32>(position + offset, 1.0);
+    let coords = clip.xyz / clip.w;
+    let uv = coords.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    let reference = coords.z - shadows.params.y;
+    let texel = 1.0 / vec2<f32>(textureDimensions(shadow_map));
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y = y + 1) {
+        for (var x = -1; x <= 1; x = x + 1) {
+            let sample_uv = uv + vec2<f32>(f32(x), f32(y)) * texel;
+            lit = lit + textureSampleCompareLevel(
+                shadow_map, shadow_sampler, sample_uv, i32(cascade), reference
+            );
+        }
+    }
+    return lit / 9.0;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Shadows are offset along the surface itself rather than its normal map
+    let geometric_normal = normalize(in.world_normal);
+    let normal = surface_normal(in, geometric_normal);
+    let view_dir = normalize(globals.camera_position.xyz - in.world_position);
+    var diffuse_color = vec3<f32>(0.0, 0.0, 0.0);
+    var specular_color = vec3<f32>(0.0, 0.0, 0.0);
+    for (var i = 0u; i < lighting.counts.x; i = i + 1u) {
+        var light = sample_light(lights[i], in.world_position);
+        // Only the first light casts shadows
+        if (i == 0u && in.receives_shadow > 0.5) {
+            light.radiance = light.radiance * shadow_factor(in.world_position, geometric_normal);
+        }
+        let shading = shade(light, normal, view_dir);
+        diffuse_color = diffuse_color + shading.diffuse;
+        specular_color = specular_color + shading.specular;
+    }
+    if (lighting.counts.z == 0u) {
+        for (var i = lighting.counts.x; i < lighting.counts.y; i = i + 1u) {
+            let shading = shade(sample_light(lights[i], in.world_position), normal, view_dir);
+            diffuse_color = diffuse_color + shading.diffuse;
+            specular_color = specular_color + shading.specular;
+        }
+    } else {
+        let to_fragment = in.world_position - globals.camera_position.xyz;
+        let depth = dot(to_fragment, lighting.camera_forward.xyz);
+        let cluster = cluster_index(in.clip_position.xy, depth);
+        let offset = clusters[cluster * 2u];
+        let count = clusters[cluster * 2u + 1u];
+        for (var i = offset; i < offset + count; i = i + 1u) {
+            let light = sample_light(lights[clusters[i]], in.world_position);
+            let shading = shade(light, normal, view_dir);
+            diffuse_color = diffuse_color + shading.diffuse;
+            specular_color = specular_color + shading.specular;
+        }
+    }
+
+    // The environment replaces the flat ambient light when there is one
+    var ambient_color = globals.ambient_color.rgb * globals.ambient_strength;
+    if (globals.environment.x > 0.0) {
+        let environment = environment_lighting(normal, view_dir);
+        ambient_color = environment.diffuse;
+        specular_color = specular_color + environment.specular;
+    }
+    let color = base_color(in);
+    let result = (diffuse_color + ambient_color * material.ambient.rgb) * color.rgb
+        + specular_color;
+    return vec4<f32>(result, color.a);
+}
+
+@fragment
+fn fs_unlit(in: VertexOutput) -> @location(0) vec4<f32> {
+    return base_color(in);
+}
+
+// Vertex stage, which pn_morph.wgsl replaces for meshes with morph targets
 struct InstanceInput {
     @location(0) v1: vec4<f32>,
     @location(1) v2: vec4<f32>,
     @location(2) v3: vec4<f32>,
     @location(3) v4: vec4<f32>,
+    @location(9) receives_shadow: f32,
 }
 
 struct VertexInput {
     @location(4) position: vec3<f32>,
     @location(5) normal: vec3<f32>,
+    @location(8) uv: vec2<f32>,
 }
 
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) world_normal: vec3<f32>,
-    @location(1) world_position: vec3<f32>,
-};
-
-@vertex
-fn vs_main(
-    model: VertexInput,
-    instance: InstanceInput,
-) -> VertexOutput {
+fn vertex(model: VertexInput, instance: InstanceInput, tangent: vec4<f32>) -> VertexOutput {
     var out: VertexOutput;
     let model_matrix = mat4x4<f32>(
         instance.v1,
@@ -55,18 +324,24 @@ fn vs_main(
     var world_normal: vec3<f32> = normal_matrix * model.normal;
     out.world_position = world_position.xyz;
     out.world_normal = world_normal;
+    out.world_tangent = vec4<f32>(normal_matrix * tangent.xyz, tangent.w);
+    out.uv = (material.uv_transform * vec3<f32>(model.uv, 1.0)).xy;
+    out.receives_shadow = instance.receives_shadow;
     out.clip_position = globals.view_proj * world_position;
     return out;
 }
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let ambient_color = globals.ambient_color * globals.ambient_strength;
-
-    let light_dir = normalize(locals.diffuse_light_position.xyz - in.world_position);
-    let diffuse_intensity = max(dot(light_dir, in.world_normal), 0.0) * locals.diffuse_light_color.a;
-    let diffuse_color = locals.diffuse_light_color.xyz * diffuse_intensity;
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    return vertex(model, instance, vec4<f32>(0.0, 0.0, 0.0, 0.0));
+}
 
-    let result = (diffuse_intensity + ambient_color.rgb) * vec3<f32>(0.5, 0.5, 0.5);
-    return vec4<f32>(result, 1.0);
+// For primitives with tangents
+@vertex
+fn vs_tangent(
+    model: VertexInput,
+    instance: InstanceInput,
+    @location(10) tangent: vec4<f32>,
+) -> VertexOutput {
+    return vertex(model, instance, tangent);
 }
@@ -0,0 +1,84 @@
+// Vertex stage of pn.wgsl for meshes with morph targets, offsetting each vertex by
+// its weighted deltas
+struct MorphDelta {
+    position: vec4<f32>,
+    normal: vec4<f32>,
+}
+
+struct MorphInfo {
+    vertex_count: u32,
+    target_count: u32,
+}
+
+@group(3) @binding(0)
+var<storage, read> morph_deltas: array<MorphDelta>;
+
+@group(3) @binding(1)
+var<uniform> morph_info: MorphInfo;
+
+struct InstanceInput {
+    @location(0) v1: vec4<f32>,
+    @location(1) v2: vec4<f32>,
+    @location(2) v3: vec4<f32>,
+    @location(3) v4: vec4<f32>,
+    @location(9) receives_shadow: f32,
+    @location(6) w1: vec4<f32>,
+    @location(7) w2: vec4<f32>,
+}
+
+struct VertexInput {
+    @builtin(vertex_index) index: u32,
+    @location(4) position: vec3<f32>,
+    @location(5) normal: vec3<f32>,
+    @location(8) uv: vec2<f32>,
+}
+
+fn vertex(model: VertexInput, instance: InstanceInput, tangent: vec4<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    let model_matrix = mat4x4<f32>(
+        instance.v1,
+        instance.v2,
+        instance.v3,
+        instance.v4,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.v1.xyz,
+        instance.v2.xyz,
+        instance.v3.xyz,
+    );
+    var weights = array<f32, 8>(
+        instance.w1.x, instance.w1.y, instance.w1.z, instance.w1.w,
+        instance.w2.x, instance.w2.y, instance.w2.z, instance.w2.w,
+    );
+    var position = model.position;
+    var normal = model.normal;
+    for (var i = 0u; i < morph_info.target_count; i = i + 1u) {
+        let delta = morph_deltas[i * morph_info.vertex_count + model.index];
+        position = position + weights[i] * delta.position.xyz;
+        normal = normal + weights[i] * delta.normal.xyz;
+    }
+    var world_position: vec4<f32> = model_matrix * vec4<f32>(position, 1.0);
+    var world_normal: vec3<f32> = normal_matrix * normalize(normal);
+    out.world_position = world_position.xyz;
+    out.world_normal = world_normal;
+    out.world_tangent = vec4<f32>(normal_matrix * tangent.xyz, tangent.w);
+    out.uv = (material.uv_transform * vec3<f32>(model.uv, 1.0)).xy;
+    out.receives_shadow = instance.receives_shadow;
+    out.clip_position = globals.view_proj * world_position;
+    return out;
+}
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    return vertex(model, instance, vec4<f32>(0.0, 0.0, 0.0, 0.0));
+}
+
+// For primitives with tangents, which morph targets leave as they are
+@vertex
+fn vs_tangent(
+    model: VertexInput,
+    instance: InstanceInput,
+    @location(10) tangent: vec4<f32>,
+) -> VertexOutput {
+    return vertex(model, instance, tangent);
+}
@@ -0,0 +1,201 @@
+use super::{
+    graph::{AttachmentName, Attachments, Pass, PassContext, BLOOM, BLOOM_BLUR, COLOR},
+    passes::{
+        fullscreen_pipeline, linear_sampler, sources_bind_group, sources_bind_group_layout,
+        uniform_bind_group,
+    },
+};
+
+// Meshes are shaded into a floating point target so lights can be brighter than
+// the surface shows, and the post process chain maps them back
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+pub const TONEMAP_PASS: &str = "Tonemap";
+pub const FXAA_PASS: &str = "FXAA";
+// Copies the tonemapped image to the surface when FXAA is off
+pub const PRESENT_PASS: &str = "Present";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tonemapper {
+    // Clamps colors brighter than white
+    None,
+    Reinhard,
+    Aces,
+}
+impl Tonemapper {
+    pub fn next(self) -> Self {
+        match self {
+            Tonemapper::None => Tonemapper::Reinhard,
+            Tonemapper::Reinhard => Tonemapper::Aces,
+            Tonemapper::Aces => Tonemapper::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostSettings {
+    // Scene colors are multiplied by this before tonemapping
+    pub exposure: f32,
+    pub tonemapper: Tonemapper,
+    pub bloom: bool,
+    // Brightness above which colors bloom, and how softly they start to
+    pub bloom_threshold: f32,
+    pub bloom_knee: f32,
+    pub bloom_intensity: f32,
+    pub fxaa: bool,
+}
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            exposure: 1.0,
+            tonemapper: Tonemapper::Aces,
+            bloom: true,
+            bloom_threshold: 1.0,
+            bloom_knee: 0.5,
+            bloom_intensity: 0.3,
+            fxaa: true,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct PostUniform {
+    // x is the exposure, y the bloom threshold, z the bloom intensity and w the knee
+    pub exposure_bloom: [f32; 4],
+    pub tonemapper: [u32; 4],
+}
+impl PostUniform {
+    pub fn new(settings: &PostSettings) -> Self {
+        let intensity = if settings.bloom {
+            settings.bloom_intensity
+        } else {
+            0.0
+        };
+        let tonemapper = match settings.tonemapper {
+            Tonemapper::None => 0,
+            Tonemapper::Reinhard => 1,
+            Tonemapper::Aces => 2,
+        };
+        Self {
+            exposure_bloom: [
+                settings.exposure,
+                settings.bloom_threshold,
+                intensity,
+                settings.bloom_knee,
+            ],
+            tonemapper: [tonemapper, 0, 0, 0],
+        }
+    }
+}
+
+// Keeps the bright parts of the color attachment at a quarter of its size and
+// blurs them, first across into the scratch attachment and then back down
+pub struct BloomPass {
+    prefilter: wgpu::RenderPipeline,
+    blur_horizontal: wgpu::RenderPipeline,
+    blur_vertical: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    uniform_bind_group: wgpu::BindGroup,
+    // Sampling the color, bloom and scratch attachments
+    bind_groups: Option<[wgpu::BindGroup; 3]>,
+}
+
+impl BloomPass {
+    pub const NAME: &'static str = "Bloom";
+
+    pub fn new(device: &wgpu::Device, post_buffer: &wgpu::Buffer) -> Self {
+        let bind_group_layout = sources_bind_group_layout(device, Self::NAME, 1);
+        let (uniform_layout, uniform_bind_group) =
+            uniform_bind_group(device, Self::NAME, post_buffer);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Bloom shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("bloom.wgsl").into()),
+        });
+        let pipeline = |entry_point| {
+            fullscreen_pipeline(
+                device,
+                Self::NAME,
+                &shader,
+                entry_point,
+                &[&bind_group_layout, &uniform_layout],
+                HDR_FORMAT,
+            )
+        };
+        Self {
+            prefilter: pipeline("fs_prefilter"),
+            blur_horizontal: pipeline("fs_blur_horizontal"),
+            blur_vertical: pipeline("fs_blur_vertical"),
+            bind_group_layout,
+            sampler: linear_sampler(device),
+            uniform_bind_group,
+            bind_groups: None,
+        }
+    }
+}
+
+impl Pass for BloomPass {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+    fn reads(&self) -> Vec<AttachmentName> {
+        vec![COLOR]
+    }
+    fn writes(&self) -> Vec<AttachmentName> {
+        vec![BLOOM, BLOOM_BLUR]
+    }
+    fn attachments_changed(&mut self, device: &wgpu::Device, attachments: &Attachments) {
+        self.bind_groups = Some([COLOR, BLOOM, BLOOM_BLUR].map(|name| {
+            sources_bind_group(
+                device,
+                &self.bind_group_layout,
+                &[&attachments.get(name).view],
+                &self.sampler,
+            )
+        }));
+    }
+    fn execute(&self, context: &PassContext, encoder: &mut wgpu::CommandEncoder) {
+        let [color, bloom, blur] = self.bind_groups.as_ref().unwrap();
+        let steps = [
+            (&self.prefilter, color, BLOOM),
+            (&self.blur_horizontal, bloom, BLOOM_BLUR),
+            (&self.blur_vertical, blur, BLOOM),
+        ];
+        for (pipeline, source, target) in steps {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(Self::NAME),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: context.view(target),
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, source, &[]);
+            render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_bloom_adds_nothing() {
+        let settings = PostSettings {
+            bloom: false,
+            tonemapper: Tonemapper::Reinhard,
+            ..Default::default()
+        };
+        let uniform = PostUniform::new(&settings);
+        assert_eq!(uniform.exposure_bloom, [1.0, 1.0, 0.0, 0.5]);
+        assert_eq!(uniform.tonemapper, [1, 0, 0, 0]);
+    }
+}
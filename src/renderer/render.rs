@@ -1,12 +1,37 @@
-use std::collections::HashMap;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
+use cgmath::{EuclideanSpace, InnerSpace};
 use wgpu::util::DeviceExt;
 
-use crate::{loaders::gltf::{GltfFile, DrawGltf, GltfFrameState}, window::Window};
+use crate::{
+    components::light::Light,
+    mesh::{MeshBatch, MeshFrameState},
+    mesh_processing::Aabb,
+    window::Window,
+};
 
 use super::{
+    buffer_arena::BufferArena,
+    capture::{CaptureSettings, FrameCapture},
+    environment::{Environment, EnvironmentBaker, EnvironmentImage},
+    graph::{
+        AttachmentDescriptor, AttachmentSize, FrameData, RenderGraph, BLOOM, BLOOM_BLUR, COLOR,
+        DEPTH, LDR, MSAA_COLOR, SHADOW_MAP, SURFACE,
+    },
+    lights::{FrameLights, LightSettings, LightingUniform},
+    passes::{
+        DepthOverlayPass, FullscreenPass, OpaquePass, ShadowPass, SkyboxPass, TransparentPass,
+    },
+    pipeline_cache::{MeshPipelineKey, PipelineCache},
     pipeline_default::DefaultPipeline,
-    Globals, Locals,
+    post::{
+        BloomPass, PostSettings, PostUniform, FXAA_PASS, HDR_FORMAT, PRESENT_PASS, TONEMAP_PASS,
+    },
+    shadow::{ShadowSettings, ShadowUniform, CASCADES, SHADOW_MAP_FORMAT, SHADOW_MAP_SIZE},
+    FrameStats, Globals, ViewFrustum,
 };
 
 pub struct Renderer {
@@ -15,8 +40,33 @@ pub struct Renderer {
     surface: wgpu::Surface,
     pub surface_config: wgpu::SurfaceConfiguration,
     pub default_pipeline: DefaultPipeline,
-    depth_texture: wgpu::Texture,
-    depth_texture_view: wgpu::TextureView,
+    pipeline_cache: PipelineCache,
+    // Sample counts the color and depth attachments can be multisampled with
+    sample_counts: Vec<u32>,
+    graph: RenderGraph,
+    globals_buffer: wgpu::Buffer,
+    pub(super) globals_bind_group: wgpu::BindGroup,
+    environment_baker: EnvironmentBaker,
+    brdf_lut: wgpu::TextureView,
+    // The sky and the light it casts on meshes, none leaves both black
+    environment: Option<Environment>,
+    lighting_buffer: wgpu::Buffer,
+    shadow_buffer: wgpu::Buffer,
+    shadow_sampler: wgpu::Sampler,
+    // The lights of the frame being drawn and the clusters listing them
+    lights_arena: RefCell<BufferArena>,
+    clusters_arena: RefCell<BufferArena>,
+    // Lights and shadows, created again whenever the light arenas grow
+    lighting_bind_group: RefCell<Option<wgpu::BindGroup>>,
+    pub light_settings: LightSettings,
+    pub shadow_settings: ShadowSettings,
+    post_buffer: wgpu::Buffer,
+    post_settings: PostSettings,
+    // Instance transforms and morph weights of the frame being drawn
+    instance_arena: RefCell<BufferArena>,
+    stats: Cell<FrameStats>,
+    // Writes the frames being drawn to files until it has written as many as asked
+    capture: RefCell<Option<FrameCapture>>,
 }
 
 impl Renderer {
@@ -37,10 +87,13 @@ impl Renderer {
             .await
             .unwrap();
 
+        // Without adapter specific format features only 4 samples are allowed
+        let features =
+            adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES;
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    features,
                     limits: if cfg!(target_arch = "wasm32") {
                         wgpu::Limits::downlevel_webgl2_defaults()
                     } else {
@@ -72,128 +125,612 @@ impl Renderer {
             view_formats: vec![],
         };
         surface.configure(&device, &surface_config);
+        let sample_counts = supported_sample_counts(|format| {
+            if features.is_empty() {
+                format.describe().guaranteed_format_features
+            } else {
+                adapter.get_texture_format_features(format)
+            }
+        });
 
         let default_pipeline = DefaultPipeline::new(&device);
 
-        let (depth_texture, depth_texture_view) =
-            Renderer::init_depth_texture(&device, &surface_config);
+        let uniform_buffer = |label, size| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: size as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let globals_buffer = uniform_buffer("Globals buffer", std::mem::size_of::<Globals>());
+        let environment_baker = EnvironmentBaker::new(&device);
+        let brdf_lut = environment_baker.bake_brdf_lut(&device, &queue);
+        let globals_bind_group = globals_bind_group(
+            &device,
+            &default_pipeline,
+            &globals_buffer,
+            &Environment::black(&device),
+            &brdf_lut,
+            &environment_baker.sampler,
+        );
+        let lighting_buffer =
+            uniform_buffer("Lighting buffer", std::mem::size_of::<LightingUniform>());
+        let shadow_buffer = uniform_buffer("Shadow buffer", std::mem::size_of::<ShadowUniform>());
+
+        let post_buffer = uniform_buffer("Post buffer", std::mem::size_of::<PostUniform>());
 
-        Renderer {
+        let graph = Renderer::default_graph(
+            &device,
+            &surface_config,
+            &default_pipeline,
+            &shadow_buffer,
+            &post_buffer,
+        );
+
+        // Hardware filtering compares four texels, the shader filters further
+        let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let mut renderer = Renderer {
             default_pipeline,
+            pipeline_cache: PipelineCache::default(),
             queue,
             device,
             surface,
             surface_config,
-            depth_texture,
-            depth_texture_view,
-        }
+            sample_counts,
+            graph,
+            globals_buffer,
+            globals_bind_group,
+            environment_baker,
+            brdf_lut,
+            environment: None,
+            lighting_buffer,
+            shadow_buffer,
+            shadow_sampler,
+            lights_arena: RefCell::new(BufferArena::new(
+                "Lights arena",
+                wgpu::BufferUsages::STORAGE,
+            )),
+            clusters_arena: RefCell::new(BufferArena::new(
+                "Light clusters arena",
+                wgpu::BufferUsages::STORAGE,
+            )),
+            lighting_bind_group: RefCell::new(None),
+            light_settings: LightSettings::default(),
+            shadow_settings: ShadowSettings::default(),
+            post_buffer,
+            post_settings: PostSettings::default(),
+            instance_arena: RefCell::new(BufferArena::new(
+                "Instance arena",
+                wgpu::BufferUsages::VERTEX,
+            )),
+            stats: Cell::new(FrameStats::default()),
+            capture: RefCell::new(None),
+        };
+        renderer.set_post_settings(PostSettings::default());
+        renderer
+    }
+
+    // Creates a buffer, counting it in the frame stats
+    pub fn create_buffer_init(
+        &self,
+        descriptor: &wgpu::util::BufferInitDescriptor,
+    ) -> wgpu::Buffer {
+        self.count_allocation();
+        self.device.create_buffer_init(descriptor)
     }
-    fn init_depth_texture(
+    fn count_allocation(&self) {
+        let mut stats = self.stats.get();
+        stats.buffer_allocations += 1;
+        self.stats.set(stats);
+    }
+    pub fn frame_stats(&self) -> FrameStats {
+        self.stats.get()
+    }
+    // Meshes are drawn to an HDR color attachment, which the post process chain
+    // tonemaps and copies to the surface where debug overlays go. The shadow map has
+    // a fixed size since the locals bind group samples it.
+    fn default_graph(
         device: &wgpu::Device,
         surface_config: &wgpu::SurfaceConfiguration,
-    ) -> (wgpu::Texture, wgpu::TextureView) {
-        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Depth texture"),
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Depth32Float,
-            mip_level_count: 1,
-            sample_count: 1,
-            size: wgpu::Extent3d {
-                width: surface_config.width,
-                height: surface_config.height,
-                depth_or_array_layers: 1,
+        default_pipeline: &DefaultPipeline,
+        shadow_buffer: &wgpu::Buffer,
+        post_buffer: &wgpu::Buffer,
+    ) -> RenderGraph {
+        let mut graph = RenderGraph::new((surface_config.width, surface_config.height));
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING;
+        graph.add_attachment(
+            device,
+            COLOR,
+            AttachmentDescriptor {
+                format: HDR_FORMAT,
+                size: AttachmentSize::Surface,
+                layers: 1,
+                samples: 1,
+                usage,
             },
-            usage: wgpu::TextureUsages::TEXTURE_BINDING
-                | wgpu::TextureUsages::RENDER_ATTACHMENT
-                | wgpu::TextureUsages::COPY_SRC,
-            view_formats: &[],
-        });
+        );
+        for bloom in [BLOOM, BLOOM_BLUR] {
+            graph.add_attachment(
+                device,
+                bloom,
+                AttachmentDescriptor {
+                    format: HDR_FORMAT,
+                    size: AttachmentSize::Scaled(4),
+                    layers: 1,
+                    samples: 1,
+                    usage,
+                },
+            );
+        }
+        graph.add_attachment(
+            device,
+            LDR,
+            AttachmentDescriptor {
+                format: surface_config.format,
+                size: AttachmentSize::Surface,
+                layers: 1,
+                samples: 1,
+                usage,
+            },
+        );
+        graph.add_attachment(
+            device,
+            DEPTH,
+            AttachmentDescriptor {
+                format: wgpu::TextureFormat::Depth32Float,
+                size: AttachmentSize::Surface,
+                layers: 1,
+                samples: 1,
+                usage,
+            },
+        );
+        graph.add_attachment(
+            device,
+            SHADOW_MAP,
+            AttachmentDescriptor {
+                format: SHADOW_MAP_FORMAT,
+                size: AttachmentSize::Fixed(SHADOW_MAP_SIZE, SHADOW_MAP_SIZE),
+                layers: CASCADES as u32,
+                samples: 1,
+                usage,
+            },
+        );
+        graph.add_pass(device, ShadowPass::new(device, default_pipeline, shadow_buffer));
+        graph.add_pass(
+            device,
+            OpaquePass {
+                clear_color: wgpu::Color::BLACK,
+            },
+        );
+        graph.add_pass(device, SkyboxPass::new(device, default_pipeline));
+        graph.set_enabled(SkyboxPass::NAME, false);
+        graph.add_pass(device, TransparentPass);
+        graph.add_pass(device, BloomPass::new(device, post_buffer));
+        graph.add_pass(
+            device,
+            FullscreenPass::new(
+                device,
+                TONEMAP_PASS,
+                include_str!("tonemap.wgsl"),
+                &[COLOR, BLOOM],
+                LDR,
+                surface_config.format,
+                Some(post_buffer),
+            ),
+        );
+        graph.add_pass(
+            device,
+            FullscreenPass::new(
+                device,
+                FXAA_PASS,
+                include_str!("fxaa.wgsl"),
+                &[LDR],
+                SURFACE,
+                surface_config.format,
+                None,
+            ),
+        );
+        graph.add_pass(
+            device,
+            FullscreenPass::new(
+                device,
+                PRESENT_PASS,
+                include_str!("blit.wgsl"),
+                &[LDR],
+                SURFACE,
+                surface_config.format,
+                None,
+            ),
+        );
+        graph.add_pass(device, DepthOverlayPass::new(surface_config.format));
+        graph.set_enabled(DepthOverlayPass::NAME, false);
+        graph
+    }
+
+    // Configures the surface for the window's new size and creates the attachments
+    // sized after it again. Minimized windows have no size and are left as they were.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.surface_config.width = width;
+        self.surface_config.height = height;
+        self.surface.configure(&self.device, &self.surface_config);
+        self.graph.resize(&self.device, (width, height));
+    }
 
-        let depth_texture_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        (depth_texture, depth_texture_view)
+    // Format of the attachment meshes are drawn to
+    pub fn color_format(&self) -> wgpu::TextureFormat {
+        self.graph.attachments().get(COLOR).descriptor.format
     }
 
-    pub fn draw(&self, gltfs: &mut Vec<GltfFrameState>, view_proj: cgmath::Matrix4<f32>) {
-        let output = self.surface.get_current_texture().unwrap();
+    pub fn sample_count(&self) -> u32 {
+        self.default_pipeline.multisample.count
+    }
+    pub fn supported_sample_counts(&self) -> &[u32] {
+        &self.sample_counts
+    }
+    // Multisamples the meshes with the highest supported count up to the one asked
+    // for. Pipelines for the new count are created the next time meshes are drawn.
+    pub fn set_sample_count(&mut self, count: u32) {
+        let count = self
+            .sample_counts
+            .iter()
+            .copied()
+            .filter(|&supported| supported <= count)
+            .max()
+            .unwrap_or(1);
+        self.default_pipeline.multisample.count = count;
+        let depth = self.graph.attachments().get(DEPTH).descriptor;
+        self.graph.add_attachment(
+            &self.device,
+            DEPTH,
+            AttachmentDescriptor {
+                samples: count,
+                ..depth
+            },
+        );
+        if count > 1 {
+            let color = self.graph.attachments().get(COLOR).descriptor;
+            self.graph.add_attachment(
+                &self.device,
+                MSAA_COLOR,
+                AttachmentDescriptor {
+                    samples: count,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                    ..color
+                },
+            );
+        } else {
+            self.graph.remove_attachment(&self.device, MSAA_COLOR);
+        }
+    }
+
+    // The mesh pipeline for the key, shared by every asset drawing with it
+    pub fn mesh_pipeline(&self, key: MeshPipelineKey) -> Rc<wgpu::RenderPipeline> {
+        self.pipeline_cache.get_or_create(key, |key| {
+            self.default_pipeline.create_mesh_pipeline(&self.device, key)
+        })
+    }
+    pub fn cached_pipelines(&self) -> usize {
+        self.pipeline_cache.len()
+    }
+
+    pub fn post_settings(&self) -> PostSettings {
+        self.post_settings
+    }
+    // Updates the post process uniform and enables the passes the settings use
+    pub fn set_post_settings(&mut self, settings: PostSettings) {
+        self.post_settings = settings;
+        self.queue.write_buffer(
+            &self.post_buffer,
+            0,
+            bytemuck::cast_slice(&[PostUniform::new(&settings)]),
+        );
+        self.graph.set_enabled(BloomPass::NAME, settings.bloom);
+        self.graph.set_enabled(FXAA_PASS, settings.fxaa);
+        self.graph.set_enabled(PRESENT_PASS, !settings.fxaa);
+    }
+
+    // Bakes the image into the skybox and the maps meshes are lit by, or goes back
+    // to a black background and no environment light
+    pub fn set_environment(&mut self, image: Option<&EnvironmentImage>) {
+        self.environment =
+            image.map(|image| self.environment_baker.bake(&self.device, &self.queue, image));
+        let black;
+        let environment = match &self.environment {
+            Some(environment) => environment,
+            None => {
+                black = Environment::black(&self.device);
+                &black
+            }
+        };
+        self.globals_bind_group = globals_bind_group(
+            &self.device,
+            &self.default_pipeline,
+            &self.globals_buffer,
+            environment,
+            &self.brdf_lut,
+            &self.environment_baker.sampler,
+        );
+        self.graph.set_enabled(SkyboxPass::NAME, self.environment.is_some());
+    }
+
+    // Writes the next frames drawn to PNG files, replacing any capture in progress
+    pub fn capture_frames(&mut self, settings: CaptureSettings) {
+        *self.capture.get_mut() = Some(FrameCapture::new(&self.device, settings));
+    }
+    pub fn is_capturing(&self) -> bool {
+        self.capture.borrow().is_some()
+    }
+
+    pub fn graph(&self) -> &RenderGraph {
+        &self.graph
+    }
+    pub fn graph_mut(&mut self) -> &mut RenderGraph {
+        &mut self.graph
+    }
+
+    // Stages the lights in their arenas, returning the bytes written. The bind group
+    // over the arenas is created again when one of them grows.
+    fn write_lights(&self, frame_lights: &FrameLights) -> u64 {
+        let mut lights_arena = self.lights_arena.borrow_mut();
+        let mut clusters_arena = self.clusters_arena.borrow_mut();
+        lights_arena.clear();
+        lights_arena.push(bytemuck::cast_slice(&frame_lights.lights));
+        clusters_arena.clear();
+        clusters_arena.push(bytemuck::cast_slice(&frame_lights.clusters));
+        let lights_grown = lights_arena.upload(&self.device, &self.queue);
+        let clusters_grown = clusters_arena.upload(&self.device, &self.queue);
+        for grown in [lights_grown, clusters_grown] {
+            if grown {
+                self.count_allocation();
+            }
+        }
+        let mut bind_group = self.lighting_bind_group.borrow_mut();
+        if lights_grown || clusters_grown || bind_group.is_none() {
+            let lights_buffer = lights_arena.buffer().expect("Lights uploaded without data");
+            let clusters_buffer = clusters_arena.buffer().expect("Clusters uploaded without data");
+            *bind_group = Some(self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Lighting bind group"),
+                layout: &self.default_pipeline.locals_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.lighting_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: self.shadow_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(
+                            &self.graph.attachments().get(SHADOW_MAP).view,
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&self.shadow_sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: lights_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: clusters_buffer.as_entire_binding(),
+                    },
+                ],
+            }));
+        }
+        lights_arena.len() + clusters_arena.len()
+    }
+
+    pub fn draw(
+        &self,
+        meshes: &[MeshFrameState],
+        view_proj: cgmath::Matrix4<f32>,
+        lights: &[(Light, cgmath::Matrix4<f32>)],
+    ) {
+        let output = match self.surface.get_current_texture() {
+            Ok(output) => output,
+            // The frame is skipped once the surface is configured again
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                self.surface.configure(&self.device, &self.surface_config);
+                return;
+            }
+            Err(error) => panic!("Unable to get the next surface texture: {}", error),
+        };
         let view = output
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
+        self.stats.set(FrameStats::default());
+        let frustum = ViewFrustum::new(view_proj);
+        let light_settings = &self.light_settings;
         let globals = Globals {
             view_proj: view_proj.into(),
-            ambient_color: [1.0, 1.0, 1.0, 1.0],
-            ambient_strength: [0.2, 0.0, 0.0, 0.0],
+            ambient_color: light_settings.ambient_color.extend(1.0).into(),
+            ambient_strength: [light_settings.ambient_strength, 0.0, 0.0, 0.0],
+            camera_position: frustum.eye.extend(1.0).into(),
+            inverse_view_proj: frustum.inverse.into(),
+            environment: match &self.environment {
+                Some(environment) => [
+                    light_settings.environment_intensity,
+                    (environment.prefiltered_mips - 1) as f32,
+                    0.0,
+                    0.0,
+                ],
+                None => [0.0; 4],
+            },
         };
-        let globals_buffer = self
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Globals buffer"),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-                contents: bytemuck::cast_slice(&[globals]),
-            });
-        let globals_bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Locals Bind group"),
-            layout: &self.default_pipeline.globals_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: globals_buffer.as_entire_binding(),
-            }],
-        });
-        let locals = Locals {
-            diffuse_light_color: [1.0, 1.0, 1.0, 1.0],
-            diffuse_light_pos: [30.0, 100.0, 0.0, 0.0],
+        let surface_size = (self.surface_config.width, self.surface_config.height);
+        let frame_lights = FrameLights::new(lights, &frustum, surface_size, light_settings);
+        // The first directional light casts shadows
+        let shadow = match frame_lights.shadow_direction {
+            Some(direction) if self.shadow_settings.enabled => {
+                ShadowUniform::new(&frustum, direction, &self.shadow_settings)
+            }
+            _ => ShadowUniform::disabled(),
         };
-        let locals_buffer = self
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Locals buffer"),
-                contents: bytemuck::cast_slice(&[locals]),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        let shadows = shadow.params[0] > 0.0;
+        self.queue.write_buffer(&self.globals_buffer, 0, bytemuck::cast_slice(&[globals]));
+        self.queue.write_buffer(
+            &self.lighting_buffer,
+            0,
+            bytemuck::cast_slice(&[frame_lights.uniform]),
+        );
+        self.queue.write_buffer(&self.shadow_buffer, 0, bytemuck::cast_slice(&[shadow]));
+        let light_bytes = self.write_lights(&frame_lights);
+
+        // Entities sharing an asset are drawn together, and every instance is staged
+        // first so the arena is uploaded with one write
+        let mut batches = MeshBatch::from_frame_states(meshes);
+        // Casters outside the view still shade it, so they are kept while their
+        // bounds swept along the light reach it
+        let shadow_sweep = frame_lights
+            .shadow_direction
+            .filter(|_| shadows)
+            .map(|direction| direction.normalize() * self.shadow_settings.caster_distance);
+        let (mut drawn_objects, mut culled_objects) = (0, 0);
+        for batch in batches.iter_mut() {
+            let sweep = shadow_sweep.filter(|_| batch.casts_shadow);
+            let (drawn, culled) = batch.cull(|aabb| match sweep {
+                Some(sweep) => frustum.intersects(&aabb.union(&Aabb {
+                    min: aabb.min + sweep,
+                    max: aabb.max + sweep,
+                })),
+                None => frustum.intersects(aabb),
             });
-        let locals_bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Locals Bind group"),
-            layout: &self.default_pipeline.locals_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: locals_buffer.as_entire_binding(),
-            }],
-        });
+            drawn_objects += drawn as u32;
+            culled_objects += culled as u32;
+        }
+        let instance_bytes = {
+            let mut arena = self.instance_arena.borrow_mut();
+            arena.clear();
+            for batch in batches.iter_mut() {
+                batch.write_instances(self, &mut arena);
+            }
+            if arena.upload(&self.device, &self.queue) {
+                self.count_allocation();
+            }
+            arena.len()
+        };
+        let mut stats = self.stats.get();
+        let uniform_bytes = std::mem::size_of::<Globals>()
+            + std::mem::size_of::<LightingUniform>()
+            + std::mem::size_of::<ShadowUniform>();
+        stats.bytes_written = uniform_bytes as u64 + light_bytes + instance_bytes;
+        stats.drawn_objects = drawn_objects;
+        stats.culled_objects = culled_objects;
+        self.stats.set(stats);
+        let transparent =
+            MeshBatch::transparent_draws(&batches, cgmath::Point3::from_vec(frustum.eye));
+        let arena = self.instance_arena.borrow();
+        let lighting_bind_group = self.lighting_bind_group.borrow();
+
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Pass Encoder"),
             });
-        for frame_state in gltfs.iter_mut() {
-            frame_state.init_buffers(&self.device);
+        let frame = FrameData {
+            batches: &batches,
+            transparent: &transparent,
+            instance_buffer: arena.buffer(),
+            lighting: lighting_bind_group
+                .as_ref()
+                .expect("Lighting bind group missing after writing lights"),
+            shadows,
+        };
+        self.graph.execute(self, &view, &frame, &mut encoder);
+        let mut capture = self.capture.borrow_mut();
+        let readbacks = capture.as_mut().map(|capture| {
+            capture.encode(
+                &self.device,
+                &mut encoder,
+                self.graph.attachments(),
+                self.post_settings.fxaa,
+            )
+        });
+        self.queue.submit(std::iter::once(encoder.finish()));
+        output.present();
+        if let (Some(frame_capture), Some(readbacks)) = (capture.as_mut(), readbacks) {
+            let saved = frame_capture.save(&self.device, readbacks);
+            if let Err(error) = &saved {
+                log::error!("Stopped capturing frames: {:#}", error);
+            }
+            if saved.is_err() || frame_capture.is_finished() {
+                *capture = None;
+            }
         }
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
+    }
+}
 
-            render_pass.set_bind_group(0, &globals_bind_group, &[]);
-            render_pass.set_bind_group(1, &locals_bind_group, &[]);
+// Sample counts supported by both the color and depth attachments meshes are drawn to
+fn supported_sample_counts(
+    format_features: impl Fn(wgpu::TextureFormat) -> wgpu::TextureFormatFeatures,
+) -> Vec<u32> {
+    let color = format_features(HDR_FORMAT).flags;
+    let depth = format_features(wgpu::TextureFormat::Depth32Float).flags;
+    [1, 2, 4, 8]
+        .into_iter()
+        .filter(|&count| {
+            let resolves = count == 1
+                || color.contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE);
+            resolves && color.sample_count_supported(count) && depth.sample_count_supported(count)
+        })
+        .collect()
+}
 
-            for gltf in gltfs.iter() {
-                render_pass.draw_gltf(gltf);
-            }
-        }
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+// The globals uniform along with the environment maps and the sampler they share
+fn globals_bind_group(
+    device: &wgpu::Device,
+    default_pipeline: &DefaultPipeline,
+    globals_buffer: &wgpu::Buffer,
+    environment: &Environment,
+    brdf_lut: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    let view = |binding, view| wgpu::BindGroupEntry {
+        binding,
+        resource: wgpu::BindingResource::TextureView(view),
+    };
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Globals bind group"),
+        layout: &default_pipeline.globals_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: globals_buffer.as_entire_binding(),
+            },
+            view(1, &environment.skybox),
+            view(2, &environment.irradiance),
+            view(3, &environment.prefiltered),
+            view(4, brdf_lut),
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guaranteed_formats_multisample_four_times() {
+        let counts = supported_sample_counts(|format| format.describe().guaranteed_format_features);
+        assert_eq!(counts, vec![1, 4]);
     }
 }
@@ -0,0 +1,174 @@
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3};
+
+use crate::systems::camera::OPENGL_TO_WGPU_MATRIX;
+
+use super::ViewFrustum;
+
+// Number of layers in the shadow map, also hardcoded in the shaders
+pub const CASCADES: usize = 3;
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+pub const SHADOW_MAP_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    // Shadows end this far from the camera, or at its far plane when closer
+    pub max_distance: f32,
+    // Blends cascade splits between uniform (0) and logarithmic (1) spacing
+    pub split_lambda: f32,
+    // How far behind each cascade casters are still drawn, for tall objects
+    // outside the view that throw shadows into it
+    pub caster_distance: f32,
+    // Subtracted from the depth of the receiver
+    pub depth_bias: f32,
+    // Moves receivers along their normal, in shadow map texels
+    pub normal_bias: f32,
+}
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_distance: 100.0,
+            split_lambda: 0.6,
+            caster_distance: 50.0,
+            depth_bias: 0.0005,
+            normal_bias: 1.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct ShadowUniform {
+    pub cascades: [[[f32; 4]; 4]; CASCADES],
+    // Distance along the view direction where each cascade ends
+    pub splits: [f32; 4],
+    // World size of one shadow map texel in each cascade
+    pub texel_sizes: [f32; 4],
+    pub camera_forward: [f32; 4],
+    // x is 1 when shadows are drawn, y is the depth bias and z the normal bias
+    pub params: [f32; 4],
+}
+impl ShadowUniform {
+    pub fn disabled() -> Self {
+        bytemuck::Zeroable::zeroed()
+    }
+
+    // Splits the view frustum into slices and fits one light projection around
+    // each. Projections cover the bounding sphere of their slice and move in whole
+    // texels, so shadows do not shimmer as the camera turns and moves.
+    pub fn new(
+        frustum: &ViewFrustum,
+        light_direction: Vector3<f32>,
+        settings: &ShadowSettings,
+    ) -> Self {
+        // Frustum edges as points on the near and far planes
+        let edges = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .map(|(x, y)| (frustum.unproject(x, y, 0.0), frustum.unproject(x, y, 1.0)));
+        let (near, far) = (frustum.near, frustum.far);
+        let splits = cascade_splits(near, far.min(settings.max_distance), settings.split_lambda);
+        let slice = |distance: f32| {
+            let t = (distance - near) / (far - near);
+            edges.map(|(near_point, far_point)| near_point + (far_point - near_point) * t)
+        };
+
+        let light_direction = light_direction.normalize();
+        let up = if light_direction.y.abs() > 0.99 {
+            Vector3::unit_z()
+        } else {
+            Vector3::unit_y()
+        };
+        let light_view = Matrix4::look_to_rh(Point3::new(0.0, 0.0, 0.0), light_direction, up);
+
+        let mut uniform = Self::disabled();
+        let mut start = near;
+        for (cascade, &end) in splits.iter().enumerate() {
+            let corners = [slice(start), slice(end)].concat();
+            let center = corners.iter().sum::<Vector3<f32>>() / corners.len() as f32;
+            let radius = corners
+                .iter()
+                .map(|corner| (corner - center).magnitude())
+                .fold(0.0, f32::max);
+            // Rounded so that the projection keeps its size while the camera turns
+            let radius = (radius * 16.0).ceil() / 16.0;
+            let texel_size = 2.0 * radius / SHADOW_MAP_SIZE as f32;
+            let center = (light_view * center.extend(1.0)).truncate();
+            let snap = |value: f32| (value / texel_size).floor() * texel_size;
+            let (x, y) = (snap(center.x), snap(center.y));
+            // The light looks down -z, so depths are negated
+            let projection = cgmath::ortho(
+                x - radius,
+                x + radius,
+                y - radius,
+                y + radius,
+                -center.z - radius - settings.caster_distance,
+                -center.z + radius,
+            );
+            uniform.cascades[cascade] = (OPENGL_TO_WGPU_MATRIX * projection * light_view).into();
+            uniform.splits[cascade] = end;
+            uniform.texel_sizes[cascade] = texel_size;
+            start = end;
+        }
+        uniform.camera_forward = frustum.forward.extend(0.0).into();
+        uniform.params = [1.0, settings.depth_bias, settings.normal_bias, 0.0];
+        uniform
+    }
+}
+
+// Where each cascade ends, mixing logarithmic splits, which suit perspective
+// resolution, with uniform splits that keep distant cascades from growing too large
+fn cascade_splits(near: f32, far: f32, lambda: f32) -> [f32; CASCADES] {
+    let mut splits = [0.0; CASCADES];
+    for (cascade, split) in splits.iter_mut().enumerate() {
+        let fraction = (cascade + 1) as f32 / CASCADES as f32;
+        let logarithmic = near * (far / near).powf(fraction);
+        let uniform = near + (far - near) * fraction;
+        *split = lambda * logarithmic + (1.0 - lambda) * uniform;
+    }
+    splits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::camera::Camera;
+    use cgmath::{SquareMatrix, Vector4};
+
+    #[test]
+    fn splits_grow_up_to_the_far_plane() {
+        let splits = cascade_splits(0.1, 100.0, 0.6);
+        assert!(splits.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((splits[CASCADES - 1] - 100.0).abs() < 1e-3);
+        assert!(splits[0] < 100.0 / CASCADES as f32);
+    }
+
+    #[test]
+    fn every_cascade_contains_its_slice_of_the_view() {
+        let camera = Camera::new(16.0 / 9.0);
+        let view_proj = camera.build_view_projection_matrix();
+        let settings = ShadowSettings::default();
+        let frustum = ViewFrustum::new(view_proj);
+        let uniform = ShadowUniform::new(&frustum, Vector3::new(-0.3, -1.0, 0.2), &settings);
+        let inverse = view_proj.invert().unwrap();
+        let eye = camera.get_position();
+        let forward = Vector3::new(0.0, -10.0, -10.0).normalize();
+        let mut start = 0.1;
+        for cascade in 0..CASCADES {
+            let matrix = Matrix4::from(uniform.cascades[cascade]);
+            let end = uniform.splits[cascade];
+            // Points on the frustum edges half way through the slice
+            for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                let far = inverse * Vector4::new(x, y, 1.0, 1.0);
+                let ray = (far.truncate() / far.w) - Vector3::new(eye.x, eye.y, eye.z);
+                let distance = (start + end) / 2.0;
+                let point = eye + ray * (distance / ray.dot(forward));
+                let clip = matrix * point.to_homogeneous();
+                for coordinate in [clip.x, clip.y] {
+                    assert!(coordinate.abs() <= 1.0, "cascade {}: {:?}", cascade, clip);
+                }
+                assert!((0.0..=1.0).contains(&clip.z), "cascade {}: {:?}", cascade, clip);
+            }
+            start = end;
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// Depth only pass drawing shadow casters into one cascade of the shadow map
+struct Shadows {
+    cascades: array<mat4x4<f32>, 3>,
+    splits: vec4<f32>,
+    texel_sizes: vec4<f32>,
+    camera_forward: vec4<f32>,
+    params: vec4<f32>,
+}
+
+struct Cascade {
+    // x is the layer being drawn
+    index: vec4<u32>,
+}
+
+struct MorphDelta {
+    position: vec4<f32>,
+    normal: vec4<f32>,
+}
+
+struct MorphInfo {
+    vertex_count: u32,
+    target_count: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> shadows: Shadows;
+
+@group(0) @binding(1)
+var<uniform> cascade: Cascade;
+
+@group(1) @binding(0)
+var<storage, read> morph_deltas: array<MorphDelta>;
+
+@group(1) @binding(1)
+var<uniform> morph_info: MorphInfo;
+
+struct InstanceInput {
+    @location(0) v1: vec4<f32>,
+    @location(1) v2: vec4<f32>,
+    @location(2) v3: vec4<f32>,
+    @location(3) v4: vec4<f32>,
+}
+
+struct MorphInput {
+    @location(6) w1: vec4<f32>,
+    @location(7) w2: vec4<f32>,
+}
+
+fn project(instance: InstanceInput, position: vec3<f32>) -> vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.v1,
+        instance.v2,
+        instance.v3,
+        instance.v4,
+    );
+    return shadows.cascades[cascade.index.x] * model_matrix * vec4<f32>(position, 1.0);
+}
+
+@vertex
+fn vs_main(
+    @location(4) position: vec3<f32>,
+    instance: InstanceInput,
+) -> @builtin(position) vec4<f32> {
+    return project(instance, position);
+}
+
+@vertex
+fn vs_morph(
+    @builtin(vertex_index) index: u32,
+    @location(4) position: vec3<f32>,
+    instance: InstanceInput,
+    morph: MorphInput,
+) -> @builtin(position) vec4<f32> {
+    var weights = array<f32, 8>(
+        morph.w1.x, morph.w1.y, morph.w1.z, morph.w1.w,
+        morph.w2.x, morph.w2.y, morph.w2.z, morph.w2.w,
+    );
+    var morphed = position;
+    for (var i = 0u; i < morph_info.target_count; i = i + 1u) {
+        morphed = morphed + weights[i] * morph_deltas[i * morph_info.vertex_count + index].position.xyz;
+    }
+    return project(instance, morphed);
+}
@@ -0,0 +1,40 @@
+// Draws the environment cubemap wherever the depth attachment is still cleared
+struct Globals {
+    view_proj: mat4x4<f32>,
+    ambient_color: vec4<f32>,
+    ambient_strength: f32,
+    camera_position: vec4<f32>,
+    inverse_view_proj: mat4x4<f32>,
+    // x scales the environment's light, zero without one
+    environment: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> globals: Globals;
+@group(0) @binding(1)
+var skybox: texture_cube<f32>;
+@group(0) @binding(5)
+var environment_sampler: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+// A single triangle covering the target on the far plane
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.ndc = vec2<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    out.clip_position = vec4<f32>(out.ndc, 1.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let far = globals.inverse_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let direction = far.xyz / far.w - globals.camera_position.xyz;
+    let color = textureSampleLevel(skybox, environment_sampler, direction, 0.0);
+    return vec4<f32>(color.rgb, 1.0);
+}
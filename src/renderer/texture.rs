@@ -0,0 +1,81 @@
+pub struct Texture {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+}
+
+impl Texture {
+    // Pixels are tightly packed rgba rows, in an sRGB format for colors and a linear
+    // one for data such as normal maps
+    pub fn from_rgba8(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        label: Option<&str>,
+        (width, height): (u32, u32),
+        pixels: &[u8],
+        format: wgpu::TextureFormat,
+        sampler: &wgpu::SamplerDescriptor,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            pixels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(4 * width),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+            size,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(sampler);
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+    // Bound in place of a missing texture so materials can always sample one
+    pub fn white(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        Self::from_rgba8(
+            device,
+            queue,
+            Some("White texture"),
+            (1, 1),
+            &[255, 255, 255, 255],
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            &wgpu::SamplerDescriptor::default(),
+        )
+    }
+    // Bound in place of a missing normal map, pointing straight out of the surface
+    pub fn flat_normal(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        Self::from_rgba8(
+            device,
+            queue,
+            Some("Flat normal texture"),
+            (1, 1),
+            &[128, 128, 255, 255],
+            wgpu::TextureFormat::Rgba8Unorm,
+            &wgpu::SamplerDescriptor::default(),
+        )
+    }
+}
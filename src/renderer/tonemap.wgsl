@@ -0,0 +1,57 @@
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.uv = uv;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+struct Post {
+    // x is the exposure, y the bloom threshold, z the bloom intensity and w the
+    // softness of the threshold
+    exposure_bloom: vec4<f32>,
+    // x selects the tonemapper: 0 clamps, 1 is Reinhard and 2 is ACES
+    tonemapper: vec4<u32>,
+}
+
+@group(0) @binding(0)
+var scene: texture_2d<f32>;
+@group(0) @binding(1)
+var bloom: texture_2d<f32>;
+@group(0) @binding(2)
+var source_sampler: sampler;
+
+@group(1) @binding(0)
+var<uniform> post: Post;
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    let mapped = (color * (a * color + b)) / (color * (c * color + d) + e);
+    return clamp(mapped, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let hdr = textureSample(scene, source_sampler, in.uv);
+    let glow = textureSample(bloom, source_sampler, in.uv).rgb * post.exposure_bloom.z;
+    let color = (hdr.rgb + glow) * post.exposure_bloom.x;
+    var mapped = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+    if (post.tonemapper.x == 1u) {
+        mapped = color / (color + 1.0);
+    } else if (post.tonemapper.x == 2u) {
+        mapped = aces(color);
+    }
+    return vec4<f32>(mapped, hdr.a);
+}
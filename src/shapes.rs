@@ -0,0 +1,530 @@
+use std::f32::consts::{PI, TAU};
+
+use cgmath::{InnerSpace, Vector3, Zero};
+
+use crate::{
+    asset_manager::{AssetHandle, AssetManager},
+    mesh::{Material, Mesh, MeshAsset, Primitive},
+    mesh_processing::{self, Bounds, ProcessSettings},
+};
+
+// Built-in meshes, centered on the origin with y up. Texture coordinates wrap
+// around curved surfaces once and cover every flat face. Segment and ring counts
+// below the fewest that enclose a volume are raised to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Plane {
+        width: f32,
+        depth: f32,
+        subdivisions: u32,
+    },
+    Cube {
+        size: f32,
+    },
+    UvSphere {
+        radius: f32,
+        segments: u32,
+        rings: u32,
+    },
+    Cylinder {
+        radius: f32,
+        height: f32,
+        segments: u32,
+    },
+    Cone {
+        radius: f32,
+        height: f32,
+        segments: u32,
+    },
+    // The height is that of the cylinder between the two hemispheres
+    Capsule {
+        radius: f32,
+        height: f32,
+        segments: u32,
+        rings: u32,
+    },
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+        major_segments: u32,
+        minor_segments: u32,
+    },
+}
+
+// A ring of vertices swept around the y axis
+struct ProfilePoint {
+    radius: f32,
+    y: f32,
+    // Normal in the (radius, y) plane
+    normal: (f32, f32),
+}
+
+impl Shape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shape::Plane { .. } => "Plane",
+            Shape::Cube { .. } => "Cube",
+            Shape::UvSphere { .. } => "UV sphere",
+            Shape::Cylinder { .. } => "Cylinder",
+            Shape::Cone { .. } => "Cone",
+            Shape::Capsule { .. } => "Capsule",
+            Shape::Torus { .. } => "Torus",
+        }
+    }
+
+    // The shape with every count raised to at least its minimum
+    fn with_minimum_counts(self) -> Self {
+        const SEGMENTS: u32 = 3;
+        match self {
+            Shape::UvSphere {
+                radius,
+                segments,
+                rings,
+            } => Shape::UvSphere {
+                radius,
+                segments: segments.max(SEGMENTS),
+                rings: rings.max(2),
+            },
+            Shape::Cylinder {
+                radius,
+                height,
+                segments,
+            } => Shape::Cylinder {
+                radius,
+                height,
+                segments: segments.max(SEGMENTS),
+            },
+            Shape::Cone {
+                radius,
+                height,
+                segments,
+            } => Shape::Cone {
+                radius,
+                height,
+                segments: segments.max(SEGMENTS),
+            },
+            Shape::Capsule {
+                radius,
+                height,
+                segments,
+                rings,
+            } => Shape::Capsule {
+                radius,
+                height,
+                segments: segments.max(SEGMENTS),
+                rings: rings.max(1),
+            },
+            Shape::Torus {
+                major_radius,
+                minor_radius,
+                major_segments,
+                minor_segments,
+            } => Shape::Torus {
+                major_radius,
+                minor_radius,
+                major_segments: major_segments.max(SEGMENTS),
+                minor_segments: minor_segments.max(SEGMENTS),
+            },
+            Shape::Plane { .. } | Shape::Cube { .. } => self,
+        }
+    }
+
+    pub fn primitive(&self) -> Primitive {
+        let mut primitive = Primitive {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            tangents: Vec::new(),
+            indices: Vec::new(),
+            material: None,
+            morph_targets: Vec::new(),
+            dropped_morph_targets: 0,
+            lods: Vec::new(),
+            bounds: Bounds::default(),
+        };
+        match self.with_minimum_counts() {
+            Shape::Plane {
+                width,
+                depth,
+                subdivisions,
+            } => {
+                let cells = subdivisions + 1;
+                let (u, v) = (Vector3::unit_x() * width, Vector3::unit_z() * depth);
+                face(&mut primitive, Vector3::zero(), u, v, cells);
+            }
+            Shape::Cube { size } => {
+                let half = size / 2.0;
+                for normal in [
+                    Vector3::unit_x(),
+                    Vector3::unit_z(),
+                    -Vector3::unit_x(),
+                    -Vector3::unit_z(),
+                ] {
+                    // Seen from outside, u runs to the right and v downwards
+                    let u = Vector3::unit_y().cross(normal) * size;
+                    face(
+                        &mut primitive,
+                        normal * half,
+                        u,
+                        -Vector3::unit_y() * size,
+                        1,
+                    );
+                }
+                let (u, v) = (Vector3::unit_x() * size, Vector3::unit_z() * size);
+                face(&mut primitive, Vector3::unit_y() * half, u, v, 1);
+                face(&mut primitive, -Vector3::unit_y() * half, u, -v, 1);
+            }
+            Shape::UvSphere {
+                radius,
+                segments,
+                rings,
+            } => {
+                let profile = (0..=rings)
+                    .map(|ring| {
+                        let (sin, cos) = (PI * ring as f32 / rings as f32).sin_cos();
+                        ProfilePoint {
+                            radius: radius * sin,
+                            y: radius * cos,
+                            normal: (sin, cos),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                revolve(&mut primitive, segments, &profile);
+            }
+            Shape::Cylinder {
+                radius,
+                height,
+                segments,
+            } => {
+                let half = height / 2.0;
+                let point = |radius, y, normal| ProfilePoint { radius, y, normal };
+                revolve(
+                    &mut primitive,
+                    segments,
+                    &[
+                        point(0.0, half, (0.0, 1.0)),
+                        point(radius, half, (0.0, 1.0)),
+                    ],
+                );
+                revolve(
+                    &mut primitive,
+                    segments,
+                    &[
+                        point(radius, half, (1.0, 0.0)),
+                        point(radius, -half, (1.0, 0.0)),
+                    ],
+                );
+                revolve(
+                    &mut primitive,
+                    segments,
+                    &[
+                        point(radius, -half, (0.0, -1.0)),
+                        point(0.0, -half, (0.0, -1.0)),
+                    ],
+                );
+            }
+            Shape::Cone {
+                radius,
+                height,
+                segments,
+            } => {
+                let half = height / 2.0;
+                let slope = cgmath::vec2(height, radius).normalize();
+                let point = |radius, y, normal| ProfilePoint { radius, y, normal };
+                let side = (slope.x, slope.y);
+                revolve(
+                    &mut primitive,
+                    segments,
+                    &[point(0.0, half, side), point(radius, -half, side)],
+                );
+                revolve(
+                    &mut primitive,
+                    segments,
+                    &[
+                        point(radius, -half, (0.0, -1.0)),
+                        point(0.0, -half, (0.0, -1.0)),
+                    ],
+                );
+            }
+            Shape::Capsule {
+                radius,
+                height,
+                segments,
+                rings,
+            } => {
+                // Each hemisphere gets `rings` rings, the cylinder joins their equators
+                let half = height / 2.0;
+                let hemisphere = |from: u32, offset: f32| {
+                    (from..=from + rings).map(move |ring| {
+                        let (sin, cos) = (PI / 2.0 * ring as f32 / rings as f32).sin_cos();
+                        ProfilePoint {
+                            radius: radius * sin,
+                            y: radius * cos + offset,
+                            normal: (sin, cos),
+                        }
+                    })
+                };
+                let profile = hemisphere(0, half)
+                    .chain(hemisphere(rings, -half))
+                    .collect::<Vec<_>>();
+                revolve(&mut primitive, segments, &profile);
+            }
+            Shape::Torus {
+                major_radius,
+                minor_radius,
+                major_segments,
+                minor_segments,
+            } => {
+                // Starts at the outer equator and turns downwards first
+                let profile = (0..=minor_segments)
+                    .map(|segment| {
+                        let (sin, cos) = (TAU * segment as f32 / minor_segments as f32).sin_cos();
+                        ProfilePoint {
+                            radius: major_radius + minor_radius * cos,
+                            y: -minor_radius * sin,
+                            normal: (cos, -sin),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                revolve(&mut primitive, major_segments, &profile);
+            }
+        }
+        mesh_processing::process(&mut primitive, ProcessSettings::default());
+        primitive
+    }
+
+    pub fn mesh(&self) -> Mesh {
+        Mesh {
+            name: Some(String::from(self.name())),
+            primitives: vec![self.primitive()],
+        }
+    }
+
+    // Registers the shape as a single mesh asset drawn with the given material
+    pub fn create_asset(&self, am: &mut AssetManager, material: Material) -> AssetHandle {
+        let mut mesh = self.mesh();
+        mesh.primitives[0].material = Some(0);
+        am.create_asset(MeshAsset::new(
+            self.name(),
+            vec![mesh],
+            vec![material],
+            Vec::new(),
+        ))
+    }
+}
+
+// Appends the quads of a grid over the rectangle centered on `center` and spanned
+// by `u` and `v`. Triangles face v × u, which is also the normal.
+fn face(
+    primitive: &mut Primitive,
+    center: Vector3<f32>,
+    u: Vector3<f32>,
+    v: Vector3<f32>,
+    cells: u32,
+) {
+    let normal = v.cross(u).normalize();
+    let first = primitive.positions.len() as u32;
+    for row in 0..=cells {
+        for column in 0..=cells {
+            let (s, t) = (column as f32 / cells as f32, row as f32 / cells as f32);
+            primitive
+                .positions
+                .push((center + u * (s - 0.5) + v * (t - 0.5)).into());
+            primitive.normals.push(normal.into());
+            primitive.uvs.push([s, t]);
+        }
+    }
+    grid_indices(primitive, first, cells, cells);
+}
+
+// Sweeps the profile, ordered from top to bottom, around the y axis. Starting at
+// +z, u follows the sweep and v the profile.
+fn revolve(primitive: &mut Primitive, segments: u32, profile: &[ProfilePoint]) {
+    let first = primitive.positions.len() as u32;
+    let length = profile
+        .windows(2)
+        .map(|pair| {
+            cgmath::vec2(pair[1].radius - pair[0].radius, pair[1].y - pair[0].y).magnitude()
+        })
+        .sum::<f32>();
+    let mut distance = 0.0;
+    for (ring, point) in profile.iter().enumerate() {
+        if ring > 0 {
+            let previous = &profile[ring - 1];
+            distance +=
+                cgmath::vec2(point.radius - previous.radius, point.y - previous.y).magnitude();
+        }
+        for segment in 0..=segments {
+            let u = segment as f32 / segments as f32;
+            let (sin, cos) = (TAU * u).sin_cos();
+            let (normal_radius, normal_y) = point.normal;
+            // Rounding can leave poles just past the axis, which would flip their triangles
+            let radius = point.radius.max(0.0);
+            primitive.positions.push([radius * sin, point.y, radius * cos]);
+            primitive
+                .normals
+                .push([normal_radius * sin, normal_y, normal_radius * cos]);
+            primitive.uvs.push([u, (distance / length).min(1.0)]);
+        }
+    }
+    grid_indices(primitive, first, segments, profile.len() as u32 - 1);
+}
+
+// Two triangles per grid cell, skipping the ones collapsed at poles and apexes
+fn grid_indices(primitive: &mut Primitive, first: u32, columns: u32, rows: u32) {
+    let index = |column: u32, row: u32| first + row * (columns + 1) + column;
+    for row in 0..rows {
+        for column in 0..columns {
+            let a = index(column, row);
+            let b = index(column, row + 1);
+            let c = index(column + 1, row + 1);
+            let d = index(column + 1, row);
+            for triangle in [[a, b, c], [a, c, d]] {
+                let [p0, p1, p2] = triangle.map(|i| Vector3::from(primitive.positions[i as usize]));
+                if (p1 - p0).cross(p2 - p0).magnitude2() > 0.0 {
+                    primitive.indices.extend(triangle);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHAPES: [Shape; 7] = [
+        Shape::Plane {
+            width: 2.0,
+            depth: 4.0,
+            subdivisions: 3,
+        },
+        Shape::Cube { size: 2.0 },
+        Shape::UvSphere {
+            radius: 1.0,
+            segments: 16,
+            rings: 8,
+        },
+        Shape::Cylinder {
+            radius: 1.0,
+            height: 2.0,
+            segments: 16,
+        },
+        Shape::Cone {
+            radius: 1.0,
+            height: 2.0,
+            segments: 16,
+        },
+        Shape::Capsule {
+            radius: 0.5,
+            height: 1.0,
+            segments: 16,
+            rings: 4,
+        },
+        Shape::Torus {
+            major_radius: 1.0,
+            minor_radius: 0.25,
+            major_segments: 16,
+            minor_segments: 8,
+        },
+    ];
+
+    #[test]
+    fn shapes_have_unit_normals_uvs_and_outward_triangles() {
+        for shape in SHAPES {
+            let primitive = shape.primitive();
+            assert!(!primitive.indices.is_empty(), "{}", shape.name());
+            assert_eq!(primitive.normals.len(), primitive.positions.len());
+            assert_eq!(primitive.uvs.len(), primitive.positions.len());
+            for normal in &primitive.normals {
+                assert!(
+                    (Vector3::from(*normal).magnitude() - 1.0).abs() < 1e-5,
+                    "{}",
+                    shape.name()
+                );
+            }
+            for uv in &primitive.uvs {
+                assert!(
+                    uv.iter().all(|c| (0.0..=1.0).contains(c)),
+                    "{}",
+                    shape.name()
+                );
+            }
+            for (triangle, [a, b, c]) in
+                primitive.indices.chunks_exact(3).zip(primitive.triangles())
+            {
+                let normal = triangle
+                    .iter()
+                    .map(|&index| Vector3::from(primitive.normals[index as usize]))
+                    .sum::<Vector3<f32>>();
+                assert!((b - a).cross(c - a).dot(normal) > 0.0, "{}", shape.name());
+            }
+        }
+    }
+
+    #[test]
+    fn shapes_are_sized_by_their_parameters() {
+        let extents = SHAPES.map(|shape| shape.primitive().bounds.aabb);
+        assert_eq!(extents[0].min, cgmath::point3(-1.0, 0.0, -2.0));
+        assert_eq!(extents[0].max, cgmath::point3(1.0, 0.0, 2.0));
+        assert_eq!(extents[1].size(), cgmath::vec3(2.0, 2.0, 2.0));
+        assert!((extents[2].max.y - 1.0).abs() < 1e-5);
+        assert!((extents[5].max.y - 1.0).abs() < 1e-5);
+        assert!((extents[6].max.x - 1.25).abs() < 1e-5);
+        assert!((extents[6].max.y - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn zero_counts_are_raised_to_the_minimum() {
+        let zero = [
+            Shape::UvSphere {
+                radius: 1.0,
+                segments: 0,
+                rings: 0,
+            },
+            Shape::Cylinder {
+                radius: 1.0,
+                height: 2.0,
+                segments: 0,
+            },
+            Shape::Cone {
+                radius: 1.0,
+                height: 2.0,
+                segments: 0,
+            },
+            Shape::Capsule {
+                radius: 0.5,
+                height: 1.0,
+                segments: 0,
+                rings: 0,
+            },
+            Shape::Torus {
+                major_radius: 1.0,
+                minor_radius: 0.25,
+                major_segments: 0,
+                minor_segments: 0,
+            },
+        ];
+        for shape in zero {
+            let primitive = shape.primitive();
+            let minimum = shape.with_minimum_counts().primitive();
+            assert_ne!(shape, shape.with_minimum_counts());
+            assert!(!primitive.indices.is_empty(), "{}", shape.name());
+            assert_eq!(primitive.positions, minimum.positions, "{}", shape.name());
+            assert!(
+                primitive.positions.iter().flatten().all(|c| c.is_finite()),
+                "{}",
+                shape.name()
+            );
+        }
+    }
+
+    #[test]
+    fn create_asset_registers_a_mesh_asset() {
+        let mut am = AssetManager::new();
+        let handle = Shape::Cube { size: 1.0 }.create_asset(&mut am, Material::default());
+        let mesh_asset = &am.get_asset::<MeshAsset>(handle).unwrap().asset;
+        assert_eq!(mesh_asset.meshes[0].primitives[0].material, Some(0));
+        assert_eq!(mesh_asset.meshes[0].primitives[0].indices.len(), 36);
+    }
+}
@@ -0,0 +1,76 @@
+use cgmath::Rotation;
+
+use crate::{
+    asset_manager::AssetManager,
+    component_manager::ComponentManager,
+    components::{
+        animation_state_machine::AnimationStateMachine,
+        click_move::ClickMove,
+        model::{AnimationState, Model},
+        transform::Transform,
+        walk_to::WalkTo,
+    },
+    loaders::gltf::GltfFile,
+    world::World,
+    EntityHandle,
+};
+
+use super::System;
+
+#[derive(Default)]
+pub struct AnimationSystem {}
+impl AnimationSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn movement_speed(cm: &ComponentManager, entity: EntityHandle) -> f32 {
+        let click_move = cm
+            .get_component::<ClickMove>(entity)
+            .filter(|click_move| click_move.target.is_some())
+            .map(|click_move| click_move.speed);
+        let walk_to = cm
+            .get_component::<WalkTo>(entity)
+            .filter(|walk_to| walk_to.target.is_some())
+            .map(|walk_to| walk_to.speed);
+        click_move.or(walk_to).unwrap_or(0.0)
+    }
+}
+impl System for AnimationSystem {
+    fn run(&mut self, world: &mut World, cm: &mut ComponentManager, am: &AssetManager, dt: f32) {
+        for (ent, mut machine) in cm.mut_all_by_type::<AnimationStateMachine>() {
+            let speed = Self::movement_speed(cm, ent);
+            machine.set_parameter("speed", speed);
+            machine.set_parameter("moving", if speed > 0.0 { 1.0 } else { 0.0 });
+            if let Some((state, duration)) = machine.update() {
+                let mut model = cm
+                    .mut_component::<Model>(ent)
+                    .unwrap_or_else(|| panic!("Entity {:?} does not have a model component", ent));
+                let layer = match &mut model.animation {
+                    Some(animation) => animation.crossfade(state.clip, duration),
+                    None => model.animation.insert(AnimationState::new(state.clip)).play(state.clip),
+                };
+                layer.speed = state.speed;
+                layer.mode = state.mode;
+            }
+        }
+        for &entity in world.get_entities() {
+            if let Some(mut model) = cm.mut_component::<Model>(entity) {
+                let asset_handle = model.asset_handle;
+                if let Some(animation) = &mut model.animation {
+                    let clips = am
+                        .get_asset::<GltfFile>(asset_handle)
+                        .map_or(&[][..], |gltf| gltf.asset.animations.as_slice());
+                    animation.advance(dt, clips);
+                    // Entities without a transform have nowhere to apply root motion
+                    if animation.root_motion.is_some() {
+                        if let Some(mut transform) = cm.mut_component::<Transform>(entity) {
+                            let delta =
+                                transform.rotation.rotate_vector(animation.root_motion_delta());
+                            transform.position += delta;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
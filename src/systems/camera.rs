@@ -18,7 +18,7 @@ pub struct Camera {
 }
 
 #[rustfmt::skip]
-const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
     0.0, 0.0, 0.5, 0.0,
@@ -37,7 +37,7 @@ impl Camera {
         }
     }
     pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
-        OPENGL_TO_WGPU_MATRIX * self.proj() * self.view()
+        self.proj() * self.view()
     }
     pub fn view(&self) -> cgmath::Matrix4<f32> {
         cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up)
@@ -90,11 +90,18 @@ impl CameraSystem {
             height,
         }
     }
+    // Keeps the camera where it orbits, only changing its aspect ratio
     pub fn resize(&mut self, width: u32, height: u32) {
-        let aspect = width as f32 / height as f32;
+        if width == 0 || height == 0 {
+            return;
+        }
         self.width = width as f32;
         self.height = height as f32;
-        self.camera = Camera::new(aspect);
+        self.camera.aspect = self.width / self.height;
+    }
+    // Cursor position as a fraction of the window's current size
+    pub fn normalize_cursor(&self, x: f32, y: f32) -> (f32, f32) {
+        (x / self.width, y / self.height)
     }
     pub fn process_keyboard(&mut self, keycode: VirtualKeyCode, state: ElementState) {
         match (keycode, state) {
@@ -165,3 +172,20 @@ impl System for CameraSystem {
         self.camera.eye = cgmath::point3(new_x, new_y, new_z);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resizing_keeps_the_orbit() {
+        let mut system = CameraSystem::new(800.0, 600.0);
+        system.camera.eye = (3.0, 4.0, 5.0).into();
+        system.resize(1000, 500);
+        assert_eq!(system.camera.eye, (3.0, 4.0, 5.0).into());
+        assert_eq!(system.camera.aspect, 2.0);
+        assert_eq!(system.normalize_cursor(500.0, 125.0), (0.5, 0.25));
+        system.resize(0, 0);
+        assert_eq!(system.camera.aspect, 2.0);
+    }
+}
@@ -3,6 +3,7 @@ use crate::{asset_manager::AssetManager, component_manager::ComponentManager, wo
 pub mod camera;
 pub mod movement;
 pub mod click;
+pub mod animation;
 
 pub trait System {
     fn run(&mut self, world: &mut World, cm: &mut ComponentManager, am: &AssetManager, dt: f32);
@@ -5,7 +5,7 @@ use cgmath::prelude::*;
 use crate::{
     asset_manager::AssetManager,
     component_manager::ComponentManager,
-    components::{transform::Transform, walk_to::WalkTo, walkable_surface::WalkableSurface, click_move::ClickMove},
+    components::{model::Model, transform::Transform, walk_to::WalkTo, walkable_surface::WalkableSurface, click_move::ClickMove},
     ray::Ray,
     world::World, EntityHandle,
 };
@@ -19,11 +19,45 @@ impl MovementSystem {
     pub fn new() -> Self {
         Self::default()
     }
+    // Moves across the ground plane and reports whether the target was reached
+    fn step_towards(
+        position: &mut cgmath::Point3<f32>,
+        target: cgmath::Point3<f32>,
+        distance: f32,
+    ) -> bool {
+        let mut direction = target - *position;
+        direction.y = 0.0;
+        if direction.magnitude() <= distance {
+            position.x = target.x;
+            position.z = target.z;
+            return true;
+        }
+        *position += direction.normalize() * distance;
+        false
+    }
+    // Root motion entities are moved by their animation, so only turn them to face the
+    // target. They arrive once it is within the distance of their animation's last step.
+    fn face_towards(transform: &mut Transform, target: cgmath::Point3<f32>, distance: f32) -> bool {
+        let mut direction = target - transform.position;
+        direction.y = 0.0;
+        if direction.magnitude() <= distance {
+            return true;
+        }
+        transform.rotation = cgmath::Quaternion::from_arc(transform.forward, direction.normalize(), None);
+        false
+    }
+    // The displacement the animation system applied to a root motion entity last frame
+    fn root_motion_step(cm: &ComponentManager, entity: EntityHandle) -> Option<f32> {
+        let model = cm.get_component::<Model>(entity)?;
+        let animation = model.animation.as_ref()?;
+        animation.root_motion?;
+        Some(animation.root_motion_delta().magnitude())
+    }
 }
 impl System for MovementSystem {
     fn run(&mut self, world: &mut World, cm: &mut ComponentManager, am: &AssetManager, dt: f32) {
-        let entities = cm.get_all_by_type::<WalkTo>();
-        entities.iter().for_each(|(ent, walk_to)| {
+        let mut entities = cm.mut_all_by_type::<WalkTo>();
+        entities.iter_mut().for_each(|(ent, walk_to)| {
             let mut transform = cm
                 .mut_component::<Transform>(*ent)
                 .unwrap_or_else(|| panic!("Entity {:?} does not have a transform component", ent));
@@ -39,14 +73,19 @@ impl System for MovementSystem {
                 }).position;
 
                 let new_y = floor_pos.y + 1.0;
-                let new_pos = (target - transform.position).normalize() * walk_to.speed * dt;
-                transform.position += new_pos;
+                let arrived = match Self::root_motion_step(cm, *ent) {
+                    Some(step) => Self::face_towards(&mut transform, target, step),
+                    None => Self::step_towards(&mut transform.position, target, walk_to.speed * dt),
+                };
+                if arrived {
+                    walk_to.target = None;
+                }
                 transform.position.y = new_y;
             }
         });
 
-        let entities = cm.get_all_by_type::<ClickMove>();
-        entities.iter().for_each(|(ent, click_move)| {
+        let mut entities = cm.mut_all_by_type::<ClickMove>();
+        entities.iter_mut().for_each(|(ent, click_move)| {
             let mut transform = cm
                 .mut_component::<Transform>(*ent)
                 .unwrap_or_else(|| panic!("Entity {:?} does not have a transform component", ent));
@@ -62,8 +101,15 @@ impl System for MovementSystem {
                 }).position;
 
                 let new_y = floor_pos.y + 1.0;
-                let new_pos = (target - transform.position).normalize() * click_move.speed * dt;
-                transform.position += new_pos;
+                let arrived = match Self::root_motion_step(cm, *ent) {
+                    Some(step) => Self::face_towards(&mut transform, target, step),
+                    None => {
+                        Self::step_towards(&mut transform.position, target, click_move.speed * dt)
+                    }
+                };
+                if arrived {
+                    click_move.target = None;
+                }
                 transform.position.y = new_y;
             }
         });
@@ -74,3 +120,21 @@ impl Default for MovementSystem {
         Self {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_motion_arrives_within_its_last_step() {
+        let mut transform = Transform::new(None, None, None);
+        let target = cgmath::point3(0.0, 0.0, 2.0);
+        assert!(!MovementSystem::face_towards(&mut transform, target, 1.5));
+        assert!((transform.rotation.rotate_vector(transform.forward) - cgmath::Vector3::unit_z())
+            .magnitude()
+            < 1e-5);
+        transform.position.z = 0.6;
+        assert!(MovementSystem::face_towards(&mut transform, target, 1.5));
+        assert_eq!(transform.position.z, 0.6);
+    }
+}
@@ -107,6 +107,16 @@ impl Window {
                     });
                     ControlFlow::Poll
                 }
+                WinEvent::WindowEvent {
+                    event: WindowEvent::ScaleFactorChanged { new_inner_size, .. },
+                    ..
+                } => {
+                    runner(Event::Resize {
+                        width: new_inner_size.width,
+                        height: new_inner_size.height,
+                    });
+                    ControlFlow::Poll
+                }
                 WinEvent::WindowEvent {
                     event: WindowEvent::CloseRequested,
                     ..